@@ -1,5 +1,9 @@
 extern crate prost_build;
 
 fn main() {
-    prost_build::compile_protos(&["src/protos/kmessages.proto"], &["src/protos"]).unwrap();
+    prost_build::compile_protos(
+        &["src/protos/kmessages.proto", "src/protos/archive.proto"],
+        &["src/protos"],
+    )
+    .unwrap();
 }
@@ -0,0 +1,131 @@
+use std::{collections::HashMap, time::Duration};
+
+use futures::executor::block_on;
+use rdkafka::{
+    admin::{
+        AdminClient, AdminOptions, AlterConfig, NewPartitions, NewTopic, ResourceSpecifier,
+        TopicReplication,
+    },
+    client::DefaultClientContext,
+    error::KafkaError,
+};
+
+use crate::{
+    config::KafkaConfig,
+    errors::AppError,
+    protos::kafka_archive::{config_entry_new, ConfigEntry, TopicMetadata},
+};
+
+fn admin_client(config: &KafkaConfig) -> Result<AdminClient<DefaultClientContext>, AppError> {
+    let admin = config.client_config().create()?;
+    Ok(admin)
+}
+
+fn admin_options() -> AdminOptions {
+    AdminOptions::new().request_timeout(Some(Duration::from_secs(60)))
+}
+
+// Returns the configs which are overridden on the topic level
+pub fn topic_configs(config: &KafkaConfig, topic_name: &str) -> Result<Vec<ConfigEntry>, AppError> {
+    let admin = admin_client(config)?;
+    let results = block_on(
+        admin.describe_configs(&[ResourceSpecifier::Topic(topic_name)], &admin_options()),
+    )?;
+
+    let mut configs = vec![];
+    for result in results {
+        let resource = result.map_err(|e| AppError::Kafka(KafkaError::AdminOp(e)))?;
+        for entry in resource.entries {
+            if !entry.is_default && !entry.is_sensitive {
+                configs.push(config_entry_new(entry.name, entry.value));
+            }
+        }
+    }
+    Ok(configs)
+}
+
+// Returns the partition count of every topic of the cluster
+pub fn topic_partitions(config: &KafkaConfig) -> Result<HashMap<String, i32>, AppError> {
+    let admin = admin_client(config)?;
+    // Metadata of all topics doesn't trigger auto creation of missing ones
+    let metadata = admin
+        .inner()
+        .fetch_metadata(None, Duration::from_secs(60))?;
+    Ok(metadata
+        .topics()
+        .iter()
+        .map(|t| (t.name().to_string(), t.partitions().len() as i32))
+        .collect())
+}
+
+// Replicas of the first partition
+pub fn replication_factor(config: &KafkaConfig, topic_name: &str) -> Result<i32, AppError> {
+    let admin = admin_client(config)?;
+    let metadata = admin
+        .inner()
+        .fetch_metadata(Some(topic_name), Duration::from_secs(60))?;
+    Ok(metadata
+        .topics()
+        .first()
+        .and_then(|t| t.partitions().first())
+        .map(|p| p.replicas().len() as i32)
+        .unwrap_or_default())
+}
+
+// Creates the topic with partitions, replication factor and configs recorded at backup time,
+// the replication factor of older archives is the broker default
+pub fn create_topic(
+    config: &KafkaConfig,
+    name: &str,
+    metadata: &TopicMetadata,
+) -> Result<(), AppError> {
+    let admin = admin_client(config)?;
+    let replication_factor = metadata
+        .replication_factor
+        .filter(|rf| *rf > 0)
+        .unwrap_or(-1);
+    let topic = metadata.configs.iter().fold(
+        NewTopic::new(
+            name,
+            metadata.partitions.len() as i32,
+            TopicReplication::Fixed(replication_factor),
+        ),
+        |topic, c| topic.set(c.name(), c.value()),
+    );
+
+    for result in block_on(admin.create_topics(&[topic], &admin_options()))? {
+        result.map_err(|(_, e)| AppError::Kafka(KafkaError::AdminOp(e)))?;
+    }
+    Ok(())
+}
+
+// Replaces the topic level configs with the configs recorded at backup time
+pub fn alter_topic_configs(
+    config: &KafkaConfig,
+    name: &str,
+    metadata: &TopicMetadata,
+) -> Result<(), AppError> {
+    let admin = admin_client(config)?;
+    let alter = metadata.configs.iter().fold(
+        AlterConfig::new(ResourceSpecifier::Topic(name)),
+        |alter, c| alter.set(c.name(), c.value()),
+    );
+    for result in block_on(admin.alter_configs(&[alter], &admin_options()))? {
+        result.map_err(|(_, e)| AppError::Kafka(KafkaError::AdminOp(e)))?;
+    }
+    Ok(())
+}
+
+// Adds partitions up to `partitions`, partitions can't be removed
+pub fn grow_partitions(
+    config: &KafkaConfig,
+    name: &str,
+    partitions: usize,
+) -> Result<(), AppError> {
+    let admin = admin_client(config)?;
+    let new_partitions = NewPartitions::new(name, partitions);
+    for result in block_on(admin.create_partitions(&[new_partitions], &admin_options()))? {
+        result.map_err(|(_, e)| AppError::Kafka(KafkaError::AdminOp(e)))?;
+    }
+    Ok(())
+}
@@ -0,0 +1,488 @@
+use std::{
+    fs::OpenOptions,
+    io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    errors::AppError,
+    protos::kafka_archive::{
+        archive_header_pack, archive_header_unpack, ArchiveHeader, TopicMetadata,
+    },
+    storage::storage,
+    volumes::{self, is_manifest},
+};
+
+// File layout: MAGIC | FORMAT_VERSION (u16 BE) | header length (u32 BE) | ArchiveHeader | blocks | trailer
+// Block: 'B' | records (u32 BE) | length (u32 BE) | CRC32C (u32 BE) | independently compressed records
+// Trailer: 'T' | blocks (u64 BE) | records (u64 BE) | CRC32C (u32 BE) of the counts
+// Version 4 is version 3 with the compressed records of the blocks encrypted
+// and an empty encrypted block before the trailer.
+// Version 2 has blocks without the kind and the checksum and has no trailer.
+// Version 1 has a single compressed stream of records instead of blocks.
+pub const MAGIC: &[u8; 4] = b"AKBT";
+pub const FORMAT_VERSION: u16 = 4;
+pub const ENCRYPTED_FORMAT_VERSION: u16 = 4;
+pub const CHECKSUM_FORMAT_VERSION: u16 = 3;
+pub const STREAM_FORMAT_VERSION: u16 = 1;
+const BLOCK_KIND: u8 = b'B';
+const TRAILER_KIND: u8 = b'T';
+const HEADER_OFFSET: u64 = (MAGIC.len() + 2 + 4) as u64;
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+/// Headers, blocks and records are never longer
+pub const MAX_FRAME_LEN: usize = 1 << 30;
+
+pub fn archive_header_new(brokers: &str, topics: Vec<TopicMetadata>) -> ArchiveHeader {
+    let created_at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as i64)
+        .unwrap_or_default();
+
+    ArchiveHeader {
+        tool_version: Some(env!("CARGO_PKG_VERSION").to_string()),
+        brokers: Some(brokers.to_string()),
+        topics,
+        created_at: Some(created_at),
+        record_count: Some(0),
+        base_archive: None,
+        compression: None,
+        filters: vec![],
+        compacted: None,
+        group_offsets: vec![],
+        volume: None,
+        encryption: None,
+    }
+}
+
+// Readers of version 3 refuse encrypted archives instead of failing on their blocks
+pub fn write_header(mut writer: impl Write, header: &ArchiveHeader) -> std::io::Result<()> {
+    let buf = archive_header_pack(header);
+    let version = match header.encryption {
+        Some(_) => ENCRYPTED_FORMAT_VERSION,
+        None => CHECKSUM_FORMAT_VERSION,
+    };
+    writer.write_all(MAGIC)?;
+    writer.write_all(&version.to_be_bytes())?;
+    writer.write_all(&(buf.len() as u32).to_be_bytes())?;
+    writer.write_all(&buf)
+}
+
+// Returns None for archives written before the header was introduced
+pub fn read_header(reader: impl BufRead) -> Result<Option<ArchiveHeader>, AppError> {
+    Ok(read_versioned_header(reader)?.map(|(_, header)| header))
+}
+
+// Same as `read_header` with the format version of the archive
+pub fn read_versioned_header(
+    mut reader: impl BufRead,
+) -> Result<Option<(u16, ArchiveHeader)>, AppError> {
+    let peek = reader
+        .fill_buf()
+        .map_err(|e| AppError::IoError(e.to_string()))?;
+
+    if peek.starts_with(&GZIP_MAGIC) {
+        return Ok(None);
+    }
+
+    let mut magic = [0; 4];
+    let mut version = [0; 2];
+    let mut len = [0; 4];
+    reader
+        .read_exact(&mut magic)
+        .map_err(|_| AppError::BadArchive("file is too short".to_string()))?;
+    if &magic != MAGIC {
+        return Err(AppError::BadArchive("unknown magic bytes".to_string()));
+    }
+    reader
+        .read_exact(&mut version)
+        .map_err(|e| AppError::BadArchive(e.to_string()))?;
+    let version = u16::from_be_bytes(version);
+    if !(STREAM_FORMAT_VERSION..=FORMAT_VERSION).contains(&version) {
+        return Err(AppError::UnsupportedVersion(version));
+    }
+    reader
+        .read_exact(&mut len)
+        .map_err(|e| AppError::BadArchive(e.to_string()))?;
+    let buf = read_len(&mut reader, u32::from_be_bytes(len) as usize, "header")?;
+
+    archive_header_unpack(&buf)
+        .map(|header| Some((version, header)))
+        .map_err(AppError::BadArchive)
+}
+
+/// Compressed records of the archive
+#[derive(Debug, Default, PartialEq)]
+pub struct Block {
+    pub records: u32,
+    pub data: Vec<u8>,
+}
+
+/// Totals written after the last block
+#[derive(Debug, Default, PartialEq)]
+pub struct Trailer {
+    pub blocks: u64,
+    pub records: u64,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Frame {
+    Block(Block),
+    Trailer(Trailer),
+}
+
+pub fn write_block(mut writer: impl Write, block: &Block) -> io::Result<()> {
+    writer.write_all(&[BLOCK_KIND])?;
+    writer.write_all(&block.records.to_be_bytes())?;
+    writer.write_all(&(block.data.len() as u32).to_be_bytes())?;
+    writer.write_all(&crc32c::crc32c(&block.data).to_be_bytes())?;
+    writer.write_all(&block.data)
+}
+
+pub fn write_trailer(mut writer: impl Write, trailer: &Trailer) -> io::Result<()> {
+    let mut buf = trailer.blocks.to_be_bytes().to_vec();
+    buf.extend_from_slice(&trailer.records.to_be_bytes());
+    writer.write_all(&[TRAILER_KIND])?;
+    writer.write_all(&buf)?;
+    writer.write_all(&crc32c::crc32c(&buf).to_be_bytes())
+}
+
+// Fills `buf` like read_exact, but returns false when the reader is already at the end
+fn read_or_eof(mut reader: impl Read, buf: &mut [u8]) -> io::Result<bool> {
+    let mut read = 0;
+    while read == 0 {
+        match reader.read(buf) {
+            Ok(0) => return Ok(false),
+            Ok(n) => read = n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    reader.read_exact(&mut buf[read..])?;
+    Ok(true)
+}
+
+/// Reads `what` of an untrusted length. The buffer grows with the data read,
+/// so a corrupted length can't allocate more than the archive has.
+pub fn read_len(reader: impl Read, len: usize, what: &str) -> Result<Vec<u8>, AppError> {
+    if len > MAX_FRAME_LEN {
+        return Err(AppError::Corrupted(format!(
+            "{} of {} bytes is longer than {}",
+            what, len, MAX_FRAME_LEN
+        )));
+    }
+    let mut buf = Vec::with_capacity(len.min(64 * 1024));
+    reader
+        .take(len as u64)
+        .read_to_end(&mut buf)
+        .map_err(|e| AppError::BadArchive(format!("truncated {}: {}", what, e)))?;
+    if buf.len() < len {
+        return Err(AppError::BadArchive(format!(
+            "truncated {}: {} bytes of {}",
+            what,
+            buf.len(),
+            len
+        )));
+    }
+    Ok(buf)
+}
+
+fn read_bytes(reader: impl Read, len: usize) -> Result<Vec<u8>, AppError> {
+    read_len(reader, len, "frame")
+}
+
+fn read_u32(reader: impl Read) -> Result<u32, AppError> {
+    Ok(u32::from_be_bytes(
+        read_bytes(reader, 4)?.try_into().unwrap(),
+    ))
+}
+
+fn check_crc(data: &[u8], crc: u32) -> Result<(), AppError> {
+    if crc != crc32c::crc32c(data) {
+        return Err(AppError::BadArchive("checksum mismatch".to_string()));
+    }
+    Ok(())
+}
+
+// Returns None at the end of the archive.
+// Version 2 has neither kinds of frames nor checksums nor the trailer.
+pub fn read_frame(mut reader: impl Read, version: u16) -> Result<Option<Frame>, AppError> {
+    let mut kind = [0; 1];
+    match read_or_eof(&mut reader, &mut kind) {
+        Ok(true) => (),
+        Ok(false) => return Ok(None),
+        Err(e) => return Err(AppError::IoError(e.to_string())),
+    }
+
+    if version < CHECKSUM_FORMAT_VERSION {
+        let mut records = [kind[0], 0, 0, 0];
+        records[1..].copy_from_slice(&read_bytes(&mut reader, 3)?);
+        let len = read_u32(&mut reader)?;
+        return Ok(Some(Frame::Block(Block {
+            records: u32::from_be_bytes(records),
+            data: read_bytes(&mut reader, len as usize)?,
+        })));
+    }
+
+    match kind[0] {
+        BLOCK_KIND => {
+            let records = read_u32(&mut reader)?;
+            let len = read_u32(&mut reader)?;
+            let crc = read_u32(&mut reader)?;
+            let data = read_bytes(&mut reader, len as usize)?;
+            check_crc(&data, crc)?;
+            Ok(Some(Frame::Block(Block { records, data })))
+        }
+        TRAILER_KIND => {
+            let buf = read_bytes(&mut reader, 16)?;
+            check_crc(&buf, read_u32(&mut reader)?)?;
+            Ok(Some(Frame::Trailer(Trailer {
+                blocks: u64::from_be_bytes(buf[..8].try_into().unwrap()),
+                records: u64::from_be_bytes(buf[8..].try_into().unwrap()),
+            })))
+        }
+        kind => Err(AppError::BadArchive(format!("unknown frame kind:{}", kind))),
+    }
+}
+
+pub fn read_archive_header(pathfile: &str) -> Result<Option<ArchiveHeader>, AppError> {
+    let (reader, _) = storage(pathfile)?.open(pathfile)?;
+    read_header(BufReader::new(reader))
+}
+
+// Returns archives of the directory ordered by name, volumes of the manifest, or the file itself
+pub fn list_archives(path: &str) -> Result<Vec<String>, AppError> {
+    if is_manifest(path) {
+        return Ok(volumes::load(path)?.volume_paths(path));
+    }
+    if !Path::new(path).is_dir() {
+        return Ok(vec![path.to_string()]);
+    }
+
+    let mut archives = vec![];
+    for entry in std::fs::read_dir(path).map_err(|e| AppError::IoError(e.to_string()))? {
+        let entry = entry.map_err(|e| AppError::IoError(e.to_string()))?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        // Manifests describe the volumes next to them
+        let hidden = name.starts_with('.') || is_manifest(&name);
+        if entry.path().is_file() && !hidden {
+            archives.push(entry.path().to_string_lossy().to_string());
+        }
+    }
+    archives.sort();
+    Ok(archives)
+}
+
+// Returns the archive preceded by all its base archives, the oldest first.
+// Base archives are looked up in the directory of the incremental one first.
+pub fn archive_chain(pathfile: &str) -> Result<Vec<String>, AppError> {
+    let mut chain = vec![pathfile.to_string()];
+    let mut current = PathBuf::from(pathfile);
+
+    while let Some(header) = read_archive_header(chain.last().unwrap())? {
+        let base = match header.base_archive {
+            Some(base) => base,
+            None => break,
+        };
+        current.set_file_name(&base);
+        let mut path = current.to_string_lossy().to_string();
+        if !storage(&path)?.exists(&path)? {
+            current = PathBuf::from(base);
+            path = current.to_string_lossy().to_string();
+        }
+        if chain.contains(&path) {
+            return Err(AppError::BadArchive(format!(
+                "archive chain has a loop at `{}`",
+                path
+            )));
+        }
+        chain.push(path);
+    }
+
+    chain.reverse();
+    Ok(chain)
+}
+
+// Rewrites the header of the finished archive with the final record count,
+// the header has to keep its size, so the count has to be in it already
+pub fn patch_record_count(
+    pathfile: &str,
+    header: &mut ArchiveHeader,
+    record_count: u64,
+) -> Result<(), AppError> {
+    let old_len = archive_header_pack(header).len();
+    let patched = ArchiveHeader {
+        record_count: Some(record_count),
+        ..header.clone()
+    };
+    let buf = archive_header_pack(&patched);
+    if header.record_count.is_none() || old_len != buf.len() {
+        return Err(AppError::BadArchive(format!(
+            "`{}`: the record count of the header can't be patched in place",
+            pathfile
+        )));
+    }
+    *header = patched;
+
+    let mut file = OpenOptions::new()
+        .write(true)
+        .open(pathfile)
+        .map_err(|e| AppError::IoError(e.to_string()))?;
+    file.seek(SeekFrom::Start(HEADER_OFFSET))
+        .and_then(|_| file.write_all(&buf))
+        .map_err(|e| AppError::IoError(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protos::kafka_archive::{partition_metadata_new, topic_metadata_new};
+
+    fn header() -> ArchiveHeader {
+        let topic = topic_metadata_new(
+            "topic".to_string(),
+            vec![partition_metadata_new(0, (0, 10), (0, 10))],
+            vec![],
+        );
+        archive_header_new("localhost:9092", vec![topic])
+    }
+
+    #[test]
+    fn header_roundtrip() {
+        let mut buf = vec![];
+        write_header(&mut buf, &header()).unwrap();
+        buf.extend_from_slice(&[0x1f, 0x8b]);
+
+        let mut reader = buf.as_slice();
+        let read = read_header(&mut reader).unwrap().unwrap();
+        let mut expected = header();
+        expected.created_at = read.created_at;
+        assert_eq!(read, expected);
+        assert_eq!(reader, &[0x1f, 0x8b]);
+    }
+
+    #[test]
+    fn legacy_archive_has_no_header() {
+        let buf = [0x1f, 0x8b, 0x08, 0x00];
+        assert_eq!(read_header(&buf[..]).unwrap(), None);
+    }
+
+    #[test]
+    fn stream_version_is_accepted() {
+        let mut buf = vec![];
+        write_header(&mut buf, &header()).unwrap();
+        buf[MAGIC.len()..MAGIC.len() + 2].copy_from_slice(&STREAM_FORMAT_VERSION.to_be_bytes());
+        let (version, _) = read_versioned_header(&buf[..]).unwrap().unwrap();
+        assert_eq!(version, STREAM_FORMAT_VERSION);
+    }
+
+    #[test]
+    fn frames_roundtrip() {
+        let block = Block {
+            records: 2,
+            data: vec![1, 2, 3],
+        };
+        let trailer = Trailer {
+            blocks: 1,
+            records: 2,
+        };
+        let mut buf = vec![];
+        write_block(&mut buf, &block).unwrap();
+        write_trailer(&mut buf, &trailer).unwrap();
+
+        let mut reader = buf.as_slice();
+        let read = |reader: &mut &[u8]| read_frame(reader, FORMAT_VERSION).unwrap();
+        assert_eq!(read(&mut reader), Some(Frame::Block(block)));
+        assert_eq!(read(&mut reader), Some(Frame::Trailer(trailer)));
+        assert_eq!(read(&mut reader), None);
+    }
+
+    #[test]
+    fn corrupted_frames_are_detected() {
+        let block = Block {
+            records: 2,
+            data: vec![1, 2, 3],
+        };
+        let mut buf = vec![];
+        write_block(&mut buf, &block).unwrap();
+
+        let mut flipped = buf.clone();
+        *flipped.last_mut().unwrap() ^= 1;
+        assert_eq!(
+            read_frame(&flipped[..], FORMAT_VERSION),
+            Err(AppError::BadArchive("checksum mismatch".to_string()))
+        );
+
+        for len in 1..buf.len() {
+            assert!(matches!(
+                read_frame(&buf[..len], FORMAT_VERSION),
+                Err(AppError::BadArchive(_))
+            ));
+        }
+
+        // Corrupted lengths are refused or limited by the data before anything is allocated
+        let mut huge = buf.clone();
+        huge[5..9].copy_from_slice(&u32::MAX.to_be_bytes());
+        assert!(matches!(
+            read_frame(&huge[..], FORMAT_VERSION),
+            Err(AppError::Corrupted(_))
+        ));
+        huge[5..9].copy_from_slice(&(MAX_FRAME_LEN as u32).to_be_bytes());
+        assert_eq!(
+            read_frame(&huge[..], FORMAT_VERSION),
+            Err(AppError::BadArchive(format!(
+                "truncated frame: 3 bytes of {}",
+                MAX_FRAME_LEN
+            )))
+        );
+    }
+
+    #[test]
+    fn blocks_without_checksum() {
+        // Version 2 block of 2 records with data [1, 2, 3]
+        let buf = [0, 0, 0, 2, 0, 0, 0, 3, 1, 2, 3];
+        let expected = Block {
+            records: 2,
+            data: vec![1, 2, 3],
+        };
+        assert_eq!(
+            read_frame(&buf[..], 2).unwrap(),
+            Some(Frame::Block(expected))
+        );
+    }
+
+    #[test]
+    fn record_count_is_patched_in_place() {
+        let dir = std::env::temp_dir().join(format!("akbt-patch-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let pathfile = dir.join("archive").to_string_lossy().to_string();
+        let mut header = header();
+        let mut buf = vec![];
+        write_header(&mut buf, &header).unwrap();
+        std::fs::write(&pathfile, &buf).unwrap();
+
+        patch_record_count(&pathfile, &mut header, 42).unwrap();
+        let read = read_header(std::fs::read(&pathfile).unwrap().as_slice()).unwrap();
+        assert_eq!(read.unwrap().record_count, Some(42));
+
+        // Without the count the header would grow over the first block
+        header.record_count = None;
+        assert!(matches!(
+            patch_record_count(&pathfile, &mut header, 42),
+            Err(AppError::BadArchive(_))
+        ));
+        assert_eq!(header.record_count, None);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn unknown_version_is_refused() {
+        let mut buf = vec![];
+        write_header(&mut buf, &header()).unwrap();
+        buf[MAGIC.len()..MAGIC.len() + 2].copy_from_slice(&(FORMAT_VERSION + 1).to_be_bytes());
+        assert_eq!(
+            read_header(&buf[..]),
+            Err(AppError::UnsupportedVersion(FORMAT_VERSION + 1))
+        );
+    }
+}
@@ -1,94 +1,298 @@
-use log::{error, info};
-use rdkafka::Message;
+use log::{error, info, warn};
+use rdkafka::message::{Headers, OwnedMessage};
+use rdkafka::{Message, Timestamp};
+use regex::Regex;
+use signal_hook::{
+    consts::{SIGINT, SIGTERM},
+    flag,
+};
 
 use std::{
+    collections::HashMap,
+    path::Path,
     sync::{
+        atomic::AtomicBool,
         mpsc::{sync_channel, Receiver, SyncSender},
         Arc, Mutex,
     },
     thread,
+    time::Duration,
 };
 
 use crate::{
-    consumer::MyConsumer,
+    admin,
+    archive::{archive_header_new, list_archives, read_archive_header},
+    checkpoint::{self, checkpoint_path, BackupCheckpoint},
+    codec::Codec,
+    compact::Compactor,
+    config::KafkaConfig,
+    consumer::{check_single_topic, recoverable, Bounds, MyConsumer},
+    crypto::Keys,
     errors::AppError,
-    gzip::{GzMsg, GzWriter},
+    filter::Filter,
+    groups::{self, PositionCommitter},
     mbprocess::MProgressBars,
-    protos::kafka_messages::{kafka_message_len, kafka_message_new, kafka_message_pack},
+    metrics::Metrics,
+    protos::kafka_archive::{
+        partition_metadata_new, topic_metadata_new, ArchiveHeader, TopicMetadata,
+    },
+    protos::kafka_messages::{
+        kafka_header_new, kafka_message_len, kafka_message_new, kafka_message_pack, KafkaMessage,
+        TimestampType,
+    },
+    schema::SchemaRegistry,
+    storage::is_remote,
+    stream::{archive_path, is_stdio, BlockEncoding, StreamMsg, StreamWriter},
+    volumes::VolumeLimits,
 };
 
+const FOLLOW_POLL_TIMEOUT: Duration = Duration::from_millis(100);
+
+fn kafka_message_from(msg: &OwnedMessage) -> KafkaMessage {
+    let headers = msg
+        .headers()
+        .map(|hdrs| {
+            hdrs.iter()
+                .map(|h| kafka_header_new(h.key.to_string(), h.value.map(Vec::from)))
+                .collect()
+        })
+        .unwrap_or_default();
+
+    let (timestamp_type, timestamp) = match msg.timestamp() {
+        Timestamp::NotAvailable => (TimestampType::NotAvailable, None),
+        Timestamp::CreateTime(ts) => (TimestampType::CreateTime, Some(ts)),
+        Timestamp::LogAppendTime(ts) => (TimestampType::LogAppendTime, Some(ts)),
+    };
+
+    kafka_message_new(
+        msg.key().map(Vec::from),
+        msg.payload().map(Vec::from),
+        Some(msg.partition() as u32),
+        headers,
+        timestamp,
+        timestamp_type,
+        Some(msg.offset()),
+    )
+}
+
+fn topic_metadata(
+    config: &KafkaConfig,
+    consumer: &MyConsumer,
+    registry: Option<&SchemaRegistry>,
+    topic_idx: usize,
+) -> Result<TopicMetadata, AppError> {
+    let topic_name = consumer.topic_name(topic_idx);
+    let mut partitions = Vec::with_capacity(consumer.partitions(topic_idx) as usize);
+    for part_idx in 0..consumer.partitions(topic_idx) {
+        partitions.push(partition_metadata_new(
+            part_idx,
+            consumer.offsets(topic_idx, part_idx)?,
+            consumer.get_range(topic_idx, part_idx),
+        ));
+    }
+
+    let configs = admin::topic_configs(config, topic_name).unwrap_or_else(|e| {
+        warn!("Can't describe configs of topic:{} error:{}", topic_name, e);
+        vec![]
+    });
+
+    let mut metadata = topic_metadata_new(topic_name.to_string(), partitions, configs);
+    metadata.replication_factor = admin::replication_factor(config, topic_name)
+        .map_err(|e| {
+            warn!(
+                "Can't get replication factor of topic:{} error:{}",
+                topic_name, e
+            )
+        })
+        .ok();
+    if let Some(registry) = registry {
+        metadata.schemas = registry.snapshot(topic_name)?;
+    }
+    Ok(metadata)
+}
+
+fn topics_metadata(
+    config: &KafkaConfig,
+    consumer: &MyConsumer,
+    schema_registry: Option<&str>,
+) -> Result<Vec<TopicMetadata>, AppError> {
+    let registry = schema_registry.map(SchemaRegistry::new);
+    (0..consumer.topics())
+        .map(|topic_idx| topic_metadata(config, consumer, registry.as_ref(), topic_idx))
+        .collect()
+}
+
+// Path, header and the checkpoint of the interrupted archive
+type Archive = (String, ArchiveHeader, Option<BackupCheckpoint>);
+
+// Offsets per topic and partition where the backups stopped
+// and the archive of every topic
+type IncrementalBase = (HashMap<(String, i32), i64>, HashMap<String, String>);
+
+fn incremental_base(pathfile: &str) -> Result<IncrementalBase, AppError> {
+    let mut offsets = HashMap::new();
+    let mut archives = HashMap::new();
+    for archive in list_archives(pathfile)? {
+        let header = read_archive_header(&archive)?.ok_or_else(|| {
+            AppError::BadArchive(format!("`{}` has no header to continue from", archive))
+        })?;
+        for topic in header.topics {
+            for p in topic.partitions.iter() {
+                let offset_end = p.offset_end.unwrap_or(p.high_watermark());
+                offsets.insert((topic.name().to_string(), p.partition()), offset_end);
+            }
+            archives.insert(topic.name().to_string(), archive.clone());
+        }
+    }
+    Ok((offsets, archives))
+}
+
+// With one encoder all topics go to the same archive and records keep the topic index,
+// otherwise every topic has its own encoder.
+// Records not matching the filters are consumed but not packed.
+// With compactors (one per encoder) records are packed only when all are consumed.
 fn pack_process(
-    receiver: Receiver<Vec<rdkafka::message::OwnedMessage>>,
-    encoder: SyncSender<GzMsg>,
+    receiver: Receiver<Vec<OwnedMessage>>,
+    encoders: Vec<SyncSender<StreamMsg>>,
+    mut compactors: Vec<Compactor>,
+    topics: HashMap<String, usize>,
+    filters: Vec<Filter>,
     mb: Arc<Mutex<MProgressBars>>,
 ) -> Result<(), AppError> {
     let mut max_capacity = 1024;
+    let single_archive = encoders.len() == 1;
+    let mut topic_names = vec![String::new(); topics.len()];
+    for (name, idx) in topics.iter() {
+        topic_names[*idx] = name.clone();
+    }
 
-    loop {
-        match receiver.recv() {
-            Ok(batch) => {
-                let mut gzmsg = GzMsg {
-                    data: Vec::with_capacity(max_capacity),
-                };
-                for msg in batch {
-                    mb.lock().unwrap().update(msg.partition(), msg.offset());
-
-                    let kmsg = kafka_message_new(
-                        msg.key().map(|slice| Vec::from(slice)),
-                        msg.payload().map(|slice| Vec::from(slice)),
-                        Some(msg.partition() as u32),
-                        vec![],
-                    );
-                    gzmsg
-                        .data
-                        .append(&mut kafka_message_len(&kmsg).to_be_bytes().to_vec());
-                    gzmsg.data.append(&mut kafka_message_pack(&kmsg));
-                }
-                if max_capacity < gzmsg.data.len() {
-                    max_capacity = gzmsg.data.len();
-                }
-                match encoder.send(gzmsg) {
-                    Ok(_) => (),
-                    Err(e) => return Err(AppError::Send2Encoder(e.to_string())),
-                };
+    while let Ok(batch) = receiver.recv() {
+        let bytes: usize = batch
+            .iter()
+            .map(|m| m.key().map_or(0, |k| k.len()) + m.payload().map_or(0, |v| v.len()))
+            .sum();
+        mb.lock().unwrap().read(batch.len() as u64, bytes as u64);
+        let mut chunks: Vec<StreamMsg> = (0..encoders.len())
+            .map(|_| StreamMsg {
+                data: Vec::with_capacity(max_capacity),
+                ..Default::default()
+            })
+            .collect();
+        // Next offsets of every chunk per (topic, partition)
+        let mut next_offsets: Vec<HashMap<(usize, i32), i64>> = vec![HashMap::new(); chunks.len()];
+        for msg in batch {
+            let topic_idx = topics[msg.topic()];
+            mb.lock()
+                .unwrap()
+                .update(topic_idx, msg.partition(), msg.offset());
+
+            let mut kmsg = kafka_message_from(&msg);
+            let chunk_idx = if single_archive {
+                kmsg.topic = (topic_idx > 0).then_some(topic_idx as u32);
+                0
+            } else {
+                topic_idx
+            };
+            next_offsets[chunk_idx].insert((topic_idx, msg.partition()), msg.offset() + 1);
+            if !filters.iter().all(|f| f.matches(&kmsg)) {
+                continue;
             }
-            Err(_) => break,
+            if let Some(compactor) = compactors.get_mut(chunk_idx) {
+                compactor.add(&kmsg)?;
+                continue;
+            }
+            let chunk = &mut chunks[chunk_idx];
+            chunk
+                .data
+                .append(&mut kafka_message_len(&kmsg).to_be_bytes().to_vec());
+            chunk.data.append(&mut kafka_message_pack(&kmsg));
+            chunk.records += 1;
+        }
+        for ((mut chunk, offsets), encoder) in
+            chunks.into_iter().zip(next_offsets).zip(encoders.iter())
+        {
+            if chunk.records == 0 {
+                continue;
+            }
+            chunk.offsets = offsets
+                .into_iter()
+                .map(|((topic_idx, partition), offset)| {
+                    (topic_names[topic_idx].clone(), partition, offset)
+                })
+                .collect();
+            if max_capacity < chunk.data.len() {
+                max_capacity = chunk.data.len();
+            }
+            match encoder.send(chunk) {
+                Ok(_) => (),
+                Err(e) => return Err(AppError::Send2Encoder(e.to_string())),
+            };
         }
     }
+
+    for (compactor, encoder) in compactors.into_iter().zip(encoders.iter()) {
+        let (kept, dropped) = compactor.finish(|chunk| {
+            encoder
+                .send(chunk)
+                .map_err(|e| AppError::Send2Encoder(e.to_string()))
+        })?;
+        info!("Compacted records kept:{} dropped:{}", kept, dropped);
+    }
     Ok(())
 }
 
+// Set by SIGTERM or SIGINT, the second signal terminates the process at once
+fn stop_on_signals() -> Result<Arc<AtomicBool>, AppError> {
+    let stop = Arc::new(AtomicBool::new(false));
+    for signal in [SIGTERM, SIGINT] {
+        flag::register_conditional_shutdown(signal, 1, stop.clone())
+            .and_then(|_| flag::register(signal, stop.clone()))
+            .map_err(|e| AppError::IoError(e.to_string()))?;
+    }
+    Ok(stop)
+}
+
 fn consumer_process(
     mut consumer: MyConsumer,
-    sender: SyncSender<Vec<rdkafka::message::OwnedMessage>>,
+    sender: SyncSender<Vec<OwnedMessage>>,
 ) -> Result<(), AppError> {
     let mut messages = 0;
     let mut last_batch = false;
+    // A following consumer wakes up to send what it has and to see the stop
+    let timeout = consumer.following().then_some(FOLLOW_POLL_TIMEOUT);
 
     loop {
         let mut batch = Vec::with_capacity(1000);
 
         for _ in 0..1000 {
-            let rd_msg = consumer.poll(None);
+            let rd_msg = consumer.poll(timeout);
             match rd_msg {
                 Some(msg) => match msg {
                     Ok(msg) => {
                         batch.push(msg);
                         messages += 1;
                     }
-                    Err(e) if e == AppError::EOF => {
+                    Err(AppError::Eof) => {
                         last_batch = true;
                         break;
                     }
-                    Err(e) => panic!("Error:{}", e.to_string()),
+                    Err(e) if recoverable(&e) => warn!("Consumer error, polling again: {:?}", e),
+                    Err(e) => return Err(e),
                 },
+                None if consumer.following() => break,
                 None => {
                     continue;
                 }
             };
         }
 
-        sender.send(batch).unwrap();
+        if batch.is_empty() && !last_batch {
+            continue;
+        }
+        // The packer stopped, its error is the cause
+        sender
+            .send(batch)
+            .map_err(|e| AppError::Send2Encoder(e.to_string()))?;
 
         if messages % 1000000 == 0 {
             info!("Recevied: 1M Total:{}", messages);
@@ -103,43 +307,299 @@ fn consumer_process(
     Ok(())
 }
 
+/// What and how to backup
+pub struct BackupOptions {
+    pub topics: Vec<String>,
+    pub topic_regex: Option<Regex>,
+    pub codec: Codec,
+    pub level: u32,
+    /// Compression threads of every archive
+    pub workers: usize,
+    pub bounds: Bounds,
+    pub incremental_from: Option<String>,
+    /// Write every topic into its own archive in the `file` directory
+    pub split_topics: bool,
+    /// Continue the interrupted backup from its checkpoints
+    pub resume: bool,
+    /// Records have to match all filters
+    pub filters: Vec<Filter>,
+    /// Schema registry to snapshot the schemas of the topics from
+    pub schema_registry: Option<String>,
+    /// Keep only the latest record of every key, with the count of keys indexed in memory
+    pub compact: Option<usize>,
+    /// Consumer groups to record the committed offsets of
+    pub groups: Vec<String>,
+    /// Roll the archive into volumes with a manifest at these limits
+    pub volumes: VolumeLimits,
+    /// Encrypt the blocks with the key
+    pub keys: Option<Keys>,
+    /// Consumer group of the backup, a following backup commits its position under it
+    pub group_id: String,
+    /// Keep consuming new records into volumes until SIGTERM or SIGINT
+    pub follow: bool,
+}
+
+// Archives of the interrupted backup with their checkpoints, finished archives have none
+fn interrupted_archives(file: &str, opts: &BackupOptions) -> Result<Vec<Archive>, AppError> {
+    let candidates = if opts.split_topics {
+        list_archives(file)?
+    } else {
+        vec![archive_path(file.to_string(), opts.codec)]
+    };
+
+    let mut archives = vec![];
+    for pathfile in candidates {
+        let Some(checkpoint) = checkpoint::load(&checkpoint_path(&pathfile, "checkpoint"))? else {
+            continue;
+        };
+        let header = read_archive_header(&pathfile)?.ok_or_else(|| {
+            AppError::BadArchive(format!("`{}` has no header to resume", pathfile))
+        })?;
+        archives.push((pathfile, header, Some(checkpoint)));
+    }
+    if archives.is_empty() {
+        return Err(AppError::InvalidArgument(format!(
+            "there is no interrupted backup in `{}` to resume",
+            file
+        )));
+    }
+    Ok(archives)
+}
+
+// Backups the same topics, ranges and filters as the interrupted archives since their checkpoints
+fn resume_bounds(archives: &[Archive], opts: &mut BackupOptions) -> Result<(), AppError> {
+    opts.topics.clear();
+    opts.topic_regex = None;
+    opts.filters.clear();
+    for (pathfile, header, checkpoint) in archives.iter() {
+        if opts.filters.is_empty() {
+            for filter in header.filters.iter() {
+                opts.filters.push(filter.parse().map_err(|e| {
+                    AppError::BadArchive(format!("`{}` has a bad filter: {}", pathfile, e))
+                })?);
+            }
+        }
+        for topic in header.topics.iter() {
+            opts.topics.push(topic.name().to_string());
+            for p in topic.partitions.iter() {
+                let key = (topic.name().to_string(), p.partition());
+                let offset_end = p.offset_end.unwrap_or(p.high_watermark());
+                opts.bounds.until.insert(key.clone(), offset_end);
+                opts.bounds
+                    .resume_from
+                    .insert(key, p.offset_begin.unwrap_or(p.low_watermark()));
+            }
+        }
+        if let Some(checkpoint) = checkpoint {
+            opts.bounds.resume_from.extend(checkpoint.resume_from());
+        }
+    }
+    Ok(())
+}
+
 pub fn backup(
-    brokers: String,
-    topic_name: String,
+    config: KafkaConfig,
     file: String,
-    level: u32,
+    mut opts: BackupOptions,
     log_enabled: bool,
+    metrics: Arc<Metrics>,
 ) -> Result<(), AppError> {
-    let mut consumer = MyConsumer::new(brokers, &topic_name)?;
-    let mb = MProgressBars::backup(&consumer, log_enabled)?;
-    MProgressBars::ticker(mb.clone());
+    let mut base_archives = HashMap::new();
+    if let Some(pathfile) = &opts.incremental_from {
+        (opts.bounds.resume_from, base_archives) = incremental_base(pathfile)?;
+    }
+
+    if (is_stdio(&file) || is_remote(&file)) && (opts.split_topics || opts.resume) {
+        return Err(AppError::InvalidArgument(
+            "--split-topics and --resume need a local file".to_string(),
+        ));
+    }
+    if is_stdio(&file) && opts.volumes.is_set() {
+        return Err(AppError::InvalidArgument(
+            "volumes need a file, not stdout".to_string(),
+        ));
+    }
+    if opts.follow && !opts.volumes.is_set() {
+        return Err(AppError::InvalidArgument(
+            "--follow needs --max-volume-size, --max-volume-records or --max-volume-time"
+                .to_string(),
+        ));
+    }
 
-    consumer.assign(None, None)?;
+    let interrupted = if opts.resume {
+        let archives = interrupted_archives(&file, &opts)?;
+        resume_bounds(&archives, &mut opts)?;
+        archives
+    } else {
+        vec![]
+    };
 
-    let (sender2encoder, encoder_handler) = GzWriter::run(file, level)?;
+    let mut consumer = MyConsumer::new(
+        &config,
+        &opts.group_id,
+        &opts.topics,
+        opts.topic_regex.as_ref(),
+    )?;
+    let topic_partitions: Vec<(String, i32)> = (0..consumer.topics())
+        .map(|idx| {
+            (
+                consumer.topic_name(idx).to_string(),
+                consumer.partitions(idx),
+            )
+        })
+        .collect();
+    check_single_topic(&opts.bounds.offsets, topic_partitions.len())?;
+    if opts.follow {
+        // A restarted backup continues from the position it committed
+        let committed =
+            groups::committed_offsets(&config, &[opts.group_id.clone()], &topic_partitions)?;
+        for o in committed {
+            opts.bounds
+                .resume_from
+                .insert((o.topic().to_string(), o.partition()), o.offset());
+        }
+        consumer.follow(stop_on_signals()?);
+    }
+    consumer.assign(&opts.bounds)?;
+
+    let filters: Vec<String> = opts.filters.iter().map(|f| f.to_string()).collect();
+    let group_offsets = groups::committed_offsets(&config, &opts.groups, &topic_partitions)?;
+    let archives: Vec<Archive> = if opts.resume {
+        interrupted
+    } else if opts.split_topics {
+        let topics = topics_metadata(&config, &consumer, opts.schema_registry.as_deref())?;
+        std::fs::create_dir_all(&file).map_err(|e| AppError::IoError(e.to_string()))?;
+        topics
+            .into_iter()
+            .map(|topic| {
+                let name = format!("{}.{}", topic.name(), opts.codec.extension());
+                let mut header = archive_header_new(config.brokers(), vec![topic]);
+                header.filters = filters.clone();
+                header.group_offsets = group_offsets
+                    .iter()
+                    .filter(|o| o.topic() == header.topics[0].name())
+                    .cloned()
+                    .collect();
+                header.base_archive = base_archives.get(header.topics[0].name()).cloned();
+                (
+                    Path::new(&file).join(name).to_string_lossy().to_string(),
+                    header,
+                    None,
+                )
+            })
+            .collect()
+    } else {
+        let topics = topics_metadata(&config, &consumer, opts.schema_registry.as_deref())?;
+        let mut header = archive_header_new(config.brokers(), topics);
+        header.filters = filters;
+        header.group_offsets = group_offsets;
+        header.base_archive = opts.incremental_from.as_ref().map(|base| {
+            let name = Path::new(base).file_name().unwrap_or_default();
+            name.to_string_lossy().to_string()
+        });
+        vec![(file, header, None)]
+    };
+
+    let mb = MProgressBars::backup(&consumer, log_enabled, Some(metrics.clone()))?;
+    MProgressBars::ticker(mb.clone());
+
+    let mut committer = match opts.follow {
+        true => Some(PositionCommitter::new(&config, &opts.group_id)?),
+        false => None,
+    };
+    let mut encoders = Vec::with_capacity(archives.len());
+    let mut encoder_handlers = Vec::with_capacity(archives.len());
+    let mut compactors = vec![];
+    for (pathfile, mut header, checkpoint) in archives {
+        if let Some(memory_keys) = opts.compact {
+            // Records of an archive on stdout or object storage are compacted
+            // in the temporary directory
+            let dir = if is_stdio(&pathfile) || is_remote(&pathfile) {
+                std::env::temp_dir()
+                    .join(format!("akbt-compact-{}", std::process::id()))
+                    .to_string_lossy()
+                    .to_string()
+            } else {
+                checkpoint_path(&pathfile, "compact")
+            };
+            compactors.push(Compactor::new(Path::new(&dir), memory_keys)?);
+            header.compacted = Some(true);
+        }
+        let encoding = BlockEncoding {
+            codec: opts.codec,
+            level: opts.level,
+            workers: opts.workers,
+            keys: opts.keys.clone(),
+        };
+        let (sender2encoder, encoder_handler) = if opts.volumes.is_set() {
+            StreamWriter::run_volumes(
+                pathfile,
+                encoding,
+                header,
+                opts.volumes,
+                committer.take(),
+                Some(metrics.clone()),
+            )?
+        } else {
+            StreamWriter::run(
+                pathfile,
+                encoding,
+                header,
+                checkpoint,
+                Some(metrics.clone()),
+            )?
+        };
+        encoders.push(sender2encoder);
+        encoder_handlers.push(encoder_handler);
+    }
     let (sender2worker, receiver) = sync_channel(2);
 
+    let topic_index = (0..consumer.topics())
+        .map(|idx| (consumer.topic_name(idx).to_string(), idx))
+        .collect();
     let mb_clone = mb.clone();
-    let pack_handler = thread::spawn(move || pack_process(receiver, sender2encoder, mb_clone));
+    let filters = opts.filters;
+    let pack_handler = thread::spawn(move || {
+        pack_process(
+            receiver,
+            encoders,
+            compactors,
+            topic_index,
+            filters,
+            mb_clone,
+        )
+    });
 
     let consumer_handler = thread::spawn(move || consumer_process(consumer, sender2worker));
 
-    match consumer_handler.join() {
-        Ok(_) => info!("Consumer closed"),
-        Err(e) => error!("Consumer closed with error:{:?}", e),
-    }
+    let consumed = consumer_handler
+        .join()
+        .unwrap_or_else(|_| Err(AppError::Panicked("consumer".to_string())));
+    info!("Consumer closed");
 
-    match pack_handler.join() {
-        Ok(_) => info!("Worker closed"),
-        Err(e) => error!("Worker closed with error:{:?}", e),
-    }
+    // Encoders finish the archives even if packing failed, the error is returned after
+    let packed = match pack_handler.join() {
+        Ok(packed) => {
+            info!("Worker closed");
+            packed
+        }
+        Err(_) => Err(AppError::Panicked("pack".to_string())),
+    };
 
-    match encoder_handler.join() {
-        Ok(_) => info!("Encoder closed"),
-        Err(e) => error!("Encoder closed with error:{:?}", e),
+    let mut encoded = Ok(());
+    for encoder_handler in encoder_handlers {
+        let result = encoder_handler
+            .join()
+            .unwrap_or_else(|_| Err(AppError::Panicked("encoder".to_string())));
+        if let Err(e) = &result {
+            error!("Encoder closed with error:{}", e);
+        }
+        encoded = encoded.and(result);
     }
+    info!("Encoders closed");
 
     mb.lock().unwrap().finish();
 
-    Ok(())
+    // A failed writer stops packing and a failed packer stops consuming, the first is the cause
+    encoded.and(packed).and(consumed)
 }
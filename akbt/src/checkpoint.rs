@@ -0,0 +1,137 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::Path,
+};
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{errors::AppError, storage::is_remote};
+
+/// Progress of an unfinished archive
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct BackupCheckpoint {
+    /// Length of the archive up to the end of the last written block
+    pub length: u64,
+    pub blocks: u64,
+    pub records: u64,
+    /// Next offset to backup per topic and partition
+    pub offsets: BTreeMap<String, BTreeMap<i32, i64>>,
+}
+
+impl BackupCheckpoint {
+    pub fn resume_from(&self) -> HashMap<(String, i32), i64> {
+        self.offsets
+            .iter()
+            .flat_map(|(topic, partitions)| {
+                partitions
+                    .iter()
+                    .map(|(partition, offset)| ((topic.clone(), *partition), *offset))
+            })
+            .collect()
+    }
+}
+
+/// Progress of an unfinished restore
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct RestoreCheckpoint {
+    /// Archives restored completely
+    pub done: Vec<String>,
+    /// Archive being restored
+    pub archive: Option<String>,
+    /// Records of the archive produced to Kafka
+    pub records: u64,
+    /// Partition streams of a manifest, every stream has its own checkpoint
+    #[serde(default)]
+    pub streams: usize,
+}
+
+// Checkpoints are hidden files next to the archive, so they aren't taken for archives.
+// Checkpoints of remote archives are kept in the current directory.
+pub fn checkpoint_path(pathfile: &str, suffix: &str) -> String {
+    let path = Path::new(pathfile);
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    if is_remote(pathfile) {
+        return format!(".{}.{}", name, suffix);
+    }
+    path.with_file_name(format!(".{}.{}", name, suffix))
+        .to_string_lossy()
+        .to_string()
+}
+
+// Replaces the checkpoint atomically, so an interruption leaves the previous one
+pub fn save<T: Serialize>(pathfile: &str, checkpoint: &T) -> Result<(), AppError> {
+    let tmp = format!("{}.tmp", pathfile);
+    let data = serde_json::to_vec(checkpoint).map_err(|e| AppError::IoError(e.to_string()))?;
+    std::fs::write(&tmp, data)
+        .and_then(|_| std::fs::rename(&tmp, pathfile))
+        .map_err(|e| AppError::IoError(e.to_string()))
+}
+
+pub fn load<T: DeserializeOwned>(pathfile: &str) -> Result<Option<T>, AppError> {
+    if !Path::new(pathfile).exists() {
+        return Ok(None);
+    }
+    let data = std::fs::read(pathfile).map_err(|e| AppError::IoError(e.to_string()))?;
+    serde_json::from_slice(&data)
+        .map(Some)
+        .map_err(|e| AppError::IoError(format!("bad checkpoint `{}`: {}", pathfile, e)))
+}
+
+pub fn remove(pathfile: &str) -> Result<(), AppError> {
+    match std::fs::remove_file(pathfile) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(AppError::IoError(e.to_string())),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hidden_next_to_archive() {
+        assert_eq!(
+            checkpoint_path("/backups/topic.gz", "checkpoint"),
+            "/backups/.topic.gz.checkpoint"
+        );
+        assert_eq!(
+            checkpoint_path("topic.gz", "checkpoint"),
+            ".topic.gz.checkpoint"
+        );
+        assert_eq!(
+            checkpoint_path("s3://bucket/backups/topic.gz", "restore-checkpoint"),
+            ".topic.gz.restore-checkpoint"
+        );
+    }
+
+    #[test]
+    fn save_and_load() {
+        let pathfile = std::env::temp_dir()
+            .join(format!("akbt-checkpoint-{}", std::process::id()))
+            .to_string_lossy()
+            .to_string();
+        let mut checkpoint = BackupCheckpoint {
+            length: 100,
+            blocks: 2,
+            records: 10,
+            ..Default::default()
+        };
+        checkpoint
+            .offsets
+            .entry("topic".to_string())
+            .or_default()
+            .insert(1, 42);
+
+        save(&pathfile, &checkpoint).unwrap();
+        let loaded: BackupCheckpoint = load(&pathfile).unwrap().unwrap();
+        assert_eq!(loaded, checkpoint);
+        assert_eq!(
+            loaded.resume_from(),
+            HashMap::from([(("topic".to_string(), 1), 42)])
+        );
+
+        remove(&pathfile).unwrap();
+        assert_eq!(load::<BackupCheckpoint>(&pathfile).unwrap(), None);
+        remove(&pathfile).unwrap();
+    }
+}
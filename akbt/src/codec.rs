@@ -0,0 +1,158 @@
+use std::io::{self, BufRead, Read, Write};
+
+use clap::ValueEnum;
+use flate2::{bufread::GzDecoder, write::GzEncoder, Compression as GzCompression};
+
+use crate::{errors::AppError, protos::kafka_archive::Compression};
+
+/// Compression of the records stream
+#[derive(ValueEnum, Debug, Clone, Copy, Default, PartialEq)]
+pub enum Codec {
+    #[default]
+    Gzip,
+    Zstd,
+    Lz4,
+    None,
+}
+
+/// Compressing writer of the records stream
+pub trait Encoder: Write + Send {
+    /// Writes the end of the stream and flushes the underlying writer
+    fn finish(self: Box<Self>) -> io::Result<()>;
+}
+
+impl<W: Write + Send> Encoder for GzEncoder<W> {
+    fn finish(self: Box<Self>) -> io::Result<()> {
+        GzEncoder::finish(*self)?.flush()
+    }
+}
+
+impl<W: Write + Send> Encoder for zstd::stream::write::Encoder<'static, W> {
+    fn finish(self: Box<Self>) -> io::Result<()> {
+        zstd::stream::write::Encoder::finish(*self)?.flush()
+    }
+}
+
+impl<W: Write + Send> Encoder for lz4_flex::frame::FrameEncoder<W> {
+    fn finish(self: Box<Self>) -> io::Result<()> {
+        lz4_flex::frame::FrameEncoder::finish(*self)
+            .map_err(io::Error::other)?
+            .flush()
+    }
+}
+
+struct Plain<W>(W);
+
+impl<W: Write> Write for Plain<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl<W: Write + Send> Encoder for Plain<W> {
+    fn finish(mut self: Box<Self>) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl Codec {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Codec::Gzip => "gz",
+            Codec::Zstd => "zst",
+            Codec::Lz4 => "lz4",
+            Codec::None => "bin",
+        }
+    }
+
+    pub fn check_level(&self, level: u32) -> Result<(), AppError> {
+        let max = match self {
+            Codec::Gzip => 9,
+            Codec::Zstd => 22,
+            Codec::Lz4 | Codec::None => return Ok(()),
+        };
+        if level > max {
+            return Err(AppError::InvalidArgument(format!(
+                "--level of {:?} is 0-{}",
+                self, max
+            )));
+        }
+        Ok(())
+    }
+
+    pub fn encoder<'a, W: Write + Send + 'a>(
+        &self,
+        writer: W,
+        level: u32,
+    ) -> io::Result<Box<dyn Encoder + 'a>> {
+        Ok(match self {
+            Codec::Gzip => Box::new(GzEncoder::new(writer, GzCompression::new(level))),
+            // Level 0 is the zstd default
+            Codec::Zstd => Box::new(zstd::stream::write::Encoder::new(writer, level as i32)?),
+            Codec::Lz4 => Box::new(lz4_flex::frame::FrameEncoder::new(writer)),
+            Codec::None => Box::new(Plain(writer)),
+        })
+    }
+
+    pub fn decoder<'a, R: BufRead + Send + 'a>(
+        &self,
+        reader: R,
+    ) -> io::Result<Box<dyn Read + Send + 'a>> {
+        Ok(match self {
+            Codec::Gzip => Box::new(GzDecoder::new(reader)),
+            Codec::Zstd => Box::new(zstd::stream::read::Decoder::with_buffer(reader)?),
+            Codec::Lz4 => Box::new(lz4_flex::frame::FrameDecoder::new(reader)),
+            Codec::None => Box::new(reader),
+        })
+    }
+}
+
+impl From<Codec> for Compression {
+    fn from(codec: Codec) -> Self {
+        match codec {
+            Codec::Gzip => Compression::Gzip,
+            Codec::Zstd => Compression::Zstd,
+            Codec::Lz4 => Compression::Lz4,
+            Codec::None => Compression::None,
+        }
+    }
+}
+
+impl From<Compression> for Codec {
+    fn from(compression: Compression) -> Self {
+        match compression {
+            Compression::Gzip => Codec::Gzip,
+            Compression::Zstd => Codec::Zstd,
+            Compression::Lz4 => Codec::Lz4,
+            Compression::None => Codec::None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roundtrip() {
+        let data = b"records of the topic ".repeat(100);
+        for codec in Codec::value_variants() {
+            let mut buf = vec![];
+            let mut encoder = codec.encoder(&mut buf, 1).unwrap();
+            encoder.write_all(&data).unwrap();
+            encoder.finish().unwrap();
+
+            let mut decoded = vec![];
+            codec
+                .decoder(buf.as_slice())
+                .unwrap()
+                .read_to_end(&mut decoded)
+                .unwrap();
+            assert_eq!(decoded, data, "{:?}", codec);
+        }
+    }
+}
@@ -0,0 +1,282 @@
+use std::{
+    cmp::Reverse,
+    collections::{hash_map::DefaultHasher, BinaryHeap, HashMap},
+    fs::File,
+    hash::{Hash, Hasher},
+    io::{self, BufReader, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+};
+
+use log::info;
+
+use crate::{
+    errors::AppError,
+    protos::kafka_messages::{kafka_message_len, kafka_message_pack, KafkaMessage},
+    stream::StreamMsg,
+};
+
+const BLOCK_RECORDS: u64 = 1000;
+/// Default count of keys indexed in memory before the index is spilled to disk
+pub const DEFAULT_MEMORY_KEYS: usize = 1_000_000;
+
+// Topic index, partition and hash of the key
+type KeyId = (u32, u32, u128);
+const ENTRY_LEN: usize = 4 + 4 + 16 + 8;
+
+fn io_error(e: io::Error) -> AppError {
+    AppError::IoError(e.to_string())
+}
+
+// Two independent 64 bit hashes, so colliding keys are practically impossible
+fn key_hash(key: &[u8]) -> u128 {
+    let mut first = DefaultHasher::new();
+    key.hash(&mut first);
+    let mut second = DefaultHasher::new();
+    (key.len(), key).hash(&mut second);
+    ((first.finish() as u128) << 64) | second.finish() as u128
+}
+
+fn write_entry(
+    mut writer: impl Write,
+    (topic, partition, hash): KeyId,
+    seq: u64,
+) -> io::Result<()> {
+    writer.write_all(&topic.to_be_bytes())?;
+    writer.write_all(&partition.to_be_bytes())?;
+    writer.write_all(&hash.to_be_bytes())?;
+    writer.write_all(&seq.to_be_bytes())
+}
+
+fn read_entry(mut reader: impl Read) -> io::Result<Option<(KeyId, u64)>> {
+    let mut buf = [0; ENTRY_LEN];
+    match reader.read_exact(&mut buf) {
+        Ok(()) => (),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    let topic = u32::from_be_bytes(buf[0..4].try_into().unwrap());
+    let partition = u32::from_be_bytes(buf[4..8].try_into().unwrap());
+    let hash = u128::from_be_bytes(buf[8..24].try_into().unwrap());
+    let seq = u64::from_be_bytes(buf[24..32].try_into().unwrap());
+    Ok(Some(((topic, partition, hash), seq)))
+}
+
+/// Keeps only the latest record of every key, tombstones included.
+/// Records are spilled to a file in `dir` as they come, the index of the latest record
+/// per key is kept in memory up to `memory_keys` keys and spilled to sorted runs beyond.
+pub struct Compactor {
+    dir: PathBuf,
+    data: BufWriter<File>,
+    index: HashMap<KeyId, u64>,
+    memory_keys: usize,
+    runs: Vec<PathBuf>,
+    records: u64,
+    /// Records without a key can't be compacted and are kept
+    keyless: Vec<u64>,
+}
+
+impl Compactor {
+    pub fn new(dir: &Path, memory_keys: usize) -> Result<Self, AppError> {
+        std::fs::create_dir_all(dir).map_err(io_error)?;
+        let data = File::create(dir.join("records")).map_err(io_error)?;
+        Ok(Compactor {
+            dir: dir.to_path_buf(),
+            data: BufWriter::new(data),
+            index: HashMap::new(),
+            memory_keys: memory_keys.max(1),
+            runs: vec![],
+            records: 0,
+            keyless: vec![],
+        })
+    }
+
+    pub fn add(&mut self, kmsg: &KafkaMessage) -> Result<(), AppError> {
+        let seq = self.records;
+        self.records += 1;
+        self.data
+            .write_all(&kafka_message_len(kmsg).to_be_bytes())
+            .and_then(|_| self.data.write_all(&kafka_message_pack(kmsg)))
+            .map_err(io_error)?;
+
+        let Some(key) = &kmsg.key else {
+            self.keyless.push(seq);
+            return Ok(());
+        };
+        self.index
+            .insert((kmsg.topic(), kmsg.partition(), key_hash(key)), seq);
+        if self.index.len() >= self.memory_keys {
+            self.spill()?;
+        }
+        Ok(())
+    }
+
+    // Writes the index sorted by key into a new run
+    fn spill(&mut self) -> Result<(), AppError> {
+        let mut entries: Vec<(KeyId, u64)> = self.index.drain().collect();
+        entries.sort_unstable();
+        let path = self.dir.join(format!("run-{}", self.runs.len()));
+        let mut writer = BufWriter::new(File::create(&path).map_err(io_error)?);
+        for (key, seq) in entries {
+            write_entry(&mut writer, key, seq).map_err(io_error)?;
+        }
+        writer.flush().map_err(io_error)?;
+        self.runs.push(path);
+        Ok(())
+    }
+
+    // Merges the runs, the latest record of every key is marked in the bitmap
+    fn latest_records(&mut self) -> Result<Vec<u64>, AppError> {
+        let mut keep = vec![0u64; (self.records as usize).div_ceil(64)];
+        let mut mark = |seq: u64| keep[(seq / 64) as usize] |= 1 << (seq % 64);
+        self.keyless.iter().for_each(|seq| mark(*seq));
+        if !self.index.is_empty() {
+            self.spill()?;
+        }
+
+        let mut readers = Vec::with_capacity(self.runs.len());
+        let mut heap = BinaryHeap::new();
+        for (run, path) in self.runs.iter().enumerate() {
+            let mut reader = BufReader::new(File::open(path).map_err(io_error)?);
+            if let Some((key, seq)) = read_entry(&mut reader).map_err(io_error)? {
+                heap.push(Reverse((key, seq, run)));
+            }
+            readers.push(reader);
+        }
+
+        let mut latest: Option<(KeyId, u64)> = None;
+        while let Some(Reverse((key, seq, run))) = heap.pop() {
+            match latest {
+                Some((latest_key, latest_seq)) if latest_key == key => {
+                    latest = Some((key, latest_seq.max(seq)))
+                }
+                Some((_, latest_seq)) => {
+                    mark(latest_seq);
+                    latest = Some((key, seq));
+                }
+                None => latest = Some((key, seq)),
+            }
+            if let Some((key, seq)) = read_entry(&mut readers[run]).map_err(io_error)? {
+                heap.push(Reverse((key, seq, run)));
+            }
+        }
+        if let Some((_, seq)) = latest {
+            mark(seq);
+        }
+        Ok(keep)
+    }
+
+    /// Sends the kept records in blocks, returns the counts of kept and dropped records
+    pub fn finish(
+        mut self,
+        mut send: impl FnMut(StreamMsg) -> Result<(), AppError>,
+    ) -> Result<(u64, u64), AppError> {
+        self.data.flush().map_err(io_error)?;
+        let keep = self.latest_records()?;
+        info!(
+            "Compacting records:{} index runs:{}",
+            self.records,
+            self.runs.len()
+        );
+
+        let file = File::open(self.dir.join("records")).map_err(io_error)?;
+        let mut reader = BufReader::new(file);
+        let mut chunk = StreamMsg::default();
+        let mut kept = 0;
+        for seq in 0..self.records {
+            let mut len = [0; 8];
+            reader.read_exact(&mut len).map_err(io_error)?;
+            let mut data = vec![0; u64::from_be_bytes(len) as usize];
+            reader.read_exact(&mut data).map_err(io_error)?;
+            if keep[(seq / 64) as usize] & (1 << (seq % 64)) == 0 {
+                continue;
+            }
+            chunk.data.extend_from_slice(&len);
+            chunk.data.extend_from_slice(&data);
+            chunk.records += 1;
+            kept += 1;
+            if chunk.records >= BLOCK_RECORDS {
+                send(std::mem::take(&mut chunk))?;
+            }
+        }
+        if chunk.records > 0 {
+            send(chunk)?;
+        }
+        std::fs::remove_dir_all(&self.dir).map_err(io_error)?;
+        Ok((kept, self.records - kept))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protos::kafka_messages::{kafka_message_new, kafka_message_unpack, TimestampType};
+
+    fn record(partition: u32, key: Option<&str>, value: Option<&str>, offset: i64) -> KafkaMessage {
+        kafka_message_new(
+            key.map(|k| k.as_bytes().to_vec()),
+            value.map(|v| v.as_bytes().to_vec()),
+            Some(partition),
+            vec![],
+            None,
+            TimestampType::NotAvailable,
+            Some(offset),
+        )
+    }
+
+    fn offsets(msg: &StreamMsg) -> Vec<i64> {
+        let mut offsets = vec![];
+        let mut data = msg.data.as_slice();
+        while !data.is_empty() {
+            let len = u64::from_be_bytes(data[..8].try_into().unwrap()) as usize;
+            offsets.push(kafka_message_unpack(&data[8..8 + len]).unwrap().offset());
+            data = &data[8 + len..];
+        }
+        offsets
+    }
+
+    #[test]
+    fn latest_record_of_every_key_is_kept() {
+        // A tiny index spills runs, the result doesn't depend on it
+        for memory_keys in [DEFAULT_MEMORY_KEYS, 2] {
+            let dir = std::env::temp_dir().join(format!(
+                "akbt-compact-{}-{}",
+                std::process::id(),
+                memory_keys
+            ));
+            let mut compactor = Compactor::new(&dir, memory_keys).unwrap();
+            let records = [
+                record(0, Some("a"), Some("1"), 0),
+                record(0, Some("b"), Some("1"), 1),
+                record(0, None, Some("no key"), 2),
+                record(0, Some("a"), Some("2"), 3),
+                record(1, Some("a"), Some("1"), 0),
+                record(0, Some("b"), None, 4),
+                record(0, Some("c"), Some("1"), 5),
+                record(0, Some("c"), Some("2"), 6),
+            ];
+            for kmsg in records.iter() {
+                compactor.add(kmsg).unwrap();
+            }
+
+            let mut blocks = vec![];
+            let counts = compactor
+                .finish(|msg| {
+                    blocks.push(msg);
+                    Ok(())
+                })
+                .unwrap();
+            assert_eq!(counts, (5, 3));
+            assert_eq!(blocks.len(), 1);
+            // Offset 0 is the record of partition 1, offset 4 is the tombstone of b
+            assert_eq!(offsets(&blocks[0]), vec![2, 3, 0, 4, 6]);
+            assert!(!dir.exists());
+        }
+    }
+
+    #[test]
+    fn keys_are_distinguished() {
+        assert_ne!(key_hash(b"a"), key_hash(b"b"));
+        assert_ne!(key_hash(b""), key_hash(b"\0"));
+        assert_eq!(key_hash(b"key"), key_hash(b"key"));
+    }
+}
@@ -0,0 +1,236 @@
+use std::collections::BTreeMap;
+
+use clap::ValueEnum;
+use rdkafka::ClientConfig;
+
+use crate::errors::AppError;
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq)]
+pub enum SecurityProtocol {
+    Plaintext,
+    Ssl,
+    #[value(name = "sasl_plaintext")]
+    SaslPlaintext,
+    #[value(name = "sasl_ssl")]
+    SaslSsl,
+}
+
+impl SecurityProtocol {
+    fn as_str(&self) -> &'static str {
+        match self {
+            SecurityProtocol::Plaintext => "plaintext",
+            SecurityProtocol::Ssl => "ssl",
+            SecurityProtocol::SaslPlaintext => "sasl_plaintext",
+            SecurityProtocol::SaslSsl => "sasl_ssl",
+        }
+    }
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq)]
+pub enum SaslMechanism {
+    #[value(name = "PLAIN")]
+    Plain,
+    #[value(name = "SCRAM-SHA-256")]
+    ScramSha256,
+    #[value(name = "SCRAM-SHA-512")]
+    ScramSha512,
+}
+
+impl SaslMechanism {
+    fn as_str(&self) -> &'static str {
+        match self {
+            SaslMechanism::Plain => "PLAIN",
+            SaslMechanism::ScramSha256 => "SCRAM-SHA-256",
+            SaslMechanism::ScramSha512 => "SCRAM-SHA-512",
+        }
+    }
+}
+
+/// Authentication, TLS and other librdkafka properties of the cluster
+#[derive(clap::Args, Debug, Clone, Default)]
+pub struct ConnectionArgs {
+    /// Properties file of the cluster with librdkafka KEY=VALUE lines, options override it
+    #[arg(long, env("KAFKA_CONFIG"))]
+    pub kafka_config: Option<String>,
+    #[arg(long, value_enum, env("SECURITY_PROTOCOL"))]
+    pub security_protocol: Option<SecurityProtocol>,
+    #[arg(long, value_enum, env("SASL_MECHANISM"))]
+    pub sasl_mechanism: Option<SaslMechanism>,
+    #[arg(long, env("SASL_USERNAME"))]
+    pub sasl_username: Option<String>,
+    #[arg(long, env("SASL_PASSWORD"), hide_env_values = true)]
+    pub sasl_password: Option<String>,
+    /// CA certificate file to verify the brokers
+    #[arg(long, env("SSL_CA_LOCATION"))]
+    pub ssl_ca_location: Option<String>,
+    /// Client certificate file
+    #[arg(long, env("SSL_CERTIFICATE_LOCATION"))]
+    pub ssl_certificate_location: Option<String>,
+    /// Client private key file
+    #[arg(long, env("SSL_KEY_LOCATION"))]
+    pub ssl_key_location: Option<String>,
+    #[arg(long, env("SSL_KEY_PASSWORD"), hide_env_values = true)]
+    pub ssl_key_password: Option<String>,
+    /// librdkafka property <KEY=VALUE> of all clients, can be repeated, overrides everything else
+    #[arg(short = 'X', value_parser = parse_property)]
+    pub property: Vec<(String, String)>,
+}
+
+impl ConnectionArgs {
+    // Properties of the typed options
+    fn properties(&self) -> Vec<(&'static str, String)> {
+        let options = [
+            (
+                "security.protocol",
+                self.security_protocol.map(|p| p.as_str().to_string()),
+            ),
+            (
+                "sasl.mechanism",
+                self.sasl_mechanism.map(|m| m.as_str().to_string()),
+            ),
+            ("sasl.username", self.sasl_username.clone()),
+            ("sasl.password", self.sasl_password.clone()),
+            ("ssl.ca.location", self.ssl_ca_location.clone()),
+            (
+                "ssl.certificate.location",
+                self.ssl_certificate_location.clone(),
+            ),
+            ("ssl.key.location", self.ssl_key_location.clone()),
+            ("ssl.key.password", self.ssl_key_password.clone()),
+        ];
+        options
+            .into_iter()
+            .filter_map(|(key, value)| value.map(|value| (key, value)))
+            .collect()
+    }
+}
+
+pub fn parse_property(s: &str) -> Result<(String, String), String> {
+    match s.split_once('=') {
+        Some((key, value)) if !key.trim().is_empty() => {
+            Ok((key.trim().to_string(), value.trim().to_string()))
+        }
+        _ => Err(format!("`{}` isn't in format KEY=VALUE", s)),
+    }
+}
+
+// Lines KEY=VALUE, blank lines and lines starting with # or ! are skipped
+fn parse_properties(text: &str) -> Result<Vec<(String, String)>, String> {
+    text.lines()
+        .enumerate()
+        .map(|(idx, line)| (idx, line.trim()))
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with(['#', '!']))
+        .map(|(idx, line)| parse_property(line).map_err(|e| format!("line {}: {}", idx + 1, e)))
+        .collect()
+}
+
+/// Properties shared by the consumer, the producer and the admin client
+#[derive(Debug, Clone, Default)]
+pub struct KafkaConfig {
+    properties: BTreeMap<String, String>,
+}
+
+impl KafkaConfig {
+    // The properties file is overridden by the options, --bootstrap-servers and -X in turn
+    pub fn new(brokers: Option<String>, args: ConnectionArgs) -> Result<Self, AppError> {
+        let mut properties = BTreeMap::new();
+        if let Some(pathfile) = &args.kafka_config {
+            let text = std::fs::read_to_string(pathfile)
+                .map_err(|e| AppError::IoError(format!("`{}`: {}", pathfile, e)))?;
+            let file_properties = parse_properties(&text)
+                .map_err(|e| AppError::InvalidArgument(format!("`{}` {}", pathfile, e)))?;
+            properties.extend(file_properties);
+        }
+        for (key, value) in args.properties() {
+            properties.insert(key.to_string(), value);
+        }
+        if let Some(brokers) = brokers {
+            properties.insert("bootstrap.servers".to_string(), brokers);
+        }
+        properties.extend(args.property);
+
+        if !properties.contains_key("bootstrap.servers") {
+            return Err(AppError::MissingArgument("bootstrap-servers".to_string()));
+        }
+        Ok(KafkaConfig { properties })
+    }
+
+    pub fn brokers(&self) -> &str {
+        self.properties
+            .get("bootstrap.servers")
+            .map(|s| s.as_str())
+            .unwrap_or_default()
+    }
+
+    /// Client config with all properties, clients add their own settings
+    pub fn client_config(&self) -> ClientConfig {
+        let mut config = ClientConfig::new();
+        for (key, value) in self.properties.iter() {
+            config.set(key, value);
+        }
+        config
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn properties_file() {
+        let text = "# cluster\n\nbootstrap.servers = kafka:9093\n! old\nsasl.password=a=b\n";
+        assert_eq!(
+            parse_properties(text).unwrap(),
+            vec![
+                ("bootstrap.servers".to_string(), "kafka:9093".to_string()),
+                ("sasl.password".to_string(), "a=b".to_string()),
+            ]
+        );
+        assert_eq!(
+            parse_properties("a=1\nb\n").unwrap_err(),
+            "line 2: `b` isn't in format KEY=VALUE"
+        );
+    }
+
+    #[test]
+    fn options_override_the_file() {
+        let pathfile = std::env::temp_dir()
+            .join(format!("akbt-config-{}", std::process::id()))
+            .to_string_lossy()
+            .to_string();
+        std::fs::write(
+            &pathfile,
+            "bootstrap.servers=file:9092\nsasl.username=file\nclient.id=file\nacks=1\n",
+        )
+        .unwrap();
+        let args = ConnectionArgs {
+            kafka_config: Some(pathfile.clone()),
+            security_protocol: Some(SecurityProtocol::SaslSsl),
+            sasl_mechanism: Some(SaslMechanism::ScramSha512),
+            sasl_username: Some("option".to_string()),
+            property: vec![("acks".to_string(), "all".to_string())],
+            ..Default::default()
+        };
+        let config = KafkaConfig::new(None, args.clone()).unwrap();
+        std::fs::remove_file(&pathfile).unwrap();
+
+        assert_eq!(config.brokers(), "file:9092");
+        let client = config.client_config();
+        assert_eq!(client.get("security.protocol"), Some("sasl_ssl"));
+        assert_eq!(client.get("sasl.mechanism"), Some("SCRAM-SHA-512"));
+        assert_eq!(client.get("sasl.username"), Some("option"));
+        assert_eq!(client.get("client.id"), Some("file"));
+        assert_eq!(client.get("acks"), Some("all"));
+
+        let args = ConnectionArgs {
+            kafka_config: None,
+            ..args
+        };
+        let config = KafkaConfig::new(Some("option:9092".to_string()), args.clone()).unwrap();
+        assert_eq!(config.brokers(), "option:9092");
+        assert_eq!(
+            KafkaConfig::new(None, args).unwrap_err(),
+            AppError::MissingArgument("bootstrap-servers".to_string())
+        );
+    }
+}
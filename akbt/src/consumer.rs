@@ -1,75 +1,276 @@
+use crate::config::KafkaConfig;
 use crate::errors::AppError;
+use log::warn;
 use rdkafka::{
     self,
     consumer::{BaseConsumer, Consumer},
+    error::KafkaError,
     message::OwnedMessage,
     util::Timeout,
-    ClientConfig, Message, TopicPartitionList,
+    Message, Offset, TopicPartitionList,
+};
+use regex::Regex;
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
 };
-use std::{ops::Index, time::Duration};
 
 struct BackupContext;
 impl rdkafka::client::ClientContext for BackupContext {}
 impl rdkafka::consumer::ConsumerContext for BackupContext {}
 
+/// Part of the topics which has to be consumed.
+/// Without any bound the whole topic is consumed.
+#[derive(Debug, Clone, Default)]
+pub struct Bounds {
+    /// Unix time in milliseconds of the first record
+    pub from_time: Option<i64>,
+    /// Unix time in milliseconds, records since it are not consumed
+    pub to_time: Option<i64>,
+    /// Only the last N records of every partition
+    pub last: Option<i64>,
+    /// Explicit [begin, end) offsets per partition of the single topic, other partitions are skipped
+    pub offsets: HashMap<i32, (i64, i64)>,
+    /// Offsets where the previous backup stopped per topic and partition,
+    /// partitions without one start from the beginning
+    pub resume_from: HashMap<(String, i32), i64>,
+    /// End offsets per topic and partition of the interrupted backup
+    pub until: HashMap<(String, i32), i64>,
+}
+
+// Offsets ranges are keyed by partition only, so they can't tell topics apart
+pub fn check_single_topic(
+    offsets: &HashMap<i32, (i64, i64)>,
+    topics: usize,
+) -> Result<(), AppError> {
+    if !offsets.is_empty() && topics > 1 {
+        return Err(AppError::InvalidArgument(format!(
+            "--offsets needs a single topic, {} are selected",
+            topics
+        )));
+    }
+    Ok(())
+}
+
+/// Non-fatal consumer errors like a restarting broker, the client recovers from them by itself
+pub fn recoverable(e: &AppError) -> bool {
+    matches!(e, AppError::Kafka(KafkaError::MessageConsumption(_)))
+}
+
+// Narrows the watermarks (low, high) of the partition down to the bounds
+fn bounded_range(
+    part_idx: i32,
+    (low, high): (i64, i64),
+    bounds: &Bounds,
+    from_time: Option<&Offset>,
+    to_time: Option<&Offset>,
+    resume_from: Option<i64>,
+    until: Option<i64>,
+) -> (i64, i64) {
+    let (mut begin, mut end) = (low, high);
+
+    if !bounds.offsets.is_empty() {
+        match bounds.offsets.get(&part_idx) {
+            Some(&(b, e)) => (begin, end) = (b.max(low), e.min(high)),
+            None => return (high, high),
+        }
+    }
+    if let Some(offset) = from_time {
+        begin = match offset {
+            Offset::Offset(o) => begin.max(*o),
+            _ => high,
+        };
+    }
+    if let Some(o) = resume_from {
+        begin = begin.max(o);
+    }
+    if let Some(Offset::Offset(o)) = to_time {
+        end = end.min(*o);
+    }
+    if let Some(o) = until {
+        end = end.min(o);
+    }
+    if let Some(last) = bounds.last {
+        begin = begin.max(end - last);
+    }
+
+    (begin.min(end), end)
+}
+
+struct TopicState {
+    name: String,
+    partitions: i32,
+    paused: Vec<bool>,
+    ranges: Vec<(i64, i64)>,
+}
+
 pub struct MyConsumer {
     inner: rdkafka::consumer::BaseConsumer<BackupContext>,
-    topic_name: String,
-    partitions: i32,
+    topics: Vec<TopicState>,
+    index: HashMap<String, usize>,
     partitions_paused: i32,
-    offsets_end: Vec<i64>,
+    /// A following consumer keeps consuming past the ranges until it's stopped
+    stop: Option<Arc<AtomicBool>>,
 }
 
 impl MyConsumer {
-    pub fn new(brokers: String, topic_name: &str) -> Result<Self, AppError> {
+    pub fn new(
+        config: &KafkaConfig,
+        group_id: &str,
+        topic_names: &[String],
+        topic_regex: Option<&Regex>,
+    ) -> Result<Self, AppError> {
         let context = BackupContext;
-        let consumer: BaseConsumer<BackupContext> = ClientConfig::new()
-            .set("bootstrap.servers", &brokers)
+        let consumer: BaseConsumer<BackupContext> = config
+            .client_config()
             .set("enable.auto.offset.store", "false")
             .set("enable.auto.commit", "false")
+            .set("enable.partition.eof", "true")
             .set("auto.offset.reset", "beginning")
-            .set("group.id", "akbt")
-            .create_with_context(context)
-            .expect("Consumer creation failed");
+            .set("group.id", group_id)
+            .create_with_context(context)?;
+
+        let mut topics: Vec<TopicState> = vec![];
+        for topic_name in topic_names {
+            let metadata = consumer
+                .fetch_metadata(Some(topic_name), Timeout::After(Duration::from_secs(60)))?;
+            let topic = &metadata.topics()[0];
 
-        let metadata =
-            consumer.fetch_metadata(Some(&topic_name), Timeout::After(Duration::from_secs(60)))?;
-        let topics = metadata.topics();
-        let topic = &topics[0];
+            if topic.partitions().is_empty() {
+                return Err(AppError::TopicNotFound(topic_name.to_string()));
+            }
+            topics.push(TopicState::new(topic_name, topic.partitions().len() as i32));
+        }
+
+        if let Some(regex) = topic_regex {
+            let metadata =
+                consumer.fetch_metadata(None, Timeout::After(Duration::from_secs(60)))?;
+            let mut matched: Vec<_> = metadata
+                .topics()
+                .iter()
+                .filter(|t| regex.is_match(t.name()) && !t.partitions().is_empty())
+                .filter(|t| !topic_names.iter().any(|name| name == t.name()))
+                .map(|t| TopicState::new(t.name(), t.partitions().len() as i32))
+                .collect();
+            if matched.is_empty() && topics.is_empty() {
+                return Err(AppError::TopicNotFound(regex.to_string()));
+            }
+            matched.sort_by(|a, b| a.name.cmp(&b.name));
+            topics.append(&mut matched);
+        }
 
-        if topic.partitions().len() == 0 {
-            return Err(AppError::TopicNotFound(topic_name.to_string()));
+        if topics.is_empty() {
+            return Err(AppError::MissingArgument("topic".to_string()));
         }
 
+        let index = topics
+            .iter()
+            .enumerate()
+            .map(|(idx, t)| (t.name.clone(), idx))
+            .collect();
+
         Ok(MyConsumer {
             inner: consumer,
-            topic_name: topic_name.to_string(),
-            partitions: topic.partitions().len() as i32,
+            topics,
+            index,
             partitions_paused: 0,
-            offsets_end: vec![],
+            stop: None,
         })
     }
 
-    pub fn assign(
-        &mut self,
-        _offset_begin: Option<i64>,
-        _offset_end: Option<i64>,
-    ) -> Result<(), AppError> {
+    /// Records added after the end of the ranges are consumed too, until `stop` is set.
+    /// Has to be called before `assign`.
+    pub fn follow(&mut self, stop: Arc<AtomicBool>) {
+        self.stop = Some(stop);
+    }
+
+    pub fn following(&self) -> bool {
+        self.stop.is_some()
+    }
+
+    // Returns offsets of the first records with timestamp >= `timestamp`
+    fn offsets_for_time(&self, topic_idx: usize, timestamp: i64) -> Result<Vec<Offset>, AppError> {
+        let topic_name = self.topic_name(topic_idx);
+        let mut tppa = TopicPartitionList::new();
+        for part_idx in 0..self.partitions(topic_idx) {
+            tppa.add_partition_offset(topic_name, part_idx, Offset::Offset(timestamp))?;
+        }
+        let tppa = self
+            .inner
+            .offsets_for_times(tppa, Timeout::After(Duration::from_secs(60)))?;
+
+        Ok((0..self.partitions(topic_idx))
+            .map(|part_idx| {
+                tppa.find_partition(topic_name, part_idx)
+                    .map_or(Offset::End, |elem| elem.offset())
+            })
+            .collect())
+    }
+
+    pub fn assign(&mut self, bounds: &Bounds) -> Result<(), AppError> {
         let mut tppa = rdkafka::TopicPartitionList::new();
-        for part_idx in 0..self.partitions() {
-            let (_offset_begin, offset_end) = self.offsets(part_idx)?;
-            tppa.add_partition(self.topic_name(), part_idx as i32);
-            tppa.set_all_offsets(rdkafka::Offset::Beginning).unwrap();
-            self.offsets_end.push(offset_end);
+
+        for topic_idx in 0..self.topics() {
+            let from_time = match bounds.from_time {
+                Some(ts) => self.offsets_for_time(topic_idx, ts)?,
+                None => vec![],
+            };
+            let to_time = match bounds.to_time {
+                Some(ts) => self.offsets_for_time(topic_idx, ts)?,
+                None => vec![],
+            };
+
+            for part_idx in 0..self.partitions(topic_idx) {
+                let topic_name = self.topic_name(topic_idx).to_string();
+                let watermarks = self.offsets(topic_idx, part_idx)?;
+                let resume_from = bounds
+                    .resume_from
+                    .get(&(topic_name.clone(), part_idx))
+                    .copied();
+                if let Some(o) = resume_from {
+                    if o < watermarks.0 {
+                        warn!(
+                            "Topic:{} partition:{} records {}..{} were deleted since the previous backup",
+                            topic_name, part_idx, o, watermarks.0
+                        );
+                    }
+                }
+                let (offset_begin, offset_end) = bounded_range(
+                    part_idx,
+                    watermarks,
+                    bounds,
+                    from_time.get(part_idx as usize),
+                    to_time.get(part_idx as usize),
+                    resume_from,
+                    bounds.until.get(&(topic_name.clone(), part_idx)).copied(),
+                );
+
+                let following = self.following();
+                let topic = &mut self.topics[topic_idx];
+                topic.ranges.push((offset_begin, offset_end));
+
+                // Nothing to consume, so the partition is done from the start
+                if offset_begin >= offset_end && !following {
+                    topic.paused.push(true);
+                    self.partitions_paused += 1;
+                    continue;
+                }
+                topic.paused.push(false);
+                tppa.add_partition_offset(&topic_name, part_idx, Offset::Offset(offset_begin))?;
+            }
         }
         self.inner.assign(&tppa)?;
         Ok(())
     }
 
     // Returns (OffsetBegin, OffsetEnd)
-    pub fn offsets(&self, part_id: i32) -> Result<(i64, i64), AppError> {
+    pub fn offsets(&self, topic_idx: usize, part_id: i32) -> Result<(i64, i64), AppError> {
         let (offset_begin, offset_end) = self.inner.fetch_watermarks(
-            self.topic_name(),
+            self.topic_name(topic_idx),
             part_id,
             Timeout::After(Duration::from_secs(1)),
         )?;
@@ -77,44 +278,226 @@ impl MyConsumer {
         Ok((offset_begin, offset_end))
     }
 
-    pub fn get_offset_end(&self, part_id: i32) -> i64 {
-        *self.offsets_end.index(part_id as usize)
+    // Returns the assigned (OffsetBegin, OffsetEnd), OffsetEnd is exclusive
+    pub fn get_range(&self, topic_idx: usize, part_id: i32) -> (i64, i64) {
+        self.topics[topic_idx].ranges[part_id as usize]
+    }
+
+    fn pause(&mut self, topic_idx: usize, part_id: i32) {
+        let topic = &mut self.topics[topic_idx];
+        if topic.paused[part_id as usize] {
+            return;
+        }
+        let mut tppa = TopicPartitionList::with_capacity(1);
+        tppa.add_partition(&topic.name, part_id);
+        self.inner.pause(&tppa).unwrap();
+        topic.paused[part_id as usize] = true;
+        self.partitions_paused += 1;
+    }
+
+    // Partition EOF doesn't tell the topic, so every topic with the partition is checked
+    fn pause_finished(&mut self, part_id: i32) -> Result<(), AppError> {
+        let positions = self.inner.position()?;
+        for topic_idx in 0..self.topics() {
+            let topic = &self.topics[topic_idx];
+            if part_id >= topic.partitions || topic.paused[part_id as usize] {
+                continue;
+            }
+            let position = positions
+                .find_partition(&topic.name, part_id)
+                .map(|elem| elem.offset());
+            if let Some(Offset::Offset(pos)) = position {
+                if pos >= topic.ranges[part_id as usize].1 {
+                    self.pause(topic_idx, part_id);
+                }
+            }
+        }
+        Ok(())
     }
 
     pub fn poll(&mut self, timeout: Option<Duration>) -> Option<Result<OwnedMessage, AppError>> {
-        if self.all_partitions_paused() {
-            return Some(Err(AppError::EOF));
+        if let Some(stop) = &self.stop {
+            if stop.load(Ordering::Relaxed) {
+                return Some(Err(AppError::Eof));
+            }
+        } else if self.all_partitions_paused() {
+            return Some(Err(AppError::Eof));
         }
 
-        let rd_msg = self.inner.poll(timeout);
-        let msg = match rd_msg {
-            Some(msg) => msg.unwrap().detach(),
-            None => return None,
+        let msg = match self.inner.poll(timeout)? {
+            Ok(msg) => msg.detach(),
+            Err(KafkaError::PartitionEOF(_)) if self.following() => return None,
+            Err(KafkaError::PartitionEOF(part)) => {
+                return self.pause_finished(part).err().map(Err);
+            }
+            Err(e) => return Some(Err(AppError::Kafka(e))),
         };
+        if self.following() {
+            return Some(Ok(msg));
+        }
 
-        let end_offset = self.get_offset_end(msg.partition());
+        let topic_idx = self.topic_index(msg.topic())?;
+        let (_, end_offset) = self.get_range(topic_idx, msg.partition());
         let offset = msg.offset();
         let part = msg.partition();
 
+        // Gaps in offsets (compaction, transaction markers) can step over the end
+        if offset >= end_offset {
+            self.pause(topic_idx, part);
+            return None;
+        }
         if offset + 1 == end_offset {
-            let mut tppa = TopicPartitionList::with_capacity(1);
-            tppa.add_partition(self.topic_name(), part);
-            self.inner.pause(&tppa).unwrap();
-            self.partitions_paused += 1;
+            self.pause(topic_idx, part);
         }
 
         Some(Ok(msg))
     }
 
     pub fn all_partitions_paused(&self) -> bool {
-        self.partitions_paused == self.partitions
+        self.partitions_paused == self.total_partitions()
+    }
+
+    pub fn topics(&self) -> usize {
+        self.topics.len()
+    }
+
+    pub fn topic_index(&self, topic_name: &str) -> Option<usize> {
+        self.index.get(topic_name).copied()
     }
 
-    pub fn partitions(&self) -> i32 {
-        self.partitions
+    pub fn partitions(&self, topic_idx: usize) -> i32 {
+        self.topics[topic_idx].partitions
     }
 
-    pub fn topic_name(&self) -> &str {
-        self.topic_name.as_str()
+    pub fn total_partitions(&self) -> i32 {
+        self.topics.iter().map(|t| t.partitions).sum()
+    }
+
+    pub fn topic_name(&self, topic_idx: usize) -> &str {
+        self.topics[topic_idx].name.as_str()
+    }
+}
+
+impl TopicState {
+    fn new(name: &str, partitions: i32) -> Self {
+        TopicState {
+            name: name.to_string(),
+            partitions,
+            paused: Vec::with_capacity(partitions as usize),
+            ranges: Vec::with_capacity(partitions as usize),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rdkafka::types::RDKafkaErrorCode;
+
+    #[test]
+    fn only_non_fatal_errors_are_recoverable() {
+        let error = |e| AppError::Kafka(e);
+        assert!(recoverable(&error(KafkaError::MessageConsumption(
+            RDKafkaErrorCode::BrokerTransportFailure
+        ))));
+        assert!(!recoverable(&error(KafkaError::MessageConsumptionFatal(
+            RDKafkaErrorCode::Fenced
+        ))));
+        assert!(!recoverable(&AppError::BadArchive(String::new())));
+    }
+
+    #[test]
+    fn offsets_need_a_single_topic() {
+        let offsets = HashMap::from([(0, (10, 20))]);
+        assert!(check_single_topic(&offsets, 1).is_ok());
+        assert!(check_single_topic(&offsets, 2).is_err());
+        assert!(check_single_topic(&HashMap::new(), 2).is_ok());
+    }
+
+    #[test]
+    fn whole_partition_without_bounds() {
+        let bounds = Bounds::default();
+        assert_eq!(
+            bounded_range(0, (5, 100), &bounds, None, None, None, None),
+            (5, 100)
+        );
+    }
+
+    #[test]
+    fn explicit_offsets_are_clamped_to_watermarks() {
+        let bounds = Bounds {
+            offsets: HashMap::from([(1, (0, 50)), (2, (90, 200))]),
+            ..Default::default()
+        };
+        assert_eq!(
+            bounded_range(0, (5, 100), &bounds, None, None, None, None),
+            (100, 100)
+        );
+        assert_eq!(
+            bounded_range(1, (5, 100), &bounds, None, None, None, None),
+            (5, 50)
+        );
+        assert_eq!(
+            bounded_range(2, (5, 100), &bounds, None, None, None, None),
+            (90, 100)
+        );
+    }
+
+    #[test]
+    fn time_bounds() {
+        let bounds = Bounds::default();
+        let from = Offset::Offset(20);
+        let to = Offset::Offset(40);
+        assert_eq!(
+            bounded_range(0, (5, 100), &bounds, Some(&from), Some(&to), None, None),
+            (20, 40)
+        );
+        // No records since the time
+        assert_eq!(
+            bounded_range(
+                0,
+                (5, 100),
+                &bounds,
+                Some(&Offset::End),
+                Some(&Offset::End),
+                None,
+                None
+            ),
+            (100, 100)
+        );
+    }
+
+    #[test]
+    fn resume_from_previous_backup() {
+        let bounds = Bounds::default();
+        assert_eq!(
+            bounded_range(0, (5, 100), &bounds, None, None, Some(50), None),
+            (50, 100)
+        );
+        // The interrupted backup is finished where it had to
+        assert_eq!(
+            bounded_range(0, (5, 100), &bounds, None, None, Some(50), Some(80)),
+            (50, 80)
+        );
+        assert_eq!(
+            bounded_range(0, (5, 100), &bounds, None, None, Some(2), None),
+            (5, 100)
+        );
+    }
+
+    #[test]
+    fn last_records() {
+        let bounds = Bounds {
+            last: Some(10),
+            ..Default::default()
+        };
+        assert_eq!(
+            bounded_range(0, (5, 100), &bounds, None, None, None, None),
+            (90, 100)
+        );
+        assert_eq!(
+            bounded_range(0, (95, 100), &bounds, None, None, None, None),
+            (95, 100)
+        );
     }
 }
@@ -1,5 +1,5 @@
 use std::{
-    io::{Read, Write},
+    io::{BufRead, Read, Write},
     sync::atomic::AtomicUsize,
 };
 
@@ -8,10 +8,7 @@ pub struct ByteCounter<'a, W> {
     count: &'a AtomicUsize,
 }
 
-impl<'a, W> ByteCounter<'a, W>
-where
-    W: Write,
-{
+impl<'a, W> ByteCounter<'a, W> {
     pub fn new(inner: W, count: &'a AtomicUsize) -> Self {
         ByteCounter { inner, count }
     }
@@ -48,3 +45,19 @@ where
         res
     }
 }
+
+// Bytes consumed from the buffer are counted, peeked ones aren't
+impl<'a, R> BufRead for ByteCounter<'a, R>
+where
+    R: BufRead,
+{
+    fn fill_buf(&mut self) -> std::io::Result<&[u8]> {
+        self.inner.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.inner.consume(amt);
+        self.count
+            .fetch_add(amt, std::sync::atomic::Ordering::SeqCst);
+    }
+}
@@ -0,0 +1,504 @@
+use std::fmt;
+
+use aes_gcm::{
+    aead::{Aead, Nonce, Payload},
+    Aes256Gcm, KeyInit,
+};
+use argon2::{Algorithm, Argon2, Params, Version};
+use chacha20poly1305::ChaCha20Poly1305;
+use clap::ValueEnum;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    errors::AppError,
+    protos::kafka_archive::{archive_header_pack, ArchiveHeader, Encryption, EncryptionCipher},
+};
+
+pub const KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 12;
+const SALT_LEN: usize = 16;
+// Argon2 costs of an archive header are limited, so a crafted header can't exhaust the host
+const MAX_KDF_MEMORY: u32 = 1024 * 1024;
+const MAX_KDF_ITERATIONS: u32 = 16;
+const MAX_KDF_PARALLELISM: u32 = 16;
+
+/// Authenticated encryption of the archive blocks
+#[derive(ValueEnum, Debug, Clone, Copy, Default, PartialEq)]
+pub enum Cipher {
+    #[default]
+    #[value(name = "aes-256-gcm")]
+    Aes256Gcm,
+    #[value(name = "chacha20-poly1305")]
+    Chacha20Poly1305,
+}
+
+impl From<Cipher> for EncryptionCipher {
+    fn from(cipher: Cipher) -> Self {
+        match cipher {
+            Cipher::Aes256Gcm => EncryptionCipher::Aes256Gcm,
+            Cipher::Chacha20Poly1305 => EncryptionCipher::Chacha20Poly1305,
+        }
+    }
+}
+
+impl From<EncryptionCipher> for Cipher {
+    fn from(cipher: EncryptionCipher) -> Self {
+        match cipher {
+            EncryptionCipher::Aes256Gcm => Cipher::Aes256Gcm,
+            EncryptionCipher::Chacha20Poly1305 => Cipher::Chacha20Poly1305,
+        }
+    }
+}
+
+/// Key of encrypted archives: the key itself, a file with it or a passphrase
+#[derive(clap::Args, Debug, Clone, Default)]
+pub struct EncryptionArgs {
+    /// Encrypt the backup with the key of 32 bytes in hex, encrypted archives are decrypted with it
+    #[arg(long, env("ENCRYPTION_KEY"), hide_env_values = true,
+        conflicts_with_all = ["encryption_key_file", "passphrase"])]
+    pub encryption_key: Option<String>,
+    /// File with the key, 32 bytes or 64 hex characters
+    #[arg(long, env("ENCRYPTION_KEY_FILE"), conflicts_with = "passphrase")]
+    pub encryption_key_file: Option<String>,
+    /// Passphrase the key is derived from by Argon2id with the salt of the archive
+    #[arg(long, env("ENCRYPTION_PASSPHRASE"), hide_env_values = true)]
+    pub passphrase: Option<String>,
+    /// Cipher of the encrypted backup, restore detects it from the archive
+    #[arg(long, value_enum, default_value = "aes-256-gcm")]
+    pub cipher: Cipher,
+}
+
+impl EncryptionArgs {
+    /// None when no key is given
+    pub fn keys(&self) -> Result<Option<Keys>, AppError> {
+        let secret = if let Some(key) = &self.encryption_key {
+            Secret::Key(hex_key(key)?)
+        } else if let Some(path) = &self.encryption_key_file {
+            let data = std::fs::read(path).map_err(|e| AppError::IoError(e.to_string()))?;
+            Secret::Key(file_key(&data)?)
+        } else if let Some(passphrase) = &self.passphrase {
+            if passphrase.is_empty() {
+                return Err(AppError::InvalidArgument(
+                    "--passphrase is empty".to_string(),
+                ));
+            }
+            Secret::Passphrase(passphrase.clone())
+        } else {
+            return Ok(None);
+        };
+        Ok(Some(Keys {
+            secret,
+            cipher: self.cipher,
+        }))
+    }
+}
+
+#[derive(Clone)]
+enum Secret {
+    Key([u8; KEY_LEN]),
+    Passphrase(String),
+}
+
+/// Key given by the options with the cipher of new archives
+#[derive(Clone)]
+pub struct Keys {
+    secret: Secret,
+    cipher: Cipher,
+}
+
+// Secrets never get into logs
+impl fmt::Debug for Keys {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Keys")
+            .field("cipher", &self.cipher)
+            .finish_non_exhaustive()
+    }
+}
+
+fn hex_key(s: &str) -> Result<[u8; KEY_LEN], AppError> {
+    let s = s.trim();
+    let invalid = || AppError::InvalidArgument("the key is 64 hex characters".to_string());
+    if s.len() != KEY_LEN * 2 || !s.is_ascii() {
+        return Err(invalid());
+    }
+    let mut key = [0; KEY_LEN];
+    for (i, byte) in key.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&s[i * 2..i * 2 + 2], 16).map_err(|_| invalid())?;
+    }
+    Ok(key)
+}
+
+// Key files hold either the raw key or its hex
+fn file_key(data: &[u8]) -> Result<[u8; KEY_LEN], AppError> {
+    if let Ok(key) = data.try_into() {
+        return Ok(key);
+    }
+    std::str::from_utf8(data)
+        .map_err(|_| AppError::InvalidArgument("the key file has neither 32 bytes nor hex".into()))
+        .and_then(hex_key)
+}
+
+/// Hex of the first 8 bytes of SHA-256 of the key, tells keys apart without revealing them
+pub fn key_id(key: &[u8; KEY_LEN]) -> String {
+    Sha256::digest(key)[..8]
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+fn derive(passphrase: &str, salt: &[u8], params: Params) -> Result<[u8; KEY_LEN], AppError> {
+    let mut key = [0; KEY_LEN];
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(|e| AppError::Encryption(format!("can't derive the key: {}", e)))?;
+    Ok(key)
+}
+
+impl Keys {
+    // Records the key of the new archive in its header, a passphrase gets a new salt
+    pub fn encrypt_header(&self, header: &mut ArchiveHeader) -> Result<BlockCipher, AppError> {
+        let mut encryption = Encryption::default();
+        encryption.set_cipher(self.cipher.into());
+        let key = match &self.secret {
+            Secret::Key(key) => *key,
+            Secret::Passphrase(passphrase) => {
+                let mut salt = vec![0; SALT_LEN];
+                getrandom::fill(&mut salt).map_err(|e| AppError::Encryption(e.to_string()))?;
+                let params = Params::DEFAULT;
+                encryption.kdf_memory = Some(params.m_cost());
+                encryption.kdf_iterations = Some(params.t_cost());
+                encryption.kdf_parallelism = Some(params.p_cost());
+                let key = derive(passphrase, &salt, params)?;
+                encryption.salt = Some(salt);
+                key
+            }
+        };
+        encryption.key_id = Some(key_id(&key));
+        header.encryption = Some(encryption);
+        Ok(BlockCipher::new(
+            header.encryption.as_ref().unwrap(),
+            &key,
+            header,
+        ))
+    }
+}
+
+/// Cipher of the blocks of the archive, None when it isn't encrypted.
+/// The key is checked against the key id of the header before any block is read.
+pub fn archive_cipher(
+    header: Option<&ArchiveHeader>,
+    keys: Option<&Keys>,
+) -> Result<Option<BlockCipher>, AppError> {
+    let Some(encryption) = header.and_then(|h| h.encryption.as_ref()) else {
+        return Ok(None);
+    };
+    let Some(keys) = keys else {
+        return Err(AppError::Encryption(format!(
+            "the archive is encrypted with the key {}, \
+             give --encryption-key, --encryption-key-file or --passphrase",
+            encryption.key_id()
+        )));
+    };
+    let key = match (&keys.secret, &encryption.salt) {
+        (Secret::Key(key), None) => *key,
+        (Secret::Passphrase(passphrase), Some(salt)) => {
+            if encryption.kdf_memory() > MAX_KDF_MEMORY
+                || encryption.kdf_iterations() > MAX_KDF_ITERATIONS
+                || encryption.kdf_parallelism() > MAX_KDF_PARALLELISM
+            {
+                return Err(AppError::BadArchive(format!(
+                    "key derivation parameters m:{} t:{} p:{} exceed m:{} t:{} p:{}",
+                    encryption.kdf_memory(),
+                    encryption.kdf_iterations(),
+                    encryption.kdf_parallelism(),
+                    MAX_KDF_MEMORY,
+                    MAX_KDF_ITERATIONS,
+                    MAX_KDF_PARALLELISM
+                )));
+            }
+            let params = Params::new(
+                encryption.kdf_memory(),
+                encryption.kdf_iterations(),
+                encryption.kdf_parallelism(),
+                Some(KEY_LEN),
+            )
+            .map_err(|e| AppError::BadArchive(format!("bad key derivation parameters: {}", e)))?;
+            derive(passphrase, salt, params)?
+        }
+        (Secret::Key(_), Some(_)) => {
+            return Err(AppError::Encryption(
+                "the archive is encrypted with a passphrase, give --passphrase".to_string(),
+            ))
+        }
+        (Secret::Passphrase(_), None) => return Err(AppError::Encryption(
+            "the archive is encrypted with a key, give --encryption-key or --encryption-key-file"
+                .to_string(),
+        )),
+    };
+    if key_id(&key) != encryption.key_id() {
+        let given = match keys.secret {
+            Secret::Key(_) => "key",
+            Secret::Passphrase(_) => "passphrase",
+        };
+        return Err(AppError::Encryption(format!(
+            "wrong {}, its key {} isn't the key {} of the archive",
+            given,
+            key_id(&key),
+            encryption.key_id()
+        )));
+    }
+    Ok(Some(BlockCipher::new(encryption, &key, header.unwrap())))
+}
+
+/// Where the block is in the archive, authenticated with its data,
+/// so blocks can't be moved, dropped or have their counts changed
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct BlockPosition {
+    pub index: u64,
+    /// Records of the archive before the block
+    pub records_before: u64,
+    pub records: u32,
+}
+
+#[derive(Clone)]
+enum BlockAead {
+    Aes256Gcm(Box<Aes256Gcm>),
+    Chacha20Poly1305(Box<ChaCha20Poly1305>),
+}
+
+/// AEAD of the compressed blocks, every block has a random nonce
+#[derive(Clone)]
+pub struct BlockCipher {
+    aead: BlockAead,
+    /// Digest of the header, binds the blocks to the topics, the volume, the key id and the salt
+    header: [u8; 32],
+}
+
+// The record count is left out, it's patched when the archive is done
+fn header_digest(header: &ArchiveHeader) -> [u8; 32] {
+    let header = ArchiveHeader {
+        record_count: None,
+        ..header.clone()
+    };
+    Sha256::digest(archive_header_pack(&header)).into()
+}
+
+fn seal<A: Aead>(aead: &A, aad: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut nonce = [0; NONCE_LEN];
+    getrandom::fill(&mut nonce).expect("Can't generate a nonce");
+    let payload = Payload { msg: data, aad };
+    let ciphertext = aead
+        .encrypt(&Nonce::<A>::try_from(&nonce[..]).unwrap(), payload)
+        .expect("Can't encrypt block");
+    let mut sealed = Vec::with_capacity(NONCE_LEN + ciphertext.len());
+    sealed.extend_from_slice(&nonce);
+    sealed.extend_from_slice(&ciphertext);
+    sealed
+}
+
+fn open<A: Aead>(aead: &A, aad: &[u8], data: &[u8]) -> Result<Vec<u8>, AppError> {
+    if data.len() < NONCE_LEN {
+        return Err(AppError::BadArchive(
+            "encrypted block is too short".to_string(),
+        ));
+    }
+    let (nonce, ciphertext) = data.split_at(NONCE_LEN);
+    let payload = Payload {
+        msg: ciphertext,
+        aad,
+    };
+    aead.decrypt(&Nonce::<A>::try_from(nonce).unwrap(), payload)
+        .map_err(|_| AppError::Encryption("block fails authentication".to_string()))
+}
+
+impl BlockCipher {
+    fn new(encryption: &Encryption, key: &[u8; KEY_LEN], header: &ArchiveHeader) -> Self {
+        let aead = match Cipher::from(encryption.cipher()) {
+            Cipher::Aes256Gcm => {
+                BlockAead::Aes256Gcm(Box::new(Aes256Gcm::new_from_slice(key).unwrap()))
+            }
+            Cipher::Chacha20Poly1305 => BlockAead::Chacha20Poly1305(Box::new(
+                ChaCha20Poly1305::new_from_slice(key).unwrap(),
+            )),
+        };
+        BlockCipher {
+            aead,
+            header: header_digest(header),
+        }
+    }
+
+    /// Same cipher for the blocks of another header, like the next volume of the archive
+    pub fn bind(&self, header: &ArchiveHeader) -> Self {
+        BlockCipher {
+            aead: self.aead.clone(),
+            header: header_digest(header),
+        }
+    }
+
+    // Header digest | index | records before | records
+    fn aad(&self, position: BlockPosition) -> Vec<u8> {
+        let mut aad = Vec::with_capacity(self.header.len() + 20);
+        aad.extend_from_slice(&self.header);
+        aad.extend_from_slice(&position.index.to_be_bytes());
+        aad.extend_from_slice(&position.records_before.to_be_bytes());
+        aad.extend_from_slice(&position.records.to_be_bytes());
+        aad
+    }
+
+    /// Nonce | ciphertext | tag of the data, the position of the block is authenticated too
+    pub fn encrypt(&self, position: BlockPosition, data: &[u8]) -> Vec<u8> {
+        let aad = self.aad(position);
+        match &self.aead {
+            BlockAead::Aes256Gcm(aead) => seal(aead.as_ref(), &aad, data),
+            BlockAead::Chacha20Poly1305(aead) => seal(aead.as_ref(), &aad, data),
+        }
+    }
+
+    pub fn decrypt(&self, position: BlockPosition, data: &[u8]) -> Result<Vec<u8>, AppError> {
+        let aad = self.aad(position);
+        match &self.aead {
+            BlockAead::Aes256Gcm(aead) => open(aead.as_ref(), &aad, data),
+            BlockAead::Chacha20Poly1305(aead) => open(aead.as_ref(), &aad, data),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::archive::archive_header_new;
+
+    fn keys(secret: Secret, cipher: Cipher) -> Keys {
+        Keys { secret, cipher }
+    }
+
+    fn position(index: u64, records: u32) -> BlockPosition {
+        BlockPosition {
+            index,
+            records_before: index * 3,
+            records,
+        }
+    }
+
+    #[test]
+    fn blocks_roundtrip() {
+        for cipher in [Cipher::Aes256Gcm, Cipher::Chacha20Poly1305] {
+            let mut encryption = Encryption {
+                key_id: Some("id".to_string()),
+                ..Default::default()
+            };
+            encryption.set_cipher(cipher.into());
+            let mut header = archive_header_new("localhost:9092", vec![]);
+            header.volume = Some(1);
+            header.encryption = Some(encryption.clone());
+            let block_cipher = BlockCipher::new(&encryption, &[7; KEY_LEN], &header);
+            let sealed = block_cipher.encrypt(position(1, 3), b"records");
+            assert_eq!(sealed.len(), NONCE_LEN + b"records".len() + 16);
+            assert_eq!(
+                block_cipher.decrypt(position(1, 3), &sealed).unwrap(),
+                b"records"
+            );
+            // Nonces are random
+            assert_ne!(block_cipher.encrypt(position(1, 3), b"records"), sealed);
+
+            let mut flipped = sealed.clone();
+            flipped[NONCE_LEN] ^= 1;
+            assert!(block_cipher.decrypt(position(1, 3), &flipped).is_err());
+            // The block is bound to its counts, its place and the header
+            assert!(block_cipher.decrypt(position(1, 4), &sealed).is_err());
+            assert!(block_cipher.decrypt(position(2, 3), &sealed).is_err());
+            let moved = BlockPosition {
+                records_before: 0,
+                ..position(1, 3)
+            };
+            assert!(block_cipher.decrypt(moved, &sealed).is_err());
+            // The record count of the header isn't authenticated, it's patched at the end
+            header.record_count = Some(100);
+            let patched = block_cipher.bind(&header);
+            assert!(patched.decrypt(position(1, 3), &sealed).is_ok());
+            header.volume = Some(2);
+            let next_volume = block_cipher.bind(&header);
+            assert!(matches!(
+                next_volume.decrypt(position(1, 3), &sealed),
+                Err(AppError::Encryption(_))
+            ));
+            header.volume = Some(1);
+            header.encryption.as_mut().unwrap().salt = Some(vec![0; SALT_LEN]);
+            let other = block_cipher.bind(&header);
+            assert!(
+                other.decrypt(position(1, 3), &sealed).is_err(),
+                "{:?}",
+                cipher
+            );
+        }
+    }
+
+    #[test]
+    fn key_formats() {
+        let hex = "00".repeat(31) + "ff";
+        let mut expected = [0; KEY_LEN];
+        expected[31] = 0xff;
+        assert_eq!(hex_key(&hex).unwrap(), expected);
+        assert_eq!(file_key(format!("{}\n", hex).as_bytes()).unwrap(), expected);
+        assert_eq!(file_key(&expected).unwrap(), expected);
+        assert!(hex_key("ff").is_err());
+        assert!(hex_key(&"zz".repeat(32)).is_err());
+    }
+
+    #[test]
+    fn archive_key_is_checked() {
+        let key = keys(Secret::Key([1; KEY_LEN]), Cipher::Chacha20Poly1305);
+        let mut header = archive_header_new("localhost:9092", vec![]);
+        let writer = key.encrypt_header(&mut header).unwrap();
+        let reader = archive_cipher(Some(&header), Some(&key)).unwrap().unwrap();
+        assert_eq!(
+            reader
+                .decrypt(position(0, 1), &writer.encrypt(position(0, 1), b"data"))
+                .unwrap(),
+            b"data"
+        );
+
+        assert!(matches!(
+            archive_cipher(Some(&header), None),
+            Err(AppError::Encryption(_))
+        ));
+        let wrong = keys(Secret::Key([2; KEY_LEN]), Cipher::Chacha20Poly1305);
+        assert!(matches!(
+            archive_cipher(Some(&header), Some(&wrong)),
+            Err(AppError::Encryption(_))
+        ));
+        let plain = archive_header_new("localhost:9092", vec![]);
+        assert!(archive_cipher(Some(&plain), Some(&key)).unwrap().is_none());
+    }
+
+    #[test]
+    fn passphrase_key_is_derived_with_the_salt() {
+        let passphrase = keys(Secret::Passphrase("secret".into()), Cipher::Aes256Gcm);
+        let mut header = archive_header_new("localhost:9092", vec![]);
+        let writer = passphrase.encrypt_header(&mut header).unwrap();
+        assert_eq!(header.encryption.as_ref().unwrap().salt().len(), SALT_LEN);
+
+        let reader = archive_cipher(Some(&header), Some(&passphrase))
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            reader
+                .decrypt(position(0, 1), &writer.encrypt(position(0, 1), b"data"))
+                .unwrap(),
+            b"data"
+        );
+
+        let wrong = keys(Secret::Passphrase("Secret".into()), Cipher::Aes256Gcm);
+        assert!(matches!(
+            archive_cipher(Some(&header), Some(&wrong)),
+            Err(AppError::Encryption(_))
+        ));
+
+        // Costs of a crafted header aren't paid
+        header.encryption.as_mut().unwrap().kdf_memory = Some(u32::MAX);
+        assert!(matches!(
+            archive_cipher(Some(&header), Some(&passphrase)),
+            Err(AppError::BadArchive(_))
+        ));
+    }
+}
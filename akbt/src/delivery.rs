@@ -0,0 +1,338 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Display,
+    ops::AddAssign,
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+use log::{error, warn};
+use rdkafka::{
+    error::KafkaError,
+    producer::{BaseProducer, BaseRecord, DeliveryResult, Producer, ProducerContext},
+    types::RDKafkaErrorCode,
+    ClientContext, Message,
+};
+
+use crate::{errors::AppError, groups::OffsetMap, metrics::Metrics};
+
+const SEND_ATTEMPTS: u32 = 5;
+const INITIAL_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_BACKOFF: Duration = Duration::from_secs(5);
+
+/// Delivery reports of a partition
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct PartitionStats {
+    pub acknowledged: u64,
+    pub failed: u64,
+}
+
+/// Counts of the records sent by the restore producer
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct DeliveryStats {
+    pub produced: u64,
+    pub acknowledged: u64,
+    pub failed: u64,
+    /// Per topic and partition, records refused before a partition is chosen have partition -1
+    pub partitions: BTreeMap<(String, i32), PartitionStats>,
+    pub last_error: Option<String>,
+}
+
+impl DeliveryStats {
+    /// Fails unless every produced record is acknowledged
+    pub fn check(self) -> Result<Self, AppError> {
+        if self.failed > 0 || self.acknowledged < self.produced {
+            return Err(AppError::Undelivered(self));
+        }
+        Ok(self)
+    }
+
+    fn acknowledged(&mut self, topic: &str, partition: i32) {
+        self.acknowledged += 1;
+        self.partition(topic, partition).acknowledged += 1;
+    }
+
+    fn failed(&mut self, topic: &str, partition: i32, error: &KafkaError) {
+        self.failed += 1;
+        self.partition(topic, partition).failed += 1;
+        self.last_error = Some(error.to_string());
+    }
+
+    fn partition(&mut self, topic: &str, partition: i32) -> &mut PartitionStats {
+        self.partitions
+            .entry((topic.to_string(), partition))
+            .or_default()
+    }
+}
+
+impl AddAssign for DeliveryStats {
+    fn add_assign(&mut self, other: Self) {
+        self.produced += other.produced;
+        self.acknowledged += other.acknowledged;
+        self.failed += other.failed;
+        for (key, stats) in other.partitions {
+            let partition = self.partitions.entry(key).or_default();
+            partition.acknowledged += stats.acknowledged;
+            partition.failed += stats.failed;
+        }
+        if other.last_error.is_some() {
+            self.last_error = other.last_error;
+        }
+    }
+}
+
+// The totals, partitions with failures and the last error
+impl Display for DeliveryStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "produced:{} acknowledged:{} failed:{}",
+            self.produced, self.acknowledged, self.failed
+        )?;
+        let failed: Vec<String> = self
+            .partitions
+            .iter()
+            .filter(|(_, stats)| stats.failed > 0)
+            .map(|((topic, partition), stats)| format!("{}/{}:{}", topic, partition, stats.failed))
+            .collect();
+        if !failed.is_empty() {
+            write!(f, " failed partitions:{}", failed.join(","))?;
+        }
+        if let Some(e) = &self.last_error {
+            write!(f, " last error:{}", e)?;
+        }
+        Ok(())
+    }
+}
+
+/// Where the record comes from and how many times it was sent
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Source {
+    /// Offset of the record in the archive, -1 when the archive doesn't have it
+    pub offset: i64,
+    pub attempt: u32,
+}
+
+pub type RecordSource = Box<Source>;
+
+pub fn source(offset: i64) -> RecordSource {
+    Box::new(Source { offset, attempt: 0 })
+}
+
+/// Producer context counting the delivery reports
+#[derive(Default)]
+pub struct DeliveryContext {
+    stats: Mutex<DeliveryStats>,
+    /// Where the records are delivered, only when the offsets have to be translated
+    offsets: Option<Mutex<OffsetMap>>,
+    metrics: Option<Arc<Metrics>>,
+}
+
+impl DeliveryContext {
+    pub fn new(map_offsets: bool, metrics: Option<Arc<Metrics>>) -> Self {
+        DeliveryContext {
+            offsets: map_offsets.then(Mutex::default),
+            metrics,
+            ..Default::default()
+        }
+    }
+
+    pub fn stats(&self) -> DeliveryStats {
+        self.stats.lock().unwrap().clone()
+    }
+
+    pub fn offsets(&self) -> OffsetMap {
+        self.offsets
+            .as_ref()
+            .map(|offsets| offsets.lock().unwrap().clone())
+            .unwrap_or_default()
+    }
+
+    // The producer retries the delivery itself, a failed report is final
+    fn failed(&self, e: &KafkaError, topic: &str, partition: i32) {
+        let mut stats = self.stats.lock().unwrap();
+        // Only the first failures are logged, the rest are counted
+        if stats.failed < 10 {
+            error!(
+                "Record isn't delivered to topic:{} partition:{}: {}",
+                topic, partition, e
+            );
+        }
+        stats.failed(topic, partition, e);
+    }
+}
+
+impl ClientContext for DeliveryContext {}
+
+impl ProducerContext for DeliveryContext {
+    type DeliveryOpaque = RecordSource;
+
+    fn delivery(&self, result: &DeliveryResult<'_>, source: Self::DeliveryOpaque) {
+        match result {
+            Ok(msg) => {
+                self.stats
+                    .lock()
+                    .unwrap()
+                    .acknowledged(msg.topic(), msg.partition());
+                if let Some(metrics) = &self.metrics {
+                    let bytes = msg.key_len() + msg.payload_len();
+                    metrics.written(1, bytes as u64);
+                }
+                if let (Some(offsets), true) = (&self.offsets, source.offset >= 0) {
+                    offsets.lock().unwrap().record(
+                        msg.topic(),
+                        msg.partition(),
+                        source.offset,
+                        msg.offset(),
+                    );
+                }
+            }
+            Err((e, msg)) => self.failed(e, msg.topic(), msg.partition()),
+        }
+    }
+}
+
+// Errors the broker or the client may recover from
+fn retriable(e: &KafkaError) -> bool {
+    matches!(
+        e.rdkafka_error_code(),
+        Some(
+            RDKafkaErrorCode::BrokerTransportFailure
+                | RDKafkaErrorCode::AllBrokersDown
+                | RDKafkaErrorCode::MessageTimedOut
+                | RDKafkaErrorCode::UnknownPartition
+                | RDKafkaErrorCode::UnknownTopicOrPartition
+                | RDKafkaErrorCode::LeaderNotAvailable
+                | RDKafkaErrorCode::NotLeaderForPartition
+                | RDKafkaErrorCode::RequestTimedOut
+                | RDKafkaErrorCode::NotEnoughReplicas
+        )
+    )
+}
+
+fn backoff(attempt: u32) -> Duration {
+    INITIAL_BACKOFF
+        .saturating_mul(2u32.saturating_pow(attempt))
+        .min(MAX_BACKOFF)
+}
+
+/// Sends the record, waiting while the queue is full and retrying retriable errors with backoff.
+/// A record that can't be sent is counted as failed.
+pub fn send(
+    prod: &BaseProducer<DeliveryContext>,
+    record: BaseRecord<'_, [u8], [u8], RecordSource>,
+) {
+    prod.context().stats.lock().unwrap().produced += 1;
+    send_attempts(prod, record);
+}
+
+fn send_attempts(
+    prod: &BaseProducer<DeliveryContext>,
+    mut record: BaseRecord<'_, [u8], [u8], RecordSource>,
+) {
+    loop {
+        match prod.send(record) {
+            Ok(_) => return,
+            Err((KafkaError::MessageProduction(RDKafkaErrorCode::QueueFull), rec)) => {
+                prod.poll(Duration::from_millis(100));
+                record = rec;
+            }
+            Err((e, mut rec))
+                if retriable(&e) && rec.delivery_opaque.attempt + 1 < SEND_ATTEMPTS =>
+            {
+                warn!("Retrying record to topic:{}: {}", rec.topic, e);
+                thread::sleep(backoff(rec.delivery_opaque.attempt));
+                prod.poll(Duration::ZERO);
+                rec.delivery_opaque.attempt += 1;
+                record = rec;
+            }
+            Err((e, rec)) => {
+                error!("Can't send record to topic:{}: {}", rec.topic, e);
+                let mut stats = prod.context().stats.lock().unwrap();
+                stats.failed(rec.topic, rec.partition.unwrap_or(-1), &e);
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_record_has_to_be_acknowledged() {
+        let mut stats = DeliveryStats {
+            produced: 3,
+            ..Default::default()
+        };
+        for partition in [0, 1, 1] {
+            stats.acknowledged("topic", partition);
+        }
+        assert_eq!(stats.clone().check(), Ok(stats.clone()));
+
+        let mut other = DeliveryStats {
+            produced: 2,
+            ..Default::default()
+        };
+        other.acknowledged("topic", 1);
+        other.failed("topic", 2, &KafkaError::Canceled);
+        stats += other;
+        assert_eq!(
+            stats.partitions[&("topic".to_string(), 1)],
+            PartitionStats {
+                acknowledged: 3,
+                failed: 0
+            }
+        );
+        assert_eq!(
+            stats.to_string(),
+            "produced:5 acknowledged:4 failed:1 failed partitions:topic/2:1 last error:KafkaError (Client dropped)"
+        );
+        assert_eq!(stats.clone().check(), Err(AppError::Undelivered(stats)));
+
+        // Reports of a timed out flush never arrive
+        let lost = DeliveryStats {
+            produced: 2,
+            acknowledged: 1,
+            ..Default::default()
+        };
+        assert_eq!(lost.clone().check(), Err(AppError::Undelivered(lost)));
+    }
+
+    #[test]
+    fn backoff_is_bounded() {
+        assert_eq!(backoff(0), INITIAL_BACKOFF);
+        assert_eq!(backoff(3), Duration::from_millis(800));
+        assert_eq!(backoff(40), MAX_BACKOFF);
+    }
+
+    #[test]
+    fn failed_delivery_reports_are_counted() {
+        let prod: BaseProducer<DeliveryContext> = rdkafka::ClientConfig::new()
+            .set("bootstrap.servers", "localhost:1")
+            .create_with_context(DeliveryContext::default())
+            .unwrap();
+        for partition in [0, 1] {
+            let record = BaseRecord::with_opaque_to("topic", source(partition))
+                .partition(partition as i32)
+                .payload(b"value".as_slice());
+            send(&prod, record);
+        }
+        // The purged records report their delivery once, they aren't sent again
+        prod.purge(rdkafka::producer::PurgeConfig::default().queue().inflight());
+        let _ = prod.flush(Duration::from_secs(1));
+        let stats = prod.context().stats();
+        assert_eq!((stats.produced, stats.failed), (2, 2));
+        assert!(stats.check().is_err());
+    }
+
+    #[test]
+    fn only_transient_errors_are_retried() {
+        let error = |code| KafkaError::MessageProduction(code);
+        assert!(retriable(&error(RDKafkaErrorCode::NotLeaderForPartition)));
+        assert!(!retriable(&error(RDKafkaErrorCode::MessageSizeTooLarge)));
+        assert!(!retriable(&KafkaError::Canceled));
+    }
+}
@@ -1,5 +1,7 @@
 use thiserror::Error;
 
+use crate::delivery::DeliveryStats;
+
 #[derive(Error, Debug, PartialEq)]
 pub enum AppError {
     #[error("rdkafka error")]
@@ -12,8 +14,38 @@ pub enum AppError {
     FileExists(String),
     #[error("Can't find file: `{0}`")]
     FileNotExists(String),
+    #[error("Missing required argument: --{0}")]
+    MissingArgument(String),
+    #[error("Invalid argument: {0}")]
+    InvalidArgument(String),
     #[error("Can't send message to encoder:{0}")]
     Send2Encoder(String),
+    #[error("Bad archive: {0}")]
+    BadArchive(String),
+    #[error("Unsupported archive format version: {0}")]
+    UnsupportedVersion(u16),
+    #[error("Archive is corrupted: {0}")]
+    Corrupted(String),
+    #[error("Records aren't delivered: {0}")]
+    Undelivered(DeliveryStats),
+    #[error("Schema registry error: {0}")]
+    SchemaRegistry(String),
+    #[error("Storage error: {0}")]
+    Storage(String),
+    #[error("Encryption error: {0}")]
+    Encryption(String),
+    #[error("Thread `{0}` panicked")]
+    Panicked(String),
     #[error("EOF")]
-    EOF,
+    Eof,
+}
+
+impl AppError {
+    /// Exit code of the process, 2 tells that a part of the records is restored
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            AppError::Undelivered(stats) if stats.acknowledged > 0 => 2,
+            _ => 1,
+        }
+    }
 }
@@ -0,0 +1,237 @@
+use std::{fmt::Display, str::FromStr};
+
+use regex::Regex;
+use serde_json::Value;
+
+use crate::protos::kafka_messages::KafkaMessage;
+
+/// Step of a JSONPath
+#[derive(Debug, Clone, PartialEq)]
+pub enum Segment {
+    Field(String),
+    Index(usize),
+    Any,
+}
+
+/// Predicate on a record, written as
+/// `key^=PREFIX`, `key~=REGEX`, `header.NAME=VALUE` or `$.JSON.PATH=VALUE`
+#[derive(Debug, Clone)]
+pub enum Filter {
+    KeyPrefix(String),
+    KeyRegex(Regex),
+    Header(String, String),
+    /// The value is compared as JSON when it parses as JSON, as a string otherwise
+    JsonPath(String, Vec<Segment>, Value),
+}
+
+// JSONPath subset: `$` followed by `.field`, `[index]` and `[*]` or `.*`
+fn parse_json_path(path: &str) -> Result<Vec<Segment>, String> {
+    let err = || format!("`{}` isn't a JSONPath like $.field[0].field", path);
+    let mut rest = path.strip_prefix('$').ok_or_else(err)?;
+    let mut segments = vec![];
+    while !rest.is_empty() {
+        if let Some(tail) = rest.strip_prefix('.') {
+            let end = tail.find(['.', '[']).unwrap_or(tail.len());
+            let segment = match &tail[..end] {
+                "" => return Err(err()),
+                "*" => Segment::Any,
+                name => Segment::Field(name.to_string()),
+            };
+            segments.push(segment);
+            rest = &tail[end..];
+        } else if let Some(tail) = rest.strip_prefix('[') {
+            let end = tail.find(']').ok_or_else(err)?;
+            let segment = match &tail[..end] {
+                "*" => Segment::Any,
+                index => Segment::Index(index.parse().map_err(|_| err())?),
+            };
+            segments.push(segment);
+            rest = &tail[end + 1..];
+        } else {
+            return Err(err());
+        }
+    }
+    Ok(segments)
+}
+
+// Values the path selects, wildcards select all items
+fn select<'a>(value: &'a Value, path: &[Segment], found: &mut Vec<&'a Value>) {
+    let Some((segment, rest)) = path.split_first() else {
+        found.push(value);
+        return;
+    };
+    match (segment, value) {
+        (Segment::Field(name), Value::Object(fields)) => {
+            if let Some(value) = fields.get(name) {
+                select(value, rest, found);
+            }
+        }
+        (Segment::Index(idx), Value::Array(items)) => {
+            if let Some(value) = items.get(*idx) {
+                select(value, rest, found);
+            }
+        }
+        (Segment::Any, Value::Array(items)) => {
+            items.iter().for_each(|value| select(value, rest, found))
+        }
+        (Segment::Any, Value::Object(fields)) => {
+            fields.values().for_each(|value| select(value, rest, found))
+        }
+        _ => (),
+    }
+}
+
+impl Filter {
+    pub fn matches(&self, kmsg: &KafkaMessage) -> bool {
+        match self {
+            Filter::KeyPrefix(prefix) => kmsg
+                .key
+                .as_ref()
+                .is_some_and(|key| key.starts_with(prefix.as_bytes())),
+            Filter::KeyRegex(regex) => kmsg
+                .key
+                .as_ref()
+                .is_some_and(|key| regex.is_match(&String::from_utf8_lossy(key))),
+            Filter::Header(name, value) => kmsg
+                .headers
+                .iter()
+                .any(|h| h.key() == name && h.value.as_deref() == Some(value.as_bytes())),
+            Filter::JsonPath(_, path, expected) => {
+                let Some(Ok(value)) = kmsg
+                    .value
+                    .as_ref()
+                    .map(|data| serde_json::from_slice::<Value>(data))
+                else {
+                    return false;
+                };
+                let mut found = vec![];
+                select(&value, path, &mut found);
+                found.into_iter().any(|value| value == expected)
+            }
+        }
+    }
+}
+
+impl FromStr for Filter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(prefix) = s.strip_prefix("key^=") {
+            return Ok(Filter::KeyPrefix(prefix.to_string()));
+        }
+        if let Some(regex) = s.strip_prefix("key~=") {
+            return Regex::new(regex)
+                .map(Filter::KeyRegex)
+                .map_err(|e| e.to_string());
+        }
+        let err = || {
+            format!(
+                "`{}` isn't a filter like key^=PREFIX, key~=REGEX, header.NAME=VALUE or $.PATH=VALUE",
+                s
+            )
+        };
+        let (lhs, value) = s.split_once('=').ok_or_else(err)?;
+        if let Some(name) = lhs.strip_prefix("header.") {
+            return Ok(Filter::Header(name.to_string(), value.to_string()));
+        }
+        if lhs.starts_with('$') {
+            let expected = serde_json::from_str(value).unwrap_or(Value::String(value.to_string()));
+            return Ok(Filter::JsonPath(
+                lhs.to_string(),
+                parse_json_path(lhs)?,
+                expected,
+            ));
+        }
+        Err(err())
+    }
+}
+
+// The expression the filter was parsed from, so it can be kept in the archive header
+impl Display for Filter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Filter::KeyPrefix(prefix) => write!(f, "key^={}", prefix),
+            Filter::KeyRegex(regex) => write!(f, "key~={}", regex),
+            Filter::Header(name, value) => write!(f, "header.{}={}", name, value),
+            // Strings are quoted only when they would be read back as other JSON
+            Filter::JsonPath(path, _, Value::String(value))
+                if serde_json::from_str::<Value>(value).is_err() =>
+            {
+                write!(f, "{}={}", path, value)
+            }
+            Filter::JsonPath(path, _, value) => write!(f, "{}={}", path, value),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protos::kafka_messages::{kafka_header_new, kafka_message_new, TimestampType};
+
+    fn record(key: &str, headers: &[(&str, &str)], value: &str) -> KafkaMessage {
+        kafka_message_new(
+            Some(key.as_bytes().to_vec()),
+            Some(value.as_bytes().to_vec()),
+            Some(0),
+            headers
+                .iter()
+                .map(|(k, v)| kafka_header_new(k.to_string(), Some(v.as_bytes().to_vec())))
+                .collect(),
+            None,
+            TimestampType::NotAvailable,
+            Some(0),
+        )
+    }
+
+    fn matches(filter: &str, kmsg: &KafkaMessage) -> bool {
+        filter.parse::<Filter>().unwrap().matches(kmsg)
+    }
+
+    #[test]
+    fn key_and_header_filters() {
+        let kmsg = record("tenant-1:42", &[("tenant", "acme")], "");
+        assert!(matches("key^=tenant-1:", &kmsg));
+        assert!(!matches("key^=tenant-2", &kmsg));
+        assert!(matches("key~=^tenant-\\d+:", &kmsg));
+        assert!(!matches("key~=^\\d", &kmsg));
+        assert!(matches("header.tenant=acme", &kmsg));
+        assert!(!matches("header.tenant=other", &kmsg));
+        assert!(!matches("header.region=acme", &kmsg));
+    }
+
+    #[test]
+    fn json_path_filters() {
+        let kmsg = record(
+            "",
+            &[],
+            r#"{"tenant":{"id":"acme","tier":2},"items":[{"sku":"a"},{"sku":"b"}]}"#,
+        );
+        assert!(matches("$.tenant.id=acme", &kmsg));
+        assert!(matches("$.tenant.id=\"acme\"", &kmsg));
+        assert!(matches("$.tenant.tier=2", &kmsg));
+        assert!(!matches("$.tenant.tier=\"2\"", &kmsg));
+        assert!(matches("$.items[1].sku=b", &kmsg));
+        assert!(matches("$.items[*].sku=a", &kmsg));
+        assert!(matches("$.*.id=acme", &kmsg));
+        assert!(!matches("$.items[2].sku=a", &kmsg));
+        assert!(!matches("$.tenant.id=acme", &record("", &[], "not json")));
+    }
+
+    #[test]
+    fn expressions_roundtrip() {
+        for expr in [
+            "key^=a=b",
+            "key~=^x.*",
+            "header.h=v",
+            "$.a[0].b=c",
+            "$.n=1",
+            "$.s=\"1\"",
+        ] {
+            assert_eq!(expr.parse::<Filter>().unwrap().to_string(), expr);
+        }
+        assert!("$.a[x]=1".parse::<Filter>().is_err());
+        assert!("$.a..b=1".parse::<Filter>().is_err());
+        assert!("value=1".parse::<Filter>().is_err());
+    }
+}
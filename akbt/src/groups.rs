@@ -0,0 +1,302 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::Path,
+    time::Duration,
+};
+
+use log::{info, warn};
+use rdkafka::{
+    consumer::{BaseConsumer, CommitMode, Consumer},
+    Offset, TopicPartitionList,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    admin,
+    config::KafkaConfig,
+    errors::AppError,
+    protos::kafka_archive::{group_offset_new, GroupOffset},
+    volumes::PartitionRange,
+};
+
+const TIMEOUT: Duration = Duration::from_secs(60);
+
+// Records consecutive in both topics share a segment
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Segment {
+    source: i64,
+    target: i64,
+    len: i64,
+}
+
+/// Offsets of the restored records in the source and the target topic per target partition
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct OffsetMap {
+    partitions: BTreeMap<(String, i32), Vec<Segment>>,
+}
+
+impl OffsetMap {
+    /// Records are expected in order of their source offsets within a partition
+    pub fn record(&mut self, topic: &str, partition: i32, source: i64, target: i64) {
+        let segments = self
+            .partitions
+            .entry((topic.to_string(), partition))
+            .or_default();
+        match segments.last_mut() {
+            Some(last) if last.source + last.len == source && last.target + last.len == target => {
+                last.len += 1
+            }
+            _ => segments.push(Segment {
+                source,
+                target,
+                len: 1,
+            }),
+        }
+    }
+
+    /// Target offset of the first restored record at or after the source offset,
+    /// past the last record for offsets beyond it
+    pub fn translate(&self, topic: &str, partition: i32, source: i64) -> Option<i64> {
+        let segments = self.partitions.get(&(topic.to_string(), partition))?;
+        let idx = segments.partition_point(|s| s.source + s.len <= source);
+        Some(match segments.get(idx) {
+            Some(s) if source <= s.source => s.target,
+            Some(s) => s.target + source - s.source,
+            None => segments.last().map(|s| s.target + s.len)?,
+        })
+    }
+
+    /// Appends the map of the next archive
+    pub fn extend(&mut self, other: OffsetMap) {
+        for (key, segments) in other.partitions {
+            self.partitions.entry(key).or_default().extend(segments);
+        }
+    }
+}
+
+// Offsets file entry
+#[derive(Serialize, Deserialize)]
+struct ExportedOffset {
+    group: String,
+    topic: String,
+    partition: i32,
+    offset: i64,
+}
+
+fn group_consumer(config: &KafkaConfig, group: &str) -> Result<BaseConsumer, AppError> {
+    let consumer = config
+        .client_config()
+        .set("group.id", group)
+        .set("enable.auto.commit", "false")
+        .create()?;
+    Ok(consumer)
+}
+
+/// Committed offsets of the groups for the partitions of the topics,
+/// the groups don't have to be active
+pub fn committed_offsets(
+    config: &KafkaConfig,
+    groups: &[String],
+    topics: &[(String, i32)],
+) -> Result<Vec<GroupOffset>, AppError> {
+    let mut tpl = TopicPartitionList::new();
+    for (topic, partitions) in topics {
+        for partition in 0..*partitions {
+            tpl.add_partition(topic, partition);
+        }
+    }
+
+    let mut offsets = vec![];
+    for group in groups {
+        let consumer = group_consumer(config, group)?;
+        let committed = consumer.committed_offsets(tpl.clone(), TIMEOUT)?;
+        let count = offsets.len();
+        for elem in committed.elements() {
+            if let Offset::Offset(offset) = elem.offset() {
+                offsets.push(group_offset_new(
+                    group.clone(),
+                    elem.topic().to_string(),
+                    elem.partition(),
+                    offset,
+                ));
+            }
+        }
+        if offsets.len() == count {
+            warn!("Group:{} has no committed offsets of the topics", group);
+        }
+    }
+    Ok(offsets)
+}
+
+/// Writes the committed offsets of the groups for the topics into a JSON file
+pub fn export_offsets(
+    config: &KafkaConfig,
+    file: &str,
+    groups: &[String],
+    topics: &[String],
+) -> Result<(), AppError> {
+    if Path::new(file).exists() {
+        return Err(AppError::FileExists(file.to_string()));
+    }
+    let partitions = admin::topic_partitions(config)?;
+    let mut selected = Vec::with_capacity(topics.len());
+    for topic in topics {
+        let count = partitions
+            .get(topic)
+            .ok_or_else(|| AppError::TopicNotFound(topic.clone()))?;
+        selected.push((topic.clone(), *count));
+    }
+
+    let offsets: Vec<ExportedOffset> = committed_offsets(config, groups, &selected)?
+        .into_iter()
+        .map(|o| ExportedOffset {
+            group: o.group().to_string(),
+            topic: o.topic().to_string(),
+            partition: o.partition(),
+            offset: o.offset(),
+        })
+        .collect();
+    let data = serde_json::to_vec_pretty(&offsets).map_err(|e| AppError::IoError(e.to_string()))?;
+    std::fs::write(file, data).map_err(|e| AppError::IoError(e.to_string()))?;
+    eprintln!("Exported offsets:{} to {}", offsets.len(), file);
+    Ok(())
+}
+
+/// Reads the offsets file written by `export_offsets`
+pub fn load_offsets(file: &str) -> Result<Vec<GroupOffset>, AppError> {
+    let data = std::fs::read(file).map_err(|e| AppError::IoError(format!("`{}`: {}", file, e)))?;
+    let offsets: Vec<ExportedOffset> = serde_json::from_slice(&data)
+        .map_err(|e| AppError::InvalidArgument(format!("`{}` {}", file, e)))?;
+    Ok(offsets
+        .into_iter()
+        .map(|o| group_offset_new(o.group, o.topic, o.partition, o.offset))
+        .collect())
+}
+
+/// Commits the offsets of the groups, topics of the offsets are the target topics.
+/// Offsets are translated through the map, partitions without restored records are skipped.
+pub fn commit_offsets(
+    config: &KafkaConfig,
+    offsets: &[GroupOffset],
+    map: &OffsetMap,
+) -> Result<(), AppError> {
+    let mut groups: BTreeMap<&str, TopicPartitionList> = BTreeMap::new();
+    for o in offsets {
+        let Some(target) = map.translate(o.topic(), o.partition(), o.offset()) else {
+            warn!(
+                "Group:{} offset of {}/{} isn't committed, no records of the partition are restored",
+                o.group(),
+                o.topic(),
+                o.partition()
+            );
+            continue;
+        };
+        groups.entry(o.group()).or_default().add_partition_offset(
+            o.topic(),
+            o.partition(),
+            Offset::Offset(target),
+        )?;
+    }
+
+    for (group, tpl) in groups {
+        let consumer = group_consumer(config, group)?;
+        consumer.commit(&tpl, CommitMode::Sync)?;
+        eprintln!("Committed group:{} partitions:{}", group, tpl.count());
+    }
+    Ok(())
+}
+
+/// Commits the position of a following backup under its group,
+/// a restarted backup continues from the end of the last sealed volume
+pub struct PositionCommitter {
+    group: String,
+    consumer: BaseConsumer,
+}
+
+impl PositionCommitter {
+    pub fn new(config: &KafkaConfig, group: &str) -> Result<Self, AppError> {
+        Ok(PositionCommitter {
+            group: group.to_string(),
+            consumer: group_consumer(config, group)?,
+        })
+    }
+
+    /// The next offset of every partition is the end of its range
+    pub fn commit(&self, ranges: &[PartitionRange]) -> Result<(), AppError> {
+        if ranges.is_empty() {
+            return Ok(());
+        }
+        let mut tpl = TopicPartitionList::new();
+        for range in ranges {
+            tpl.add_partition_offset(&range.topic, range.partition, Offset::Offset(range.end))?;
+        }
+        self.consumer.commit(&tpl, CommitMode::Sync)?;
+        info!("Committed group:{} partitions:{}", self.group, tpl.count());
+        Ok(())
+    }
+}
+
+/// Replaces the topics of the offsets with the target topics, offsets of other topics are dropped.
+/// Later offsets of the same group and partition override earlier ones.
+pub fn target_offsets(
+    offsets: impl IntoIterator<Item = GroupOffset>,
+    targets: &HashMap<String, String>,
+) -> Vec<GroupOffset> {
+    let mut latest = BTreeMap::new();
+    for mut o in offsets {
+        let Some(target) = targets.get(o.topic()) else {
+            continue;
+        };
+        o.topic = Some(target.clone());
+        latest.insert((o.group().to_string(), target.clone(), o.partition()), o);
+    }
+    latest.into_values().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn offsets_are_translated() {
+        let mut map = OffsetMap::default();
+        // Source offsets 10..13 and 20, 21 (gap after compaction) restored at 0..5
+        for (target, source) in [10, 11, 12, 20, 21].into_iter().enumerate() {
+            map.record("t", 0, source, target as i64);
+        }
+        assert_eq!(map.partitions[&("t".to_string(), 0)].len(), 2);
+
+        assert_eq!(map.translate("t", 0, 0), Some(0));
+        assert_eq!(map.translate("t", 0, 11), Some(1));
+        assert_eq!(map.translate("t", 0, 15), Some(3));
+        assert_eq!(map.translate("t", 0, 21), Some(4));
+        assert_eq!(map.translate("t", 0, 22), Some(5));
+        assert_eq!(map.translate("t", 0, 100), Some(5));
+        assert_eq!(map.translate("t", 1, 10), None);
+
+        // The next archive of an incremental chain
+        let mut next = OffsetMap::default();
+        next.record("t", 0, 30, 5);
+        map.extend(next);
+        assert_eq!(map.translate("t", 0, 22), Some(5));
+        assert_eq!(map.translate("t", 0, 31), Some(6));
+    }
+
+    #[test]
+    fn offsets_follow_the_targets() {
+        let offsets = vec![
+            group_offset_new("g".to_string(), "a".to_string(), 0, 5),
+            group_offset_new("g".to_string(), "skipped".to_string(), 0, 5),
+            group_offset_new("g".to_string(), "a".to_string(), 0, 7),
+            group_offset_new("h".to_string(), "a".to_string(), 1, 3),
+        ];
+        let targets = HashMap::from([("a".to_string(), "b".to_string())]);
+        assert_eq!(
+            target_offsets(offsets, &targets),
+            vec![
+                group_offset_new("g".to_string(), "b".to_string(), 0, 7),
+                group_offset_new("h".to_string(), "b".to_string(), 1, 3),
+            ]
+        );
+    }
+}
@@ -0,0 +1,278 @@
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use clap::ValueEnum;
+use serde_json::{json, Value};
+
+use crate::{
+    crypto::Keys,
+    errors::AppError,
+    mbprocess::MProgressBars,
+    protos::{kafka_archive::ArchiveHeader, kafka_messages::KafkaMessage},
+    stream::{join_reader, StreamReader},
+};
+
+/// How to print keys and values in dump mode
+#[derive(ValueEnum, Debug, Clone, Copy)]
+pub enum Encoding {
+    Utf8,
+    Hex,
+    Base64,
+}
+
+impl Encoding {
+    fn encode(&self, data: Option<&[u8]>) -> Value {
+        match data {
+            None => Value::Null,
+            Some(data) => Value::String(match self {
+                Encoding::Utf8 => String::from_utf8_lossy(data).into_owned(),
+                Encoding::Hex => data.iter().map(|b| format!("{:02x}", b)).collect(),
+                Encoding::Base64 => STANDARD.encode(data),
+            }),
+        }
+    }
+}
+
+#[derive(Default)]
+struct SizeStats {
+    count: u64,
+    total: u64,
+    min: u64,
+    max: u64,
+}
+
+impl SizeStats {
+    fn add(&mut self, size: usize) {
+        let size = size as u64;
+        if self.count == 0 || size < self.min {
+            self.min = size;
+        }
+        if size > self.max {
+            self.max = size;
+        }
+        self.count += 1;
+        self.total += size;
+    }
+}
+
+impl std::fmt::Display for SizeStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.count == 0 {
+            return write!(f, "-");
+        }
+        write!(
+            f,
+            "total:{} min:{} avg:{} max:{}",
+            self.total,
+            self.min,
+            self.total / self.count,
+            self.max
+        )
+    }
+}
+
+#[derive(Default)]
+struct PartitionStats {
+    records: u64,
+    first_offset: Option<i64>,
+    last_offset: Option<i64>,
+    keys: SizeStats,
+    values: SizeStats,
+    tombstones: u64,
+}
+
+impl PartitionStats {
+    fn add(&mut self, kmsg: &KafkaMessage) {
+        self.records += 1;
+        if let Some(offset) = kmsg.offset {
+            self.first_offset.get_or_insert(offset);
+            self.last_offset = Some(offset);
+        }
+        if let Some(key) = &kmsg.key {
+            self.keys.add(key.len());
+        }
+        match &kmsg.value {
+            Some(value) => self.values.add(value.len()),
+            None => self.tombstones += 1,
+        }
+    }
+}
+
+fn offset_to_string(offset: Option<i64>) -> String {
+    offset.map_or("-".to_string(), |o| o.to_string())
+}
+
+fn print_header(file: &str, header: &Option<ArchiveHeader>) {
+    println!("Archive     : {}", file);
+    let header = match header {
+        Some(header) => header,
+        None => {
+            println!("Format      : legacy (no header)");
+            return;
+        }
+    };
+
+    println!("Created by  : akbt {}", header.tool_version());
+    println!("Created at  : {}", header.created_at());
+    println!("Brokers     : {}", header.brokers());
+    println!("Records     : {}", header.record_count());
+    println!("Compression : {:?}", header.compression());
+    for filter in header.filters.iter() {
+        println!("Filter      : {}", filter);
+    }
+    if let Some(volume) = header.volume {
+        println!("Volume      : {}", volume);
+    }
+    if let Some(encryption) = &header.encryption {
+        let source = match encryption.salt {
+            Some(_) => " derived from a passphrase",
+            None => "",
+        };
+        println!(
+            "Encryption  : {:?} key id:{}{}",
+            encryption.cipher(),
+            encryption.key_id(),
+            source
+        );
+    }
+    if header.compacted() {
+        println!("Compacted   : latest record of every key");
+    }
+    for group in header.group_offsets.iter() {
+        println!(
+            "Group       : {} {}/{} offset:{}",
+            group.group(),
+            group.topic(),
+            group.partition(),
+            group.offset()
+        );
+    }
+    if let Some(base) = &header.base_archive {
+        println!("Continues   : {}", base);
+    }
+    for topic in header.topics.iter() {
+        println!("Topic       : {}", topic.name());
+        println!("Partitions  : {}", topic.partitions.len());
+        if let Some(replication_factor) = topic.replication_factor {
+            println!("Replication : {}", replication_factor);
+        }
+        for part in topic.partitions.iter() {
+            println!(
+                "  {:>4}: watermarks {}..{} backed up {}..{}",
+                part.partition(),
+                part.low_watermark(),
+                part.high_watermark(),
+                part.offset_begin.unwrap_or(part.low_watermark()),
+                part.offset_end.unwrap_or(part.high_watermark())
+            );
+        }
+        for config in topic.configs.iter() {
+            println!("  {}={}", config.name(), config.value());
+        }
+        for schema in topic.schemas.iter() {
+            println!(
+                "  schema {} version:{} id:{}",
+                schema.subject(),
+                schema.version(),
+                schema.id()
+            );
+        }
+    }
+}
+
+fn dump_record(
+    out: &mut impl Write,
+    topic: Option<&str>,
+    kmsg: &KafkaMessage,
+    encoding: Encoding,
+) -> io::Result<()> {
+    let headers: Vec<Value> = kmsg
+        .headers
+        .iter()
+        .map(|h| json!({"key": h.key(), "value": encoding.encode(h.value.as_deref())}))
+        .collect();
+
+    let record = json!({
+        "topic": topic,
+        "partition": kmsg.partition,
+        "offset": kmsg.offset,
+        "timestamp": kmsg.timestamp,
+        "timestamp_type": kmsg.timestamp_type().as_str_name(),
+        "headers": headers,
+        "key": encoding.encode(kmsg.key.as_deref()),
+        "value": encoding.encode(kmsg.value.as_deref()),
+    });
+    writeln!(out, "{}", record)
+}
+
+pub fn inspect(
+    file: String,
+    dump: bool,
+    encoding: Encoding,
+    workers: usize,
+    keys: Option<Keys>,
+) -> Result<(), AppError> {
+    let mb = MProgressBars::restore(String::new(), file.clone(), true, None);
+    let (receiver, _, header, decoder_handler) =
+        StreamReader::run(file.clone(), workers, keys.as_ref(), mb)?;
+
+    // Legacy archives have no header, so their records have no topic name
+    let topic_names: Vec<&str> = header
+        .as_ref()
+        .map(|h| h.topics.iter().map(|t| t.name()).collect())
+        .unwrap_or_default();
+    let mut stats: BTreeMap<(u32, u32), PartitionStats> = BTreeMap::new();
+    let mut out = io::BufWriter::new(io::stdout().lock());
+
+    // Records before the corruption are still shown
+    let mut corruption = None;
+    for batch in receiver {
+        let batch = match batch {
+            Ok(batch) => batch,
+            Err(e) => {
+                corruption = Some(e);
+                break;
+            }
+        };
+        for kmsg in batch {
+            if dump {
+                let topic = topic_names.get(kmsg.topic() as usize).copied();
+                dump_record(&mut out, topic, &kmsg, encoding)
+                    .map_err(|e| AppError::IoError(e.to_string()))?;
+            } else {
+                stats
+                    .entry((kmsg.topic(), kmsg.partition()))
+                    .or_default()
+                    .add(&kmsg);
+            }
+        }
+    }
+    join_reader(decoder_handler)?;
+    out.flush().map_err(|e| AppError::IoError(e.to_string()))?;
+    drop(out);
+
+    if dump {
+        return corruption.map_or(Ok(()), Err);
+    }
+
+    print_header(&file, &header);
+    println!(
+        "Read records: {}",
+        stats.values().map(|s| s.records).sum::<u64>()
+    );
+    for ((topic, partition), s) in stats.iter() {
+        println!(
+            "{} partition {:>4}: records:{} offsets:{}..{} tombstones:{}",
+            topic_names.get(*topic as usize).unwrap_or(&"-"),
+            partition,
+            s.records,
+            offset_to_string(s.first_offset),
+            offset_to_string(s.last_offset),
+            s.tombstones
+        );
+        println!("  keys  : {}", s.keys);
+        println!("  values: {}", s.values);
+    }
+    corruption.map_or(Ok(()), Err)
+}
@@ -1,79 +1,489 @@
+mod admin;
+mod archive;
 mod backup;
+mod checkpoint;
+mod codec;
+mod compact;
+mod config;
 mod consumer;
 mod counters;
+mod crypto;
+mod delivery;
 mod errors;
-mod gzip;
+mod filter;
+mod groups;
+mod inspect;
 mod mbprocess;
+mod metrics;
+mod partitioner;
 mod protos;
 mod restore;
+mod s3;
+mod schema;
+mod storage;
+mod stream;
+mod transform;
+mod verify;
+mod volumes;
+mod workers;
 
 use std::fmt::Display;
 use std::process::ExitCode;
 
+use backup::BackupOptions;
 use clap::{Parser, Subcommand};
-use log::info;
+use codec::Codec;
+use config::{ConnectionArgs, KafkaConfig};
+use consumer::Bounds;
+use crypto::EncryptionArgs;
+use errors::AppError;
+use filter::Filter;
+use inspect::Encoding;
+use log::{error, info};
+use metrics::{Metrics, ProgressMode};
+use partitioner::Partitioning;
+use regex::Regex;
+use restore::RestoreOptions;
 use std::env;
+use std::sync::Arc;
+use std::time::Duration;
+use transform::TransformOptions;
+use volumes::VolumeLimits;
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 struct Args {
     #[arg(short, long, env("BOOTSTRAP_SERVERS"))]
-    bootstrap_servers: String,
-    #[arg(short, long, env("TOPIC"))]
-    topic: String,
+    bootstrap_servers: Option<String>,
+    #[command(flatten)]
+    connection: ConnectionArgs,
+    #[command(flatten)]
+    encryption: EncryptionArgs,
+    /// Topics to backup, comma separated. Target topic of a single topic archive on restore
+    #[arg(short, long, env("TOPIC"), value_delimiter = ',')]
+    topic: Vec<String>,
     #[command(subcommand)]
     cmd: Commands,
+    /// Archive file or directory, `-` writes the backup to stdout and restores from stdin,
+    /// s3://bucket/key is an object of an S3-compatible storage configured by AWS_* variables
     #[arg(short, long, env("FILE"))]
     file: String,
-    ///Compression level <0-9>(none-the_best)
+    ///Compression level, gzip <0-9>(none-the_best), zstd <0-22>(0 is the default)
     #[arg(short, long, default_value = "0")]
     level: u32,
+    /// Compression codec of the backup, restore detects it from the archive
+    #[arg(long, value_enum, default_value = "gzip")]
+    codec: Codec,
+    /// Threads compressing or decompressing blocks of the archive
+    #[arg(short = 'j', long, default_value_t = workers::default_workers())]
+    workers: usize,
+    /// How the progress of backup and restore is shown
+    #[arg(long, value_enum, default_value = "bars")]
+    progress: ProgressMode,
+    /// Seconds between JSON progress lines
+    #[arg(long, default_value_t = 10)]
+    progress_interval: u64,
+    /// Write the summary of the command as JSON into the file when it ends
+    #[arg(long)]
+    report: Option<String>,
+    /// Serve the counters in the Prometheus text format on <HOST:PORT> while running
+    #[arg(long, env("METRICS_ADDR"))]
+    metrics_addr: Option<String>,
 }
 
 #[derive(Subcommand, Debug, Clone)]
 enum Commands {
     /// Backup topic to file
-    Backup,
-    /// Restore topic from file
-    Restore,
+    Backup {
+        /// Backup records since the time (Unix time in milliseconds)
+        #[arg(long)]
+        from_time: Option<i64>,
+        /// Backup records before the time (Unix time in milliseconds)
+        #[arg(long)]
+        to_time: Option<i64>,
+        /// Backup only the last N records of every partition
+        #[arg(long, conflicts_with = "from_time")]
+        last: Option<i64>,
+        /// Offsets range of the partition <PARTITION:BEGIN-END>, END is exclusive.
+        /// Can be repeated, partitions without a range are skipped. Needs a single topic
+        #[arg(long, value_parser = parse_partition_range)]
+        offsets: Vec<(i32, i64, i64)>,
+        /// Backup only records added since the previous archive (or directory of archives)
+        #[arg(long, conflicts_with_all = ["from_time", "last", "offsets"])]
+        incremental_from: Option<String>,
+        /// Backup also all topics matching the regular expression
+        #[arg(long, value_parser = parse_topic_regex)]
+        topic_regex: Option<Regex>,
+        /// Write every topic into its own archive in the FILE directory
+        #[arg(long, conflicts_with_all = ["max_volume_size", "max_volume_records", "max_volume_time"])]
+        split_topics: bool,
+        /// Continue the interrupted backup of FILE from its checkpoint
+        #[arg(long, conflicts_with_all = ["incremental_from", "topic_regex", "filter", "compact", "groups",
+            "max_volume_size", "max_volume_records", "max_volume_time"])]
+        resume: bool,
+        /// Backup only records matching the filter: key^=PREFIX, key~=REGEX,
+        /// header.NAME=VALUE or $.JSON.PATH=VALUE. Can be repeated, all filters have to match
+        #[arg(long)]
+        filter: Vec<Filter>,
+        /// Schema registry URL to snapshot the key and value schemas of the topics from
+        #[arg(long, env("SCHEMA_REGISTRY"))]
+        schema_registry: Option<String>,
+        /// Keep only the latest record of every key (tombstones included) like log compaction,
+        /// the archive is written when all records are consumed
+        #[arg(long)]
+        compact: bool,
+        /// Keys indexed in memory while compacting, the index is spilled to disk beyond
+        #[arg(long, requires = "compact", default_value_t = compact::DEFAULT_MEMORY_KEYS)]
+        compact_memory_keys: usize,
+        /// Record the committed offsets of the consumer groups in the archive, comma separated
+        #[arg(long, value_delimiter = ',')]
+        groups: Vec<String>,
+        /// Roll the archive into numbered volumes of this size (K, M, G, T suffixes),
+        /// listed with their offset ranges and checksums in FILE.manifest.json
+        #[arg(long, value_parser = volumes::parse_size)]
+        max_volume_size: Option<u64>,
+        /// Roll the archive into numbered volumes of this many records
+        #[arg(long)]
+        max_volume_records: Option<u64>,
+        /// Roll the archive into numbered volumes after this many seconds
+        #[arg(long)]
+        max_volume_time: Option<u64>,
+        /// Keep consuming new records until SIGTERM or SIGINT, rolling them into volumes.
+        /// The end of every volume is committed under --group-id and a restart continues from it
+        #[arg(long, conflicts_with_all = ["to_time", "offsets", "incremental_from", "split_topics",
+            "resume", "compact"])]
+        follow: bool,
+        /// Consumer group of the backup
+        #[arg(long, env("GROUP_ID"), default_value = "akbt")]
+        group_id: String,
+    },
+    /// Restore topic from file or directory of archives
+    Restore {
+        /// Restore only the given archive without the archives it continues
+        #[arg(long)]
+        no_chain: bool,
+        /// Restore only these topics of the archive, comma separated
+        #[arg(long, value_delimiter = ',')]
+        only: Vec<String>,
+        /// Restore the topic into another one <SOURCE=TARGET>, can be repeated
+        #[arg(long, value_parser = parse_rename)]
+        rename: Vec<(String, String)>,
+        /// How records are spread over partitions of the target topic
+        #[arg(long, value_enum, default_value = "preserve")]
+        partitioning: Partitioning,
+        /// Create missing target topics with partitions and configs of the backup
+        #[arg(long)]
+        create_topics: bool,
+        /// Restore records before the corruption instead of refusing a damaged archive
+        #[arg(long)]
+        allow_partial: bool,
+        /// Continue the interrupted restore from its checkpoint
+        #[arg(long, conflicts_with_all = ["commit_groups", "group_offsets"])]
+        resume: bool,
+        /// Produce with the idempotent producer committing a transaction per block,
+        /// consumers of the target topics have to use isolation.level=read_committed
+        #[arg(long)]
+        exactly_once: bool,
+        /// Apply configs of the backup to existing target topics and add missing partitions
+        #[arg(long)]
+        apply_configs: bool,
+        /// Schema registry URL to register the schemas of the backup in,
+        /// schema ids of the records are replaced with the registered ones
+        #[arg(long, env("SCHEMA_REGISTRY"))]
+        schema_registry: Option<String>,
+        /// Don't produce tombstones (records without a value)
+        #[arg(long)]
+        skip_tombstones: bool,
+        /// Commit the consumer group offsets recorded in the archive,
+        /// translated to the offsets of the restored records
+        #[arg(long)]
+        commit_groups: bool,
+        /// Commit the consumer group offsets of the file written by export-offsets,
+        /// translated to the offsets of the restored records
+        #[arg(long)]
+        group_offsets: Option<String>,
+        /// Partitions of a FILE.manifest.json are split into streams restored at the same time,
+        /// every stream reads the volumes in order and restores the records of its partitions
+        #[arg(long, default_value_t = 1)]
+        parallel_partitions: usize,
+    },
+    /// Show the archive contents without connecting to Kafka
+    Inspect {
+        /// Print records as JSON lines instead of the summary
+        #[arg(long)]
+        dump: bool,
+        /// Encoding of keys and values in dump mode
+        #[arg(long, value_enum, default_value = "utf8")]
+        encoding: Encoding,
+    },
+    /// Check checksums and record counts of the archive or directory of archives
+    Verify,
+    /// Write the committed offsets of consumer groups for the topics into the JSON --file
+    ExportOffsets {
+        /// Consumer groups, comma separated
+        #[arg(long, required = true, value_delimiter = ',')]
+        groups: Vec<String>,
+    },
+    /// Write a filtered, redacted, merged or split copy of archives into --file without Kafka
+    Transform {
+        /// Archives to read, comma separated, records of several archives are merged in order
+        #[arg(short, long, required = true, value_delimiter = ',')]
+        input: Vec<String>,
+        /// Copy only these partitions, comma separated
+        #[arg(long, value_delimiter = ',')]
+        partitions: Vec<u32>,
+        /// Copy only records with a key matching the regex
+        #[arg(long, value_parser = Regex::new)]
+        key_regex: Option<Regex>,
+        /// Copy records since the time (Unix time in milliseconds)
+        #[arg(long)]
+        from_time: Option<i64>,
+        /// Copy records before the time (Unix time in milliseconds)
+        #[arg(long)]
+        to_time: Option<i64>,
+        /// Offsets range of the partition <PARTITION:BEGIN-END>, END is exclusive.
+        /// Can be repeated, partitions without a range are skipped. Needs a single topic
+        #[arg(long, value_parser = parse_partition_range)]
+        offsets: Vec<(i32, i64, i64)>,
+        /// Field of JSON values to redact, nested fields are separated by dots. Can be repeated
+        #[arg(long)]
+        redact: Vec<String>,
+        /// Write an archive per topic partition into the directory --file
+        #[arg(long)]
+        split_partitions: bool,
+    },
 }
 
 impl Display for Commands {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            Commands::Backup => write!(f, "Backup"),
-            Commands::Restore => write!(f, "Restore"),
+            Commands::Backup { .. } => write!(f, "Backup"),
+            Commands::Restore { .. } => write!(f, "Restore"),
+            Commands::Inspect { .. } => write!(f, "Inspect"),
+            Commands::Verify => write!(f, "Verify"),
+            Commands::ExportOffsets { .. } => write!(f, "ExportOffsets"),
+            Commands::Transform { .. } => write!(f, "Transform"),
         }
     }
 }
 
+fn parse_partition_range(s: &str) -> Result<(i32, i64, i64), String> {
+    let err = || format!("`{}` isn't in format PARTITION:BEGIN-END", s);
+    let (partition, range) = s.split_once(':').ok_or_else(err)?;
+    let (begin, end) = range.split_once('-').ok_or_else(err)?;
+    let partition = partition.parse().map_err(|_| err())?;
+    let begin = begin.parse().map_err(|_| err())?;
+    let end = end.parse().map_err(|_| err())?;
+    if begin > end {
+        return Err(format!("`{}` BEGIN is greater than END", s));
+    }
+    Ok((partition, begin, end))
+}
+
+fn parse_topic_regex(s: &str) -> Result<Regex, String> {
+    // The whole topic name has to match
+    Regex::new(&format!("^(?:{})$", s)).map_err(|e| e.to_string())
+}
+
+fn parse_rename(s: &str) -> Result<(String, String), String> {
+    match s.split_once('=') {
+        Some((source, target)) if !source.is_empty() && !target.is_empty() => {
+            Ok((source.to_string(), target.to_string()))
+        }
+        _ => Err(format!("`{}` isn't in format SOURCE=TARGET", s)),
+    }
+}
+
+fn single_topic(topics: Vec<String>) -> Result<Option<String>, AppError> {
+    if topics.len() > 1 {
+        return Err(AppError::InvalidArgument(
+            "restore accepts a single --topic, use --only and --rename".to_string(),
+        ));
+    }
+    Ok(topics.into_iter().next())
+}
+
+fn run(c: Args, log_enabled: bool, metrics: Arc<Metrics>) -> Result<(), AppError> {
+    match c.cmd {
+        Commands::Backup {
+            from_time,
+            to_time,
+            last,
+            offsets,
+            incremental_from,
+            topic_regex,
+            split_topics,
+            resume,
+            filter,
+            schema_registry,
+            compact,
+            compact_memory_keys,
+            groups,
+            max_volume_size,
+            max_volume_records,
+            max_volume_time,
+            follow,
+            group_id,
+        } => backup::backup(
+            KafkaConfig::new(c.bootstrap_servers, c.connection)?,
+            c.file,
+            BackupOptions {
+                topics: c.topic,
+                topic_regex,
+                codec: c.codec,
+                level: c.level,
+                workers: c.workers,
+                bounds: Bounds {
+                    from_time,
+                    to_time,
+                    last,
+                    offsets: offsets.into_iter().map(|(p, b, e)| (p, (b, e))).collect(),
+                    ..Default::default()
+                },
+                incremental_from,
+                split_topics,
+                resume,
+                filters: filter,
+                schema_registry,
+                compact: compact.then_some(compact_memory_keys),
+                groups,
+                volumes: VolumeLimits {
+                    size: max_volume_size,
+                    records: max_volume_records,
+                    time: max_volume_time.map(Duration::from_secs),
+                },
+                keys: c.encryption.keys()?,
+                group_id,
+                follow,
+            },
+            log_enabled,
+            metrics,
+        ),
+        Commands::Restore {
+            no_chain,
+            only,
+            rename,
+            partitioning,
+            create_topics,
+            allow_partial,
+            resume,
+            exactly_once,
+            apply_configs,
+            schema_registry,
+            skip_tombstones,
+            commit_groups,
+            group_offsets,
+            parallel_partitions,
+        } => restore::restore(
+            KafkaConfig::new(c.bootstrap_servers, c.connection)?,
+            c.file,
+            RestoreOptions {
+                topic: single_topic(c.topic)?,
+                only,
+                rename: rename.into_iter().collect(),
+                no_chain,
+                partitioning,
+                create_topics,
+                workers: c.workers,
+                allow_partial,
+                resume,
+                exactly_once,
+                apply_configs,
+                schema_registry,
+                skip_tombstones,
+                commit_groups,
+                group_offsets,
+                parallel_partitions,
+                keys: c.encryption.keys()?,
+            },
+            log_enabled,
+            metrics,
+        ),
+        Commands::Inspect { dump, encoding } => {
+            inspect::inspect(c.file, dump, encoding, c.workers, c.encryption.keys()?)
+        }
+        Commands::Verify => verify::verify(c.file, c.workers, c.encryption.keys()?),
+        Commands::ExportOffsets { groups } => groups::export_offsets(
+            &KafkaConfig::new(c.bootstrap_servers, c.connection)?,
+            &c.file,
+            &groups,
+            &c.topic,
+        ),
+        Commands::Transform {
+            input,
+            partitions,
+            key_regex,
+            from_time,
+            to_time,
+            offsets,
+            redact,
+            split_partitions,
+        } => transform::transform(
+            c.file,
+            TransformOptions {
+                inputs: input,
+                topic: single_topic(c.topic)?,
+                partitions,
+                key_regex,
+                from_time,
+                to_time,
+                offsets: offsets.into_iter().map(|(p, b, e)| (p, (b, e))).collect(),
+                redact,
+                split_partitions,
+                codec: c.codec,
+                level: c.level,
+                workers: c.workers,
+                keys: c.encryption.keys()?,
+            },
+        ),
+    }
+}
+
 fn main() -> ExitCode {
+    // Any option with an environment variable can be set in .env
+    dotenv::dotenv().ok();
     env_logger::init();
 
-    let log_enabled = if let Ok(_) = env::var("RUST_LOG") {
-        true
-    } else {
-        false
-    };
+    let log_enabled = env::var("RUST_LOG").is_ok();
 
     let c = Args::parse();
 
     info!("Another Kafka Backup Tool starting...");
 
-    info!("BOOTSTRAP_SERVERS: {}", c.bootstrap_servers);
-    info!("TOPIC: {}", c.topic);
+    info!(
+        "BOOTSTRAP_SERVERS: {}",
+        c.bootstrap_servers.as_deref().unwrap_or_default()
+    );
+    info!("TOPIC: {}", c.topic.join(","));
     info!("Command: {}", c.cmd);
 
-    let result = match c.cmd {
-        Commands::Backup => {
-            backup::backup(c.bootstrap_servers, c.topic, c.file, c.level, log_enabled)
+    let metrics = Metrics::new(c.cmd.to_string(), matches!(c.cmd, Commands::Backup { .. }));
+    let (progress, report) = (c.progress, c.report.clone());
+    if progress == ProgressMode::Json {
+        Metrics::json_lines(metrics.clone(), Duration::from_secs(c.progress_interval));
+    }
+    // Bars are drawn only in their mode and without logs
+    let hidden = log_enabled || progress != ProgressMode::Bars;
+    let result = match &c.metrics_addr {
+        Some(addr) => Metrics::serve(metrics.clone(), addr),
+        None => Ok(()),
+    }
+    .and_then(|_| run(c, hidden, metrics.clone()));
+
+    let summary = metrics.summary(&result);
+    if progress == ProgressMode::Json {
+        eprintln!("{}", summary);
+    }
+    if let Some(report) = report {
+        if let Err(e) = std::fs::write(&report, format!("{}\n", summary)) {
+            error!("Can't write the report `{}`: {}", report, e);
         }
-        Commands::Restore => restore::restore(c.bootstrap_servers, c.topic, c.file, log_enabled),
-    };
+    }
 
-    if result.is_err() {
-        println!("{:?}", result.unwrap_err().to_string());
-        return ExitCode::FAILURE;
+    // Errors go to stderr, stdout may carry the archive
+    if let Err(e) = result {
+        eprintln!("{:?}", e.to_string());
+        return ExitCode::from(e.exit_code());
     }
     ExitCode::SUCCESS
 }
@@ -6,16 +6,23 @@ use std::sync::Mutex;
 use std::thread;
 use std::time;
 
-use crate::{consumer::MyConsumer, errors::AppError};
+use crate::{
+    consumer::MyConsumer,
+    errors::AppError,
+    metrics::{Metrics, PartitionProgress},
+};
 type PartitionID = i32;
+type TopicID = usize;
 
 struct PartitionItem {
+    begin: i64,
     lastoffset: i64,
     lastpublished: i64,
+    end: i64,
     finished: bool,
 }
 
-type HashMapPartitions = HashMap<PartitionID, PartitionItem>;
+type HashMapPartitions = HashMap<(TopicID, PartitionID), PartitionItem>;
 
 const PB_PROCESS: &str = "{spinner:.green} [{elapsed_precise}]: [{bar:.green/red}] ETA:{eta}";
 const PB_HEADER_B1: &str = "Topic       : {msg}";
@@ -24,6 +31,9 @@ const PB_HEADER_B3: &str = "Records     : {human_pos} Total: {human_len}";
 const PB_HEADER_R1: &str = "Topic       : {msg}";
 const PB_HEADER_R2: &str = "Archive     : {msg}";
 const PB_HEADER_R3: &str = "Read bytes  : {human_pos} Total: {human_len}";
+const PB_HEADER_R3_RECORDS: &str = "Read records: {human_pos}";
+const PB_RECORDS: &str = "{spinner:.green} [{elapsed_precise}]: {human_pos} records";
+const PB_TOPIC: &str = "{msg:>24} [{bar:30.green/red}] {human_pos}/{human_len}";
 const PB_FINISH: &str = "{spinner:.green} {msg:>12} {bar:.green/red} done {elapsed_precise}";
 
 enum Action {
@@ -40,20 +50,39 @@ pub struct MProgressBars {
     header2: ProgressBar,
     header3: ProgressBar,
     progressbar: ProgressBar,
+    topicbars: Vec<ProgressBar>,
+    topic_names: Vec<String>,
+    metrics: Option<Arc<Metrics>>,
+    finished: bool,
+    /// Records read, the progress of an archive of unknown size
+    records: u64,
+    unknown_size: bool,
 }
 
 impl MProgressBars {
+    // Ticks until finished while there are bars or metrics to update
     pub fn ticker(mpb: Arc<Mutex<MProgressBars>>) {
-        let hidden = mpb.lock().unwrap().hidden;
-        if !hidden {
+        let (hidden, metrics) = {
+            let mpb = mpb.lock().unwrap();
+            (mpb.hidden, mpb.metrics.is_some())
+        };
+        if !hidden || metrics {
             thread::spawn(move || loop {
                 thread::sleep(time::Duration::from_millis(100));
-                mpb.lock().unwrap().tick();
+                let mut mpb = mpb.lock().unwrap();
+                if mpb.finished {
+                    break;
+                }
+                mpb.tick();
             });
         }
     }
 
-    pub fn backup(consumer: &MyConsumer, hidden: bool) -> Result<Arc<Mutex<Self>>, AppError> {
+    pub fn backup(
+        consumer: &MyConsumer,
+        hidden: bool,
+        metrics: Option<Arc<Metrics>>,
+    ) -> Result<Arc<Mutex<Self>>, AppError> {
         let mb = MultiProgress::new();
         let hashmap: HashMapPartitions = HashMap::new();
 
@@ -64,8 +93,10 @@ impl MProgressBars {
                 ProgressBar::new(0).with_style(ProgressStyle::with_template(PB_HEADER_B1).unwrap()),
             );
 
-            let topic_name = consumer.topic_name().to_string();
-            pb.set_message(topic_name);
+            let topic_names: Vec<&str> = (0..consumer.topics())
+                .map(|idx| consumer.topic_name(idx))
+                .collect();
+            pb.set_message(topic_names.join(", "));
             pb.finish();
             pb
         };
@@ -76,7 +107,7 @@ impl MProgressBars {
             let pb = mb.add(
                 ProgressBar::new(0).with_style(ProgressStyle::with_template(PB_HEADER_B2).unwrap()),
             );
-            pb.set_length(consumer.partitions() as u64);
+            pb.set_length(consumer.total_partitions() as u64);
             pb
         };
 
@@ -100,6 +131,24 @@ impl MProgressBars {
             pb
         };
 
+        // Progress of every topic is shown only when there are few of them
+        let topicbars = (0..consumer.topics())
+            .map(|idx| {
+                if hidden || consumer.topics() == 1 {
+                    return ProgressBar::hidden();
+                }
+                let pb = mb.add(
+                    ProgressBar::new(0).with_style(
+                        ProgressStyle::with_template(PB_TOPIC)
+                            .unwrap()
+                            .progress_chars("=>-"),
+                    ),
+                );
+                pb.set_message(consumer.topic_name(idx).to_string());
+                pb
+            })
+            .collect();
+
         let mut mpb = Self {
             mb,
             action: Action::Backup,
@@ -109,17 +158,32 @@ impl MProgressBars {
             header2,
             header3,
             progressbar,
+            topicbars,
+            topic_names: (0..consumer.topics())
+                .map(|idx| consumer.topic_name(idx).to_string())
+                .collect(),
+            metrics,
+            finished: false,
+            records: 0,
+            unknown_size: false,
         };
 
-        for part_idx in 0..consumer.partitions() {
-            let (offset_begin, offset_end) = consumer.offsets(part_idx)?;
-            mpb.add_pb(part_idx as i32, offset_begin, offset_end);
+        for topic_idx in 0..consumer.topics() {
+            for part_idx in 0..consumer.partitions(topic_idx) {
+                let (offset_begin, offset_end) = consumer.get_range(topic_idx, part_idx);
+                mpb.add_pb(topic_idx, part_idx, offset_begin, offset_end);
+            }
         }
 
         Ok(Arc::new(Mutex::new(mpb)))
     }
 
-    pub fn restore(topic: String, file: String, hidden: bool) -> Arc<Mutex<Self>> {
+    pub fn restore(
+        topic: String,
+        file: String,
+        hidden: bool,
+        metrics: Option<Arc<Metrics>>,
+    ) -> Arc<Mutex<Self>> {
         let mb = MultiProgress::new();
         let hashmap: HashMapPartitions = HashMap::with_capacity(1);
 
@@ -174,58 +238,109 @@ impl MProgressBars {
             header2,
             header3,
             progressbar,
+            topicbars: vec![ProgressBar::hidden()],
+            topic_names: vec![],
+            metrics,
+            finished: false,
+            records: 0,
+            unknown_size: false,
         }))
     }
 
-    pub fn add_pb(&mut self, id: i32, min: i64, max: i64) {
+    pub fn add_pb(&mut self, topic: TopicID, id: PartitionID, min: i64, max: i64) {
         match self.action {
             Action::Backup => (),
             Action::Restore => {
-                if self.hashmap.len() >= 1 {
+                if !self.hashmap.is_empty() {
                     panic!("Can't be few progress bar for restore process");
                 }
             }
         }
 
         let part_item = PartitionItem {
+            begin: min,
             lastoffset: min,
             lastpublished: min,
-            finished: false,
+            end: max,
+            finished: min >= max,
         };
 
-        match self.hashmap.insert(id, part_item) {
-            Some(_) => panic!("Can't insert twice PartitionID:{}", id),
-            None => (),
+        if self.hashmap.insert((topic, id), part_item).is_some() {
+            panic!("Can't insert twice TopicID:{} PartitionID:{}", topic, id);
         }
         if !self.hidden {
             self.progressbar
-                .set_length(self.progressbar.length().unwrap() + max as u64);
+                .set_length(self.progressbar.length().unwrap() + (max - min) as u64);
+            let topicbar = &self.topicbars[topic];
+            topicbar.set_length(topicbar.length().unwrap() + (max - min) as u64);
+        }
+    }
+
+    pub fn update(&mut self, topic: TopicID, id: PartitionID, pos: i64) {
+        let item = self.hashmap.get_mut(&(topic, id)).unwrap();
+        item.lastoffset = pos;
+        if let Action::Backup = self.action {
+            // Offsets are positions of records, the next one is expected after `pos`
+            item.lastoffset += 1;
+            item.finished = item.lastoffset >= item.end;
         }
     }
 
-    pub fn update(&mut self, id: PartitionID, pos: i64) {
-        self.hashmap.get_mut(&id).unwrap().lastoffset = pos;
+    pub fn finish_partition(&mut self, topic: TopicID, id: PartitionID) {
+        self.hashmap.get_mut(&(topic, id)).unwrap().finished = true;
     }
 
-    pub fn finish_partition(&mut self, id: PartitionID) {
+    pub fn set_topic(&self, topic: String) {
+        self.header1.set_message(topic);
+    }
+
+    /// The archive is read from a stream, the progress is shown in records instead of bytes
+    pub fn unknown_size(&mut self) {
+        self.unknown_size = true;
         if !self.hidden {
-            self.hashmap.get_mut(&id).unwrap().finished = true;
+            self.header3
+                .set_style(ProgressStyle::with_template(PB_HEADER_R3_RECORDS).unwrap());
+            self.progressbar
+                .set_style(ProgressStyle::with_template(PB_RECORDS).unwrap());
+        }
+    }
+
+    /// Records and bytes taken from the source
+    pub fn read(&mut self, records: u64, bytes: u64) {
+        self.records += records;
+        if let Some(metrics) = &self.metrics {
+            metrics.read(records, bytes);
+        }
+    }
+
+    /// Records and bytes delivered to the destination
+    pub fn written(&self, records: u64, bytes: u64) {
+        if let Some(metrics) = &self.metrics {
+            metrics.written(records, bytes);
         }
     }
 
     pub fn finish(&mut self) {
+        if !self.finished {
+            self.tick();
+            self.finished = true;
+        }
         if !self.hidden {
             self.header1.finish();
             self.header2.finish();
             self.header3.finish();
             self.progressbar.finish();
+            for topicbar in self.topicbars.iter() {
+                topicbar.finish();
+            }
         }
     }
 
     pub fn tick(&mut self) {
         let mut diff = 0;
         let mut finished = 0;
-        for (_, v) in self.hashmap.iter_mut() {
+        for ((topic, _), v) in self.hashmap.iter_mut() {
+            self.topicbars[*topic].inc((v.lastoffset - v.lastpublished) as u64);
             diff += v.lastoffset - v.lastpublished;
             v.lastpublished = v.lastoffset;
             if v.finished {
@@ -240,7 +355,39 @@ impl MProgressBars {
             self.progressbar.position(),
             HumanCount(self.progressbar.length().unwrap())
         ));
-        self.progressbar.inc(diff as u64);
+        if self.unknown_size {
+            self.header3.set_position(self.records);
+            self.progressbar.set_position(self.records);
+        } else {
+            self.progressbar.inc(diff as u64);
+        }
         self.header3.tick();
+        self.publish(diff);
+    }
+
+    // Positions of a backup are offsets, positions of a restore are bytes of the archive
+    fn publish(&self, diff: i64) {
+        let Some(metrics) = &self.metrics else {
+            return;
+        };
+        if let Action::Restore = self.action {
+            metrics.read(0, diff as u64);
+        }
+        let (mut done, mut total) = (0, 0);
+        let mut partitions = Vec::with_capacity(self.topic_names.len());
+        for ((topic, partition), v) in self.hashmap.iter() {
+            done += (v.lastoffset.min(v.end) - v.begin).max(0) as u64;
+            total += (v.end - v.begin).max(0) as u64;
+            if let Some(name) = self.topic_names.get(*topic) {
+                partitions.push(PartitionProgress {
+                    topic: name.clone(),
+                    partition: *partition,
+                    offset: v.lastoffset,
+                    end: v.end,
+                });
+            }
+        }
+        partitions.sort_by(|a, b| (&a.topic, a.partition).cmp(&(&b.topic, b.partition)));
+        metrics.set_progress(done, total, partitions);
     }
 }
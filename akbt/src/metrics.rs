@@ -0,0 +1,351 @@
+use std::{
+    fmt::Write as _,
+    io::{BufRead, BufReader, Write},
+    net::TcpListener,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use clap::ValueEnum;
+use log::warn;
+use serde::Serialize;
+
+use crate::errors::AppError;
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Default)]
+pub enum ProgressMode {
+    /// Progress bars on a terminal, hidden when RUST_LOG is set
+    #[default]
+    Bars,
+    /// Periodic JSON lines on stderr
+    Json,
+    None,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct PartitionProgress {
+    pub topic: String,
+    pub partition: i32,
+    /// Next offset to consume
+    pub offset: i64,
+    /// Offset where the partition is done
+    pub end: i64,
+}
+
+/// Counters of the running command
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Snapshot {
+    pub command: String,
+    pub elapsed_secs: f64,
+    pub records_read: u64,
+    pub records_written: u64,
+    pub records_per_sec: f64,
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+    /// Size of the records to the size of the archive
+    pub compression_ratio: Option<f64>,
+    /// Done part of the current backup or archive between 0 and 1
+    pub progress: f64,
+    pub eta_secs: Option<f64>,
+    pub partitions: Vec<PartitionProgress>,
+}
+
+/// Snapshot at the end of the command
+#[derive(Serialize, Debug)]
+struct Summary<'a> {
+    status: &'static str,
+    error: Option<String>,
+    finished_at: i64,
+    #[serde(flatten)]
+    snapshot: &'a Snapshot,
+}
+
+/// Counters shared by the workers of the command, the progress output and the metrics endpoint
+pub struct Metrics {
+    command: String,
+    /// The archive is the output (backup) rather than the input (restore)
+    writes_archive: bool,
+    started: Instant,
+    records_read: AtomicU64,
+    records_written: AtomicU64,
+    bytes_read: AtomicU64,
+    bytes_written: AtomicU64,
+    // Done and total units of the progress: offsets of a backup, bytes of a restored archive
+    progress: Mutex<(u64, u64)>,
+    partitions: Mutex<Vec<PartitionProgress>>,
+}
+
+impl Metrics {
+    pub fn new(command: String, writes_archive: bool) -> Arc<Self> {
+        Arc::new(Metrics {
+            command,
+            writes_archive,
+            started: Instant::now(),
+            records_read: AtomicU64::new(0),
+            records_written: AtomicU64::new(0),
+            bytes_read: AtomicU64::new(0),
+            bytes_written: AtomicU64::new(0),
+            progress: Mutex::new((0, 0)),
+            partitions: Mutex::default(),
+        })
+    }
+
+    pub fn read(&self, records: u64, bytes: u64) {
+        self.records_read.fetch_add(records, Ordering::Relaxed);
+        self.bytes_read.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn written(&self, records: u64, bytes: u64) {
+        self.records_written.fetch_add(records, Ordering::Relaxed);
+        self.bytes_written.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn set_progress(&self, done: u64, total: u64, partitions: Vec<PartitionProgress>) {
+        *self.progress.lock().unwrap() = (done, total);
+        *self.partitions.lock().unwrap() = partitions;
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        let elapsed = self.started.elapsed().as_secs_f64();
+        let records_read = self.records_read.load(Ordering::Relaxed);
+        let bytes_read = self.bytes_read.load(Ordering::Relaxed);
+        let bytes_written = self.bytes_written.load(Ordering::Relaxed);
+        let (records, archive) = if self.writes_archive {
+            (bytes_read, bytes_written)
+        } else {
+            (bytes_written, bytes_read)
+        };
+        let (done, total) = *self.progress.lock().unwrap();
+        let progress = match total {
+            0 => 0.0,
+            total => done.min(total) as f64 / total as f64,
+        };
+        Snapshot {
+            command: self.command.clone(),
+            elapsed_secs: elapsed,
+            records_read,
+            records_written: self.records_written.load(Ordering::Relaxed),
+            records_per_sec: if elapsed > 0.0 {
+                records_read as f64 / elapsed
+            } else {
+                0.0
+            },
+            bytes_read,
+            bytes_written,
+            compression_ratio: (archive > 0).then(|| records as f64 / archive as f64),
+            progress,
+            eta_secs: (progress > 0.0).then(|| elapsed * (1.0 - progress) / progress),
+            partitions: self.partitions.lock().unwrap().clone(),
+        }
+    }
+
+    /// Prints a JSON line with the snapshot to stderr every `interval`
+    pub fn json_lines(metrics: Arc<Metrics>, interval: Duration) {
+        thread::spawn(move || loop {
+            thread::sleep(interval);
+            match serde_json::to_string(&metrics.snapshot()) {
+                Ok(line) => eprintln!("{}", line),
+                Err(e) => warn!("Can't serialize progress: {}", e),
+            }
+        });
+    }
+
+    /// Serves the snapshot in the Prometheus text format at any path of `addr`
+    pub fn serve(metrics: Arc<Metrics>, addr: &str) -> Result<(), AppError> {
+        let listener = TcpListener::bind(addr)
+            .map_err(|e| AppError::InvalidArgument(format!("metrics address `{}`: {}", addr, e)))?;
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(mut stream) = stream else {
+                    continue;
+                };
+                // Only the request line is of interest, the request is answered the same way
+                let mut request = String::new();
+                let _ = BufReader::new(&stream).read_line(&mut request);
+                let body = prometheus(&metrics.snapshot());
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                );
+                if let Err(e) = stream.write_all(response.as_bytes()) {
+                    warn!("Can't send metrics: {}", e);
+                }
+            }
+        });
+        Ok(())
+    }
+
+    /// Final snapshot with the result of the command as JSON
+    pub fn summary(&self, result: &Result<(), AppError>) -> String {
+        let snapshot = self.snapshot();
+        let summary = Summary {
+            status: if result.is_ok() { "ok" } else { "failed" },
+            error: result.as_ref().err().map(|e| e.to_string()),
+            finished_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_millis() as i64)
+                .unwrap_or_default(),
+            snapshot: &snapshot,
+        };
+        serde_json::to_string(&summary).unwrap_or_default()
+    }
+}
+
+fn label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+// Text exposition format, metric families with their type
+fn prometheus(snapshot: &Snapshot) -> String {
+    let mut text = String::new();
+    let command = label(&snapshot.command);
+    let mut metric = |name: &str, kind: &str, help: &str, value: f64| {
+        let _ = writeln!(text, "# HELP akbt_{} {}", name, help);
+        let _ = writeln!(text, "# TYPE akbt_{} {}", name, kind);
+        let _ = writeln!(text, "akbt_{}{{command=\"{}\"}} {}", name, command, value);
+    };
+    metric(
+        "records_read_total",
+        "counter",
+        "Records read",
+        snapshot.records_read as f64,
+    );
+    metric(
+        "records_written_total",
+        "counter",
+        "Records written",
+        snapshot.records_written as f64,
+    );
+    metric(
+        "bytes_read_total",
+        "counter",
+        "Bytes read",
+        snapshot.bytes_read as f64,
+    );
+    metric(
+        "bytes_written_total",
+        "counter",
+        "Bytes written",
+        snapshot.bytes_written as f64,
+    );
+    metric(
+        "records_per_second",
+        "gauge",
+        "Records read per second",
+        snapshot.records_per_sec,
+    );
+    metric(
+        "progress_ratio",
+        "gauge",
+        "Done part between 0 and 1",
+        snapshot.progress,
+    );
+    if let Some(ratio) = snapshot.compression_ratio {
+        metric(
+            "compression_ratio",
+            "gauge",
+            "Size of the records to the size of the archive",
+            ratio,
+        );
+    }
+    if let Some(eta) = snapshot.eta_secs {
+        metric("eta_seconds", "gauge", "Estimated time left", eta);
+    }
+
+    if !snapshot.partitions.is_empty() {
+        for (name, help) in [
+            ("partition_offset", "Next offset to consume"),
+            ("partition_end_offset", "Offset where the partition is done"),
+        ] {
+            let _ = writeln!(text, "# HELP akbt_{} {}", name, help);
+            let _ = writeln!(text, "# TYPE akbt_{} gauge", name);
+            for p in snapshot.partitions.iter() {
+                let value = if name == "partition_offset" {
+                    p.offset
+                } else {
+                    p.end
+                };
+                let _ = writeln!(
+                    text,
+                    "akbt_{}{{command=\"{}\",topic=\"{}\",partition=\"{}\"}} {}",
+                    name,
+                    command,
+                    label(&p.topic),
+                    p.partition,
+                    value
+                );
+            }
+        }
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Read, net::TcpStream};
+
+    use super::*;
+
+    fn metrics() -> Arc<Metrics> {
+        let metrics = Metrics::new("Backup".to_string(), true);
+        metrics.read(10, 4000);
+        metrics.written(8, 1000);
+        metrics.set_progress(
+            25,
+            100,
+            vec![PartitionProgress {
+                topic: "a\"b".to_string(),
+                partition: 0,
+                offset: 25,
+                end: 100,
+            }],
+        );
+        metrics
+    }
+
+    #[test]
+    fn snapshot_estimates() {
+        let snapshot = metrics().snapshot();
+        assert_eq!(snapshot.compression_ratio, Some(4.0));
+        assert_eq!(snapshot.progress, 0.25);
+        // Three quarters are left after a quarter took the elapsed time
+        let eta = snapshot.eta_secs.unwrap();
+        assert!((eta - snapshot.elapsed_secs * 3.0).abs() < 1e-9);
+
+        let restore = Metrics::new("Restore".to_string(), false);
+        restore.read(0, 1000);
+        restore.written(10, 3000);
+        let snapshot = restore.snapshot();
+        assert_eq!(snapshot.compression_ratio, Some(3.0));
+        assert_eq!(snapshot.eta_secs, None);
+    }
+
+    #[test]
+    fn prometheus_endpoint() {
+        let metrics = metrics();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        drop(listener);
+        Metrics::serve(metrics, &addr).unwrap();
+
+        let mut stream = TcpStream::connect(&addr).unwrap();
+        stream.write_all(b"GET /metrics HTTP/1.1\r\n\r\n").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("# TYPE akbt_records_read_total counter\n"));
+        assert!(response.contains("akbt_bytes_written_total{command=\"Backup\"} 1000\n"));
+        assert!(response.contains("akbt_compression_ratio{command=\"Backup\"} 4\n"));
+        assert!(response.contains(
+            "akbt_partition_end_offset{command=\"Backup\",topic=\"a\\\"b\",partition=\"0\"} 100\n"
+        ));
+    }
+}
@@ -0,0 +1,149 @@
+use clap::ValueEnum;
+
+use crate::protos::kafka_messages::KafkaMessage;
+
+/// How records are spread over partitions of the target topic
+#[derive(ValueEnum, Debug, Clone, Copy, Default, PartialEq)]
+pub enum Partitioning {
+    /// Keep the partition of the record
+    #[default]
+    Preserve,
+    /// Hash the key like the Kafka default partitioner (murmur2)
+    Hash,
+    /// Spread records over partitions one by one
+    RoundRobin,
+    /// Original partition modulo the partition count of the target
+    Modulo,
+}
+
+/// Picks the target partition of restored records.
+/// Partition counts are indexed by the topic index of records.
+pub struct Partitioner {
+    strategy: Partitioning,
+    partitions: Vec<i32>,
+    next: Vec<i32>,
+}
+
+impl Partitioner {
+    pub fn new(strategy: Partitioning, partitions: Vec<i32>) -> Self {
+        let next = vec![0; partitions.len()];
+        Self {
+            strategy,
+            partitions,
+            next,
+        }
+    }
+
+    // None leaves the choice to the producer partitioner
+    pub fn partition(&mut self, topic_idx: usize, kmsg: &KafkaMessage) -> Option<i32> {
+        let partitions = self.partitions[topic_idx].max(1);
+        match self.strategy {
+            Partitioning::Preserve => Some(kmsg.partition() as i32),
+            Partitioning::Hash => kmsg
+                .key
+                .as_deref()
+                .map(|key| murmur2_partition(key, partitions)),
+            Partitioning::RoundRobin => {
+                let next = &mut self.next[topic_idx];
+                let partition = *next;
+                *next = (*next + 1) % partitions;
+                Some(partition)
+            }
+            Partitioning::Modulo => Some(kmsg.partition() as i32 % partitions),
+        }
+    }
+}
+
+// Same as `Utils.murmur2` of the Java client
+fn murmur2(data: &[u8]) -> i32 {
+    const SEED: u32 = 0x9747b28c;
+    const M: u32 = 0x5bd1e995;
+    const R: u32 = 24;
+
+    let length = data.len() as u32;
+    let mut h = SEED ^ length;
+
+    let mut chunks = data.chunks_exact(4);
+    for chunk in chunks.by_ref() {
+        let mut k = u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+        k = k.wrapping_mul(M);
+        k ^= k >> R;
+        k = k.wrapping_mul(M);
+        h = h.wrapping_mul(M);
+        h ^= k;
+    }
+
+    let tail = chunks.remainder();
+    if tail.len() >= 3 {
+        h ^= (tail[2] as u32) << 16;
+    }
+    if tail.len() >= 2 {
+        h ^= (tail[1] as u32) << 8;
+    }
+    if !tail.is_empty() {
+        h ^= tail[0] as u32;
+        h = h.wrapping_mul(M);
+    }
+
+    h ^= h >> 13;
+    h = h.wrapping_mul(M);
+    h ^= h >> 15;
+    h as i32
+}
+
+fn murmur2_partition(key: &[u8], partitions: i32) -> i32 {
+    (murmur2(key) & 0x7fffffff) % partitions
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protos::kafka_messages::{kafka_message_new, TimestampType};
+
+    fn message(key: Option<&str>, partition: u32) -> KafkaMessage {
+        kafka_message_new(
+            key.map(|k| k.as_bytes().to_vec()),
+            None,
+            Some(partition),
+            vec![],
+            None,
+            TimestampType::NotAvailable,
+            None,
+        )
+    }
+
+    #[test]
+    fn murmur2_matches_java_client() {
+        // Values from the Java client test suite
+        assert_eq!(murmur2(b"21"), -973932308);
+        assert_eq!(murmur2(b"foobar"), -790332482);
+        assert_eq!(murmur2(b"a-little-bit-long-string"), -985981536);
+        assert_eq!(murmur2(b"a-little-bit-longer-string"), -1486304829);
+        assert_eq!(
+            murmur2(b"lkjh234lh9fiuh90y23oiuhsafujhadof229phr9h19h89h8"),
+            -58897971
+        );
+        assert_eq!(murmur2(b"abc"), 479470107);
+    }
+
+    #[test]
+    fn strategies() {
+        let mut p = Partitioner::new(Partitioning::Preserve, vec![2]);
+        assert_eq!(p.partition(0, &message(None, 5)), Some(5));
+
+        let mut p = Partitioner::new(Partitioning::Modulo, vec![2]);
+        assert_eq!(p.partition(0, &message(None, 5)), Some(1));
+
+        let mut p = Partitioner::new(Partitioning::RoundRobin, vec![2, 3]);
+        let parts: Vec<_> = (0..4).map(|_| p.partition(1, &message(None, 0))).collect();
+        assert_eq!(parts, [Some(0), Some(1), Some(2), Some(0)]);
+        assert_eq!(p.partition(0, &message(None, 0)), Some(0));
+
+        let mut p = Partitioner::new(Partitioning::Hash, vec![3]);
+        assert_eq!(p.partition(0, &message(None, 0)), None);
+        assert_eq!(
+            p.partition(0, &message(Some("foobar"), 0)),
+            Some((-790332482 & 0x7fffffff) % 3)
+        );
+    }
+}
@@ -7,32 +7,105 @@ pub mod kafka_messages {
         key: Option<Vec<u8>>,
         value: Option<Vec<u8>>,
         partition: Option<u32>,
-        headers: Vec<Vec<u8>>,
+        headers: Vec<KafkaHeader>,
+        timestamp: Option<i64>,
+        timestamp_type: TimestampType,
+        offset: Option<i64>,
     ) -> KafkaMessage {
         KafkaMessage {
             key,
             value,
             partition,
             headers,
+            timestamp,
+            timestamp_type: Some(timestamp_type as i32),
+            offset,
+            topic: None,
+        }
+    }
+
+    pub fn kafka_header_new(key: String, value: Option<Vec<u8>>) -> KafkaHeader {
+        KafkaHeader {
+            key: Some(key),
+            value,
         }
     }
 
     pub fn kafka_message_pack(msg: &KafkaMessage) -> Vec<u8> {
-        let mut buf = Vec::new();
-        buf.reserve(msg.encoded_len());
+        let mut buf = Vec::with_capacity(msg.encoded_len());
         msg.encode(&mut buf).unwrap();
         buf
     }
 
     pub fn kafka_message_unpack(buf: &[u8]) -> Result<KafkaMessage, String> {
-        let res = KafkaMessage::decode(buf);
-        if res.is_err() {
-            return Err(format!("Can't decode message:{}", res.unwrap_err()));
-        }
-        Ok(res.unwrap())
+        KafkaMessage::decode(buf).map_err(|e| format!("Can't decode message:{}", e))
     }
 
     pub fn kafka_message_len(msg: &KafkaMessage) -> usize {
         msg.encoded_len()
     }
 }
+
+pub mod kafka_archive {
+    use prost::Message;
+
+    include!(concat!(env!("OUT_DIR"), "/kafka_archive.rs"));
+    pub fn partition_metadata_new(
+        partition: i32,
+        (low_watermark, high_watermark): (i64, i64),
+        (offset_begin, offset_end): (i64, i64),
+    ) -> PartitionMetadata {
+        PartitionMetadata {
+            partition: Some(partition),
+            low_watermark: Some(low_watermark),
+            high_watermark: Some(high_watermark),
+            offset_begin: Some(offset_begin),
+            offset_end: Some(offset_end),
+        }
+    }
+
+    pub fn config_entry_new(name: String, value: Option<String>) -> ConfigEntry {
+        ConfigEntry {
+            name: Some(name),
+            value,
+        }
+    }
+
+    pub fn topic_metadata_new(
+        name: String,
+        partitions: Vec<PartitionMetadata>,
+        configs: Vec<ConfigEntry>,
+    ) -> TopicMetadata {
+        TopicMetadata {
+            name: Some(name),
+            partitions,
+            configs,
+            replication_factor: None,
+            schemas: vec![],
+        }
+    }
+
+    pub fn group_offset_new(
+        group: String,
+        topic: String,
+        partition: i32,
+        offset: i64,
+    ) -> GroupOffset {
+        GroupOffset {
+            group: Some(group),
+            topic: Some(topic),
+            partition: Some(partition),
+            offset: Some(offset),
+        }
+    }
+
+    pub fn archive_header_pack(header: &ArchiveHeader) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(header.encoded_len());
+        header.encode(&mut buf).unwrap();
+        buf
+    }
+
+    pub fn archive_header_unpack(buf: &[u8]) -> Result<ArchiveHeader, String> {
+        ArchiveHeader::decode(buf).map_err(|e| format!("Can't decode archive header:{}", e))
+    }
+}
@@ -1,80 +1,864 @@
-use core::{panic, time};
 use std::{
+    collections::{HashMap, HashSet},
+    path::Path,
     sync::{mpsc::Receiver, Arc},
     thread,
-    time::Duration,
+    time::{Duration, Instant},
 };
 
+use log::{error, info, warn};
 use rdkafka::{
-    consumer::ConsumerContext,
-    error::KafkaError,
+    message::{Header, OwnedHeaders},
     producer::{BaseProducer, BaseRecord, Producer},
-    types::RDKafkaErrorCode,
-    ClientConfig, ClientContext,
 };
 
 use crate::{
-    errors::AppError, gzip::GzReader, mbprocess::MProgressBars,
-    protos::kafka_messages::KafkaMessage,
+    admin,
+    archive::{archive_chain, list_archives, read_archive_header},
+    checkpoint::{self, checkpoint_path, RestoreCheckpoint},
+    config::KafkaConfig,
+    crypto::Keys,
+    delivery::{self, DeliveryContext, DeliveryStats, RecordSource},
+    errors::AppError,
+    groups::{self, OffsetMap},
+    mbprocess::MProgressBars,
+    metrics::Metrics,
+    partitioner::{Partitioner, Partitioning},
+    protos::{
+        kafka_archive::{ArchiveHeader, GroupOffset},
+        kafka_messages::KafkaMessage,
+    },
+    schema::{remap_schema_id, SchemaRegistry},
+    stream::{is_stdio, join_reader, MessageBatch, StreamReader},
+    verify::{verify_archive, verify_volume},
+    volumes::{self, is_manifest, partition_streams, Manifest},
 };
 
-fn produce_worker(brokers: String, topic_name: String, receiver: Receiver<Vec<KafkaMessage>>) {
-    let prod: BaseProducer = ClientConfig::new()
-        .set("bootstrap.servers", &brokers)
-        .create()
-        .expect("Producer creation failed");
+fn record_from<'a>(
+    topic_name: &'a str,
+    partition: Option<i32>,
+    kmsg: &'a KafkaMessage,
+) -> BaseRecord<'a, [u8], [u8], RecordSource> {
+    let mut record =
+        BaseRecord::with_opaque_to(topic_name, delivery::source(kmsg.offset.unwrap_or(-1)));
+    record.partition = partition;
+    record.key = kmsg.key.as_deref();
+    record.payload = kmsg.value.as_deref();
+
+    if let Some(ts) = kmsg.timestamp {
+        record = record.timestamp(ts);
+    }
+
+    if !kmsg.headers.is_empty() {
+        let headers = kmsg.headers.iter().fold(
+            OwnedHeaders::new_with_capacity(kmsg.headers.len()),
+            |headers, h| {
+                headers.insert(Header {
+                    key: h.key(),
+                    value: h.value.as_deref(),
+                })
+            },
+        );
+        record = record.headers(headers);
+    }
+
+    record
+}
+
+const CHECKPOINT_INTERVAL: Duration = Duration::from_secs(5);
+const TRANSACTION_TIMEOUT: Duration = Duration::from_secs(60);
+// How long the producer retries the delivery of a record before it fails
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(600);
+
+type DeliveryProducer = BaseProducer<DeliveryContext>;
+
+// The idempotent producer retries deliveries itself and keeps the order of every partition.
+// A restarted restore with the same transactional id fences the old one.
+fn producer(
+    config: &KafkaConfig,
+    transactional_id: Option<&str>,
+    context: DeliveryContext,
+) -> Result<DeliveryProducer, AppError> {
+    let mut config = config.client_config();
+    config
+        // Records without a partition are hashed by key like the Java client does
+        .set("partitioner", "murmur2_random")
+        .set("enable.idempotence", "true")
+        .set(
+            "message.timeout.ms",
+            DELIVERY_TIMEOUT.as_millis().to_string(),
+        );
+    if let Some(transactional_id) = transactional_id {
+        // The delivery timeout can't exceed the transaction timeout
+        config.set("transactional.id", transactional_id).set(
+            "transaction.timeout.ms",
+            DELIVERY_TIMEOUT.as_millis().to_string(),
+        );
+    }
+    let prod: DeliveryProducer = config.create_with_context(context)?;
+    if transactional_id.is_some() {
+        prod.init_transactions(TRANSACTION_TIMEOUT)?;
+    }
+    Ok(prod)
+}
+
+// Flushes the producer, records sent so far have to be delivered
+fn flush(prod: &DeliveryProducer) -> Result<(), AppError> {
+    prod.flush(None)?;
+    prod.context().stats().check().map(|_| ())
+}
+
+// A transaction with records that couldn't be sent is aborted
+fn commit(prod: &DeliveryProducer) -> Result<(), AppError> {
+    let result = flush(prod).and_then(|_| {
+        prod.commit_transaction(TRANSACTION_TIMEOUT)
+            .map_err(AppError::from)
+    });
+    if let Err(e) = result {
+        warn!("Aborting the transaction: {}", e);
+        prod.abort_transaction(TRANSACTION_TIMEOUT)?;
+        return Err(e);
+    }
+    prod.context().stats().check().map(|_| ())
+}
+
+// Where the records of the archive go
+struct Routing {
+    /// Target topic by the topic index of a record, None skips the record
+    targets: Vec<Option<String>>,
+    partitioner: Partitioner,
+    /// Schema ids of keys and values replaced with the registered ones
+    schema_ids: HashMap<i32, i32>,
+    skip_tombstones: bool,
+    /// Topic indexes and partitions of the restored records, None restores all
+    partitions: Option<HashSet<(u32, u32)>>,
+}
+
+// Records produced before the checkpoint are skipped,
+// the checkpoint is updated when the records sent so far are delivered.
+// In transactional mode every batch is a transaction and the checkpoint follows every commit.
+// There is no checkpoint of an archive read from stdin.
+fn produce_batches(
+    prod: &DeliveryProducer,
+    transactional: bool,
+    mut routing: Routing,
+    receiver: Receiver<MessageBatch>,
+    checkpoint_file: Option<String>,
+    mut checkpoint: RestoreCheckpoint,
+) -> Result<(), AppError> {
+    let skip = checkpoint.records;
+    let mut records = 0;
+    let mut saved_at = Instant::now();
     for batch in receiver {
-        for kmsg in batch {
-            loop {
-                let record = BaseRecord::to(&topic_name)
-                    .key(kmsg.key())
-                    .payload(kmsg.value())
-                    .partition(kmsg.partition() as i32);
-                match prod.send(record) {
-                    Ok(_) => break,
-                    Err((KafkaError::MessageProduction(RDKafkaErrorCode::QueueFull), _)) => {
-                        prod.poll(Duration::from_millis(100));
-                        continue;
-                    }
-                    Err(e) => panic!("Can't sending message:{:?}", e),
+        let batch = batch?;
+        if transactional {
+            prod.begin_transaction()?;
+        }
+        for mut kmsg in batch {
+            records += 1;
+            if records <= skip {
+                continue;
+            }
+            let topic_name = match routing.targets.get(kmsg.topic() as usize) {
+                Some(Some(topic_name)) => topic_name,
+                _ => continue,
+            };
+            if routing.skip_tombstones && kmsg.value.is_none() {
+                continue;
+            }
+            if let Some(partitions) = &routing.partitions {
+                if !partitions.contains(&(kmsg.topic(), kmsg.partition())) {
+                    continue;
                 }
             }
+            if !routing.schema_ids.is_empty() {
+                for data in [&mut kmsg.key, &mut kmsg.value].into_iter().flatten() {
+                    remap_schema_id(data, &routing.schema_ids);
+                }
+            }
+            let partition = routing.partitioner.partition(kmsg.topic() as usize, &kmsg);
+            delivery::send(prod, record_from(topic_name, partition, &kmsg));
+        }
+        if transactional {
+            commit(prod)?;
+        }
+        let Some(checkpoint_file) = &checkpoint_file else {
+            continue;
+        };
+        if records > skip && (transactional || saved_at.elapsed() >= CHECKPOINT_INTERVAL) {
+            if !transactional {
+                flush(prod)?;
+            }
+            checkpoint.records = records;
+            checkpoint::save(checkpoint_file, &checkpoint)?;
+            saved_at = Instant::now();
         }
     }
-    prod.flush(None).unwrap();
+    Ok(())
 }
 
-pub fn restore(
-    _brokers: String,
-    _topic_name: String,
-    _file: String,
-    log_enabled: bool,
-) -> Result<(), AppError> {
-    let mb = MProgressBars::restore(_topic_name.clone(), _file.clone(), log_enabled);
-    let (receiver, max, decoder_handler) = GzReader::run(_file, mb.clone())?;
+// Returns the delivery counts and the offsets of delivered records even when the restore fails
+fn produce_worker(
+    config: KafkaConfig,
+    transactional_id: Option<String>,
+    context: DeliveryContext,
+    routing: Routing,
+    receiver: Receiver<MessageBatch>,
+    checkpoint_file: Option<String>,
+    checkpoint: RestoreCheckpoint,
+) -> (DeliveryStats, OffsetMap, Result<(), AppError>) {
+    let prod = match producer(&config, transactional_id.as_deref(), context) {
+        Ok(prod) => prod,
+        Err(e) => return (DeliveryStats::default(), OffsetMap::default(), Err(e)),
+    };
+    let result = produce_batches(
+        &prod,
+        transactional_id.is_some(),
+        routing,
+        receiver,
+        checkpoint_file,
+        checkpoint,
+    );
+    // Records before a corruption are still delivered
+    let flushed = flush(&prod);
+    let context = prod.context();
+    (context.stats(), context.offsets(), result.and(flushed))
+}
+
+/// Which topics to restore and where
+#[derive(Debug, Clone, Default)]
+pub struct RestoreOptions {
+    /// Target topic of a single topic archive
+    pub topic: Option<String>,
+    /// Restore only these topics of the archive
+    pub only: Vec<String>,
+    /// Source topic -> target topic
+    pub rename: HashMap<String, String>,
+    /// Restore only the given archive without the archives it continues
+    pub no_chain: bool,
+    pub partitioning: Partitioning,
+    /// Create missing target topics like they were at backup time
+    pub create_topics: bool,
+    /// Decompression threads
+    pub workers: usize,
+    /// Restore records before the corruption of a damaged archive
+    pub allow_partial: bool,
+    /// Continue the interrupted restore from its checkpoint
+    pub resume: bool,
+    /// Produce every block in a transaction
+    pub exactly_once: bool,
+    /// Apply configs and partition counts of the backup to existing topics
+    pub apply_configs: bool,
+    /// Schema registry to register the schemas of the backup in
+    pub schema_registry: Option<String>,
+    /// Don't produce records without a value
+    pub skip_tombstones: bool,
+    /// Commit the consumer group offsets recorded in the archives
+    pub commit_groups: bool,
+    /// Commit the consumer group offsets of the exported offsets file
+    pub group_offsets: Option<String>,
+    /// Partition streams of a manifest restored at the same time
+    pub parallel_partitions: usize,
+    /// Decrypts encrypted archives
+    pub keys: Option<Keys>,
+}
+
+impl RestoreOptions {
+    fn commits_groups(&self) -> bool {
+        self.commit_groups || self.group_offsets.is_some()
+    }
+}
+
+// Registers the schemas of every restored topic under the target topic.
+// Returns the new id of every schema id of the archive.
+fn register_schemas(
+    header: &Option<ArchiveHeader>,
+    targets: &[Option<String>],
+    opts: &RestoreOptions,
+) -> Result<HashMap<i32, i32>, AppError> {
+    let (Some(url), Some(header)) = (&opts.schema_registry, header) else {
+        return Ok(HashMap::new());
+    };
+    let registry = SchemaRegistry::new(url);
+    let mut ids = HashMap::new();
+    for (topic, target) in header.topics.iter().zip(targets) {
+        if let Some(target) = target {
+            ids.extend(registry.register(&topic.schemas, topic.name(), target)?);
+        }
+    }
+    Ok(ids)
+}
+
+fn topic_targets(
+    header: &Option<ArchiveHeader>,
+    opts: &RestoreOptions,
+) -> Result<Vec<Option<String>>, AppError> {
+    let topics = match header {
+        Some(header) => &header.topics,
+        None => {
+            // Legacy archives don't know their topic
+            let topic = opts
+                .topic
+                .clone()
+                .ok_or_else(|| AppError::MissingArgument("topic".to_string()))?;
+            return Ok(vec![Some(topic)]);
+        }
+    };
+
+    if let Some(topic) = &opts.topic {
+        if topics.len() != 1 {
+            return Err(AppError::InvalidArgument(
+                "--topic can't be used with a multi-topic archive, use --rename".to_string(),
+            ));
+        }
+        return Ok(vec![Some(topic.clone())]);
+    }
 
-    mb.lock().unwrap().add_pb(0, 0, max as i64);
+    Ok(topics
+        .iter()
+        .map(|t| {
+            let name = t.name().to_string();
+            if !opts.only.is_empty() && !opts.only.contains(&name) {
+                return None;
+            }
+            Some(opts.rename.get(&name).cloned().unwrap_or(name))
+        })
+        .collect())
+}
+
+// Returns the partition count of every target topic, missing topics are created on demand.
+// Existing topics get the configs and the partitions of the backup on demand.
+fn target_partitions(
+    config: &KafkaConfig,
+    header: &Option<ArchiveHeader>,
+    targets: &[Option<String>],
+    opts: &RestoreOptions,
+) -> Result<Vec<i32>, AppError> {
+    let existing = admin::topic_partitions(config)?;
+    let mut partitions = Vec::with_capacity(targets.len());
+    for (topic_idx, target) in targets.iter().enumerate() {
+        let Some(target) = target else {
+            partitions.push(0);
+            continue;
+        };
+        let source = header.as_ref().map(|h| &h.topics[topic_idx]);
+        let count = match (existing.get(target), source) {
+            (Some(count), Some(source)) if opts.apply_configs => {
+                info!("Applying configs of the backup to topic:{}", target);
+                admin::alter_topic_configs(config, target, source)?;
+                let source_count = source.partitions.len() as i32;
+                if source_count > *count {
+                    info!(
+                        "Adding partitions to topic:{} up to {}",
+                        target, source_count
+                    );
+                    admin::grow_partitions(config, target, source_count as usize)?;
+                }
+                source_count.max(*count)
+            }
+            (Some(count), _) => *count,
+            (None, Some(source)) if opts.create_topics => {
+                info!(
+                    "Creating topic:{} partitions:{}",
+                    target,
+                    source.partitions.len()
+                );
+                admin::create_topic(config, target, source)?;
+                source.partitions.len() as i32
+            }
+            (None, None) if opts.create_topics => {
+                return Err(AppError::BadArchive(format!(
+                    "no metadata to create topic:{}",
+                    target
+                )))
+            }
+            (None, _) => return Err(AppError::TopicNotFound(target.clone())),
+        };
+
+        if let Some(source) = source {
+            let source_count = source.partitions.len() as i32;
+            if opts.partitioning == Partitioning::Preserve && source_count > count {
+                return Err(AppError::InvalidArgument(format!(
+                    "topic:{} has {} partitions, the archive has {}, choose another --partitioning",
+                    target, count, source_count
+                )));
+            }
+            if source_count != count {
+                warn!(
+                    "Topic:{} has {} partitions, the archive has {}",
+                    target, count, source_count
+                );
+            }
+        }
+        partitions.push(count);
+    }
+    Ok(partitions)
+}
+
+// Target topics of an archive, ready to receive its records
+#[derive(Clone, Default)]
+struct Targets {
+    names: Vec<Option<String>>,
+    partitions: Vec<i32>,
+    schema_ids: HashMap<i32, i32>,
+}
 
-    if !log_enabled {
-        let mb_clone = Arc::clone(&mb);
-        thread::spawn(move || loop {
-            thread::sleep(time::Duration::from_millis(100));
-            mb_clone.lock().unwrap().tick();
+// Creates or alters the target topics and registers the schemas,
+// nothing is done without a selected topic
+fn prepare_targets(
+    config: &KafkaConfig,
+    header: &Option<ArchiveHeader>,
+    opts: &RestoreOptions,
+) -> Result<Targets, AppError> {
+    let names = topic_targets(header, opts)?;
+    if names.iter().all(Option::is_none) {
+        return Ok(Targets {
+            names,
+            ..Default::default()
         });
     }
+    Ok(Targets {
+        partitions: target_partitions(config, header, &names, opts)?,
+        schema_ids: register_schemas(header, &names, opts)?,
+        names,
+    })
+}
+
+// A partition stream of a manifest restored next to the other streams
+struct Stream<'a> {
+    idx: usize,
+    /// Topics and partitions of the restored records
+    partitions: &'a HashSet<(String, i32)>,
+    /// Targets of every volume, prepared before the streams start
+    targets: &'a HashMap<String, Targets>,
+}
+
+// With a stream only the records of its partitions are restored
+fn restore_archive(
+    config: KafkaConfig,
+    file: String,
+    opts: &RestoreOptions,
+    stream: Option<&Stream>,
+    (checkpoint_file, checkpoint): (Option<&str>, &RestoreCheckpoint),
+    log_enabled: bool,
+    metrics: Arc<Metrics>,
+) -> Result<(DeliveryStats, OffsetMap), AppError> {
+    // The header comes from the reader, so an archive on stdin is read once
+    let file_name = file.clone();
+    let mb = MProgressBars::restore(String::new(), file, log_enabled, Some(metrics.clone()));
+    let (receiver, _, header, decoder_handler) = StreamReader::run(
+        file_name.clone(),
+        opts.workers,
+        opts.keys.as_ref(),
+        mb.clone(),
+    )?;
+
+    let targets = match stream.and_then(|stream| stream.targets.get(&file_name)) {
+        Some(targets) => targets.clone(),
+        None => prepare_targets(&config, &header, opts)?,
+    };
+    let target_names: Vec<String> = targets.names.iter().flatten().cloned().collect();
+    if target_names.is_empty() {
+        info!("Archive:{} has no selected topics", file_name);
+        return Ok((DeliveryStats::default(), OffsetMap::default()));
+    }
+    mb.lock().unwrap().set_topic(target_names.join(", "));
+    let routing = Routing {
+        partitioner: Partitioner::new(opts.partitioning, targets.partitions),
+        schema_ids: targets.schema_ids,
+        skip_tombstones: opts.skip_tombstones,
+        partitions: stream.map(|stream| {
+            let partitions = stream.partitions;
+            let topics = header.iter().flat_map(|h| h.topics.iter());
+            topics
+                .enumerate()
+                .flat_map(|(idx, topic)| {
+                    topic
+                        .partitions
+                        .iter()
+                        .filter(|p| partitions.contains(&(topic.name().to_string(), p.partition())))
+                        .map(move |p| (idx as u32, p.partition() as u32))
+                })
+                .collect()
+        }),
+        targets: targets.names,
+    };
+
+    if let Some(header) = header {
+        let topic_names: Vec<&str> = header.topics.iter().map(|t| t.name()).collect();
+        info!(
+            "Archive of topics:{} created:{} by akbt:{} records:{}",
+            topic_names.join(", "),
+            header.created_at(),
+            header.tool_version(),
+            header.record_count()
+        );
+    }
+
+    MProgressBars::ticker(mb.clone());
 
+    // Streams restore the same volumes, so each has its own transactional id
+    let transactional_id = opts.exactly_once.then(|| match stream {
+        Some(stream) => format!("akbt-restore-{}-{}", file_name, stream.idx),
+        None => format!("akbt-restore-{}", file_name),
+    });
+    let (checkpoint_file, checkpoint) = (checkpoint_file.map(String::from), checkpoint.clone());
+    // Offsets of the delivered records are mapped for committing consumer groups
+    let context = DeliveryContext::new(opts.commits_groups(), Some(metrics));
     let prod_handler = thread::spawn(move || {
-        produce_worker(_brokers, _topic_name, receiver);
+        produce_worker(
+            config,
+            transactional_id,
+            context,
+            routing,
+            receiver,
+            checkpoint_file,
+            checkpoint,
+        )
     });
 
-    decoder_handler.join().unwrap();
-    prod_handler.join().unwrap();
+    let joined = prod_handler.join();
+    let decoded = join_reader(decoder_handler);
+    let (stats, offsets, result) =
+        joined.map_err(|_| AppError::Panicked("producer".to_string()))?;
+    decoded?;
     mb.lock().unwrap().finish();
-    Ok(())
+    info!("Archive:{} records {}", file_name, stats);
+    match result {
+        Err(e @ AppError::BadArchive(_)) if opts.allow_partial => {
+            error!("Archive:{} is restored partially: {}", file_name, e);
+            Ok((stats, offsets))
+        }
+        result => result.map(|_| (stats, offsets)),
+    }
 }
 
-struct RestoreContext;
+// Archives of a directory are restored in order of creation
+fn directory_archives(path: &str) -> Result<Vec<String>, AppError> {
+    let mut archives = vec![];
+    for pathfile in list_archives(path)? {
+        let created_at = read_archive_header(&pathfile)?
+            .map(|h| h.created_at())
+            .unwrap_or_default();
+        archives.push((created_at, pathfile));
+    }
+    archives.sort();
+    Ok(archives.into_iter().map(|(_, pathfile)| pathfile).collect())
+}
 
-impl ClientContext for RestoreContext {}
+// Archives are restored one after another, the checkpoint keeps the restored ones
+// and the progress of the current one
+fn restore_archives(
+    config: &KafkaConfig,
+    archives: &[String],
+    opts: &RestoreOptions,
+    stream: Option<&Stream>,
+    (checkpoint_file, mut checkpoint): (Option<&str>, RestoreCheckpoint),
+    log_enabled: bool,
+    metrics: Arc<Metrics>,
+) -> Result<(DeliveryStats, OffsetMap), AppError> {
+    let mut stats = DeliveryStats::default();
+    let mut offsets = OffsetMap::default();
+    for pathfile in archives {
+        if checkpoint.done.contains(pathfile) {
+            info!("Archive:{} is already restored", pathfile);
+            continue;
+        }
+        if checkpoint.archive.as_ref() != Some(pathfile) {
+            checkpoint.archive = Some(pathfile.clone());
+            checkpoint.records = 0;
+        }
+        if let Some(checkpoint_file) = checkpoint_file {
+            checkpoint::save(checkpoint_file, &checkpoint)?;
+        }
+        info!(
+            "Restoring archive:{} from record:{}",
+            pathfile, checkpoint.records
+        );
+        let (archive_stats, archive_offsets) = restore_archive(
+            config.clone(),
+            pathfile.clone(),
+            opts,
+            stream,
+            (checkpoint_file, &checkpoint),
+            log_enabled,
+            metrics.clone(),
+        )
+        .map_err(|e| match e {
+            // The summary covers the archives restored before
+            AppError::Undelivered(mut failed) => {
+                failed += stats.clone();
+                AppError::Undelivered(failed)
+            }
+            e => e,
+        })?;
+        stats += archive_stats;
+        offsets.extend(archive_offsets);
+        checkpoint.done.push(pathfile.clone());
+        checkpoint.archive = None;
+    }
+    Ok((stats, offsets))
+}
+
+// Targets of every volume, volumes with the topics of the previous volume share its targets
+fn volume_targets(
+    config: &KafkaConfig,
+    paths: &[String],
+    opts: &RestoreOptions,
+) -> Result<HashMap<String, Targets>, AppError> {
+    let mut targets = HashMap::new();
+    let mut prepared: Option<(Option<ArchiveHeader>, Targets)> = None;
+    for path in paths {
+        let header = read_archive_header(path)?;
+        let volume_targets = match &prepared {
+            Some((h, t)) if h.as_ref().map(|h| &h.topics) == header.as_ref().map(|h| &h.topics) => {
+                t.clone()
+            }
+            _ => {
+                let t = prepare_targets(config, &header, opts)?;
+                prepared = Some((header, t.clone()));
+                t
+            }
+        };
+        targets.insert(path.clone(), volume_targets);
+    }
+    Ok(targets)
+}
+
+// Partitions of the manifest are split into up to `parallel_partitions` streams restored at
+// the same time. Every stream reads the volumes with records of its partitions in order and
+// restores only these records, so the records of a partition keep their order.
+// The checkpoint of the manifest keeps the number of streams for a resumed restore,
+// every stream has its own checkpoint.
+fn restore_volumes(
+    config: &KafkaConfig,
+    file: &str,
+    manifest: &Manifest,
+    opts: &RestoreOptions,
+    (checkpoint_file, mut checkpoint): (&str, RestoreCheckpoint),
+    log_enabled: bool,
+    metrics: Arc<Metrics>,
+) -> Result<(DeliveryStats, OffsetMap), AppError> {
+    if checkpoint.streams == 0 {
+        checkpoint.streams = opts.parallel_partitions.max(1);
+        checkpoint::save(checkpoint_file, &checkpoint)?;
+    }
+    let paths = manifest.volume_paths(file);
+    let streams = match partition_streams(&manifest.volumes, checkpoint.streams) {
+        streams if streams.len() > 1 => streams.into_iter().map(Some).collect(),
+        _ => vec![None],
+    };
+    // The streams share the target topics, they are set up once before the streams start
+    let targets = if streams.len() > 1 {
+        volume_targets(config, &paths, opts)?
+    } else {
+        HashMap::new()
+    };
+    let targets = Arc::new(targets);
+
+    let mut handles = Vec::with_capacity(streams.len());
+    for (idx, partitions) in streams.into_iter().enumerate() {
+        let stream_file = checkpoint_path(file, &format!("restore-checkpoint-{}", idx));
+        let stream_checkpoint = match checkpoint::load(&stream_file)? {
+            Some(cp) if opts.resume => cp,
+            _ => RestoreCheckpoint::default(),
+        };
+        let volumes: Vec<String> = paths
+            .iter()
+            .zip(&manifest.volumes)
+            .filter(|(_, volume)| {
+                partitions.as_ref().is_none_or(|partitions| {
+                    volume
+                        .ranges
+                        .iter()
+                        .any(|r| partitions.contains(&(r.topic.clone(), r.partition)))
+                })
+            })
+            .map(|(path, _)| path.clone())
+            .collect();
+        info!("Restoring stream:{} of volumes:{}", idx, volumes.len());
+
+        let (config, opts, metrics) = (config.clone(), opts.clone(), metrics.clone());
+        let targets = targets.clone();
+        handles.push(thread::spawn(move || {
+            let stream = partitions.as_ref().map(|partitions| Stream {
+                idx,
+                partitions,
+                targets: &targets,
+            });
+            let restored = restore_archives(
+                &config,
+                &volumes,
+                &opts,
+                stream.as_ref(),
+                (Some(&stream_file), stream_checkpoint),
+                log_enabled,
+                metrics,
+            );
+            (stream_file, restored)
+        }));
+    }
 
-impl ConsumerContext for RestoreContext {}
+    let mut stats = DeliveryStats::default();
+    let mut offsets = OffsetMap::default();
+    let mut stream_files = vec![];
+    let mut result = Ok(());
+    for handle in handles {
+        let (stream_file, restored) = handle
+            .join()
+            .map_err(|_| AppError::Panicked("stream".to_string()))?;
+        match restored {
+            Ok((stream_stats, stream_offsets)) => {
+                stats += stream_stats;
+                offsets.extend(stream_offsets);
+                stream_files.push(stream_file);
+            }
+            Err(AppError::Undelivered(failed)) => {
+                stats += failed;
+                result = result.and(Err(AppError::Undelivered(DeliveryStats::default())));
+            }
+            Err(e) => result = result.and(Err(e)),
+        }
+    }
+
+    // The summary covers all streams, a failed stream is resumed from its checkpoint
+    match result {
+        Err(AppError::Undelivered(_)) => Err(AppError::Undelivered(stats)),
+        Err(e) => Err(e),
+        Ok(()) => {
+            for stream_file in stream_files {
+                checkpoint::remove(&stream_file)?;
+            }
+            Ok((stats, offsets))
+        }
+    }
+}
+
+// Consumer group offsets of the archives and of the offsets file with the target topics
+fn restored_group_offsets(
+    archives: &[String],
+    opts: &RestoreOptions,
+) -> Result<Vec<GroupOffset>, AppError> {
+    let mut targets = HashMap::new();
+    let mut offsets = vec![];
+    for pathfile in archives {
+        let header = read_archive_header(pathfile)?;
+        let names = topic_targets(&header, opts)?;
+        let Some(header) = header else {
+            continue;
+        };
+        for (topic, target) in header.topics.iter().zip(names) {
+            if let Some(target) = target {
+                targets.insert(topic.name().to_string(), target);
+            }
+        }
+        if opts.commit_groups {
+            offsets.extend(header.group_offsets);
+        }
+    }
+    if let Some(file) = &opts.group_offsets {
+        offsets.extend(groups::load_offsets(file)?);
+    }
+    Ok(groups::target_offsets(offsets, &targets))
+}
+
+pub fn restore(
+    config: KafkaConfig,
+    file: String,
+    opts: RestoreOptions,
+    log_enabled: bool,
+    metrics: Arc<Metrics>,
+) -> Result<(), AppError> {
+    let checkpoint_file = checkpoint_path(&file, "restore-checkpoint");
+    let stdin = is_stdio(&file);
+    if stdin && opts.resume {
+        return Err(AppError::InvalidArgument(
+            "a restore from stdin can't be resumed".to_string(),
+        ));
+    }
+    if stdin && opts.commits_groups() {
+        return Err(AppError::InvalidArgument(
+            "consumer group offsets can't be committed when restoring from stdin".to_string(),
+        ));
+    }
+    let archives = if Path::new(&file).is_dir() {
+        directory_archives(&file)?
+    } else if is_manifest(&file) {
+        list_archives(&file)?
+    } else if stdin || opts.no_chain {
+        vec![file.clone()]
+    } else {
+        archive_chain(&file)?
+    };
+
+    let manifest = match is_manifest(&file) {
+        true => Some(volumes::load(&file)?),
+        false => None,
+    };
+
+    // Nothing is restored until all archives are known to be intact,
+    // stdin can be read only once and is checked while it's restored.
+    // Volumes have to match the manifest.
+    if !opts.allow_partial && !stdin {
+        for (idx, pathfile) in archives.iter().enumerate() {
+            info!("Verifying archive:{}", pathfile);
+            let verified = match manifest.as_ref().map(|m| &m.volumes[idx]) {
+                Some(volume) => verify_volume(pathfile, volume, opts.workers, opts.keys.as_ref()),
+                None => verify_archive(pathfile, opts.workers, opts.keys.as_ref()),
+            };
+            verified.map_err(|e| match e {
+                AppError::Encryption(_) => e,
+                e => AppError::Corrupted(format!(
+                    "{} {}, use --allow-partial to restore records before the corruption",
+                    pathfile, e
+                )),
+            })?;
+        }
+    }
+
+    let checkpoint = if opts.resume {
+        checkpoint::load(&checkpoint_file)?.ok_or_else(|| {
+            AppError::InvalidArgument(format!("there is no interrupted restore of `{}`", file))
+        })?
+    } else {
+        RestoreCheckpoint::default()
+    };
+
+    // Offsets are translated partition by partition
+    if opts.commits_groups() && opts.partitioning != Partitioning::Preserve {
+        return Err(AppError::InvalidArgument(
+            "consumer group offsets can be committed only with --partitioning preserve".to_string(),
+        ));
+    }
+    let group_offsets = if opts.commits_groups() {
+        restored_group_offsets(&archives, &opts)?
+    } else {
+        vec![]
+    };
+
+    let (stats, offsets) = match &manifest {
+        Some(manifest) => restore_volumes(
+            &config,
+            &file,
+            manifest,
+            &opts,
+            (&checkpoint_file, checkpoint),
+            log_enabled,
+            metrics,
+        )?,
+        None => restore_archives(
+            &config,
+            &archives,
+            &opts,
+            None,
+            ((!stdin).then_some(checkpoint_file.as_str()), checkpoint),
+            log_enabled,
+            metrics,
+        )?,
+    };
+    eprintln!("Restored records {}", stats);
+    if !group_offsets.is_empty() {
+        groups::commit_offsets(&config, &group_offsets, &offsets)?;
+    }
+    checkpoint::remove(&checkpoint_file)
+}
@@ -0,0 +1,395 @@
+use std::{collections::HashMap, time::Duration};
+
+use log::info;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::json;
+
+use crate::{
+    errors::AppError,
+    protos::kafka_archive::{SchemaReference, SchemaSnapshot},
+};
+
+const CONTENT_TYPE: &str = "application/vnd.schemaregistry.v1+json";
+
+#[derive(Serialize, Deserialize)]
+struct Reference {
+    name: String,
+    subject: String,
+    version: i32,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SubjectVersion {
+    subject: String,
+    version: i32,
+    id: i32,
+    schema_type: Option<String>,
+    schema: String,
+    #[serde(default)]
+    references: Vec<Reference>,
+}
+
+#[derive(Deserialize)]
+struct Registered {
+    id: i32,
+}
+
+impl From<SubjectVersion> for SchemaSnapshot {
+    fn from(v: SubjectVersion) -> Self {
+        SchemaSnapshot {
+            subject: Some(v.subject),
+            version: Some(v.version),
+            id: Some(v.id),
+            schema_type: v.schema_type,
+            schema: Some(v.schema),
+            references: v
+                .references
+                .into_iter()
+                .map(|r| SchemaReference {
+                    name: Some(r.name),
+                    subject: Some(r.subject),
+                    version: Some(r.version),
+                })
+                .collect(),
+        }
+    }
+}
+
+// Subjects of other naming strategies may have any characters
+fn encode_segment(s: &str) -> String {
+    s.bytes()
+        .map(|b| match b {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (b as char).to_string()
+            }
+            b => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+fn registry_error(e: impl std::fmt::Display) -> AppError {
+    AppError::SchemaRegistry(e.to_string())
+}
+
+// Subjects of the topic name strategy follow the renamed topic, other subjects are kept
+fn target_subject(subject: &str, source: &str, target: &str) -> String {
+    match subject.strip_prefix(source) {
+        Some(suffix @ ("-key" | "-value")) => format!("{}{}", target, suffix),
+        _ => subject.to_string(),
+    }
+}
+
+/// Replaces the schema id of a record in the Confluent wire format
+pub fn remap_schema_id(data: &mut [u8], ids: &HashMap<i32, i32>) {
+    if data.len() < 5 || data[0] != 0 {
+        return;
+    }
+    let id = i32::from_be_bytes(data[1..5].try_into().unwrap());
+    if let Some(new_id) = ids.get(&id) {
+        data[1..5].copy_from_slice(&new_id.to_be_bytes());
+    }
+}
+
+/// Client of the Confluent compatible schema registry
+pub struct SchemaRegistry {
+    url: String,
+    agent: ureq::Agent,
+}
+
+impl SchemaRegistry {
+    pub fn new(url: &str) -> Self {
+        SchemaRegistry {
+            url: url.trim_end_matches('/').to_string(),
+            agent: ureq::AgentBuilder::new()
+                .timeout(Duration::from_secs(30))
+                .build(),
+        }
+    }
+
+    fn subject_version(
+        &self,
+        subject: &str,
+        version: &str,
+    ) -> Result<Option<SchemaSnapshot>, AppError> {
+        let url = format!(
+            "{}/subjects/{}/versions/{}",
+            self.url,
+            encode_segment(subject),
+            version
+        );
+        match self.agent.get(&url).call() {
+            Ok(response) => {
+                let version: SubjectVersion = response.into_json().map_err(registry_error)?;
+                Ok(Some(version.into()))
+            }
+            Err(ureq::Error::Status(404, _)) => Ok(None),
+            Err(e) => Err(registry_error(e)),
+        }
+    }
+
+    // Adds the referenced schemas before the schema
+    fn add_with_references(
+        &self,
+        snapshot: SchemaSnapshot,
+        schemas: &mut Vec<SchemaSnapshot>,
+    ) -> Result<(), AppError> {
+        let known = |schemas: &[SchemaSnapshot], subject: &str, version: i32| {
+            schemas
+                .iter()
+                .any(|s| s.subject() == subject && s.version() == version)
+        };
+        for reference in snapshot.references.iter() {
+            if known(schemas, reference.subject(), reference.version()) {
+                continue;
+            }
+            let referenced = self
+                .subject_version(reference.subject(), &reference.version().to_string())?
+                .ok_or_else(|| {
+                    registry_error(format!(
+                        "referenced subject {} version {} not found",
+                        reference.subject(),
+                        reference.version()
+                    ))
+                })?;
+            self.add_with_references(referenced, schemas)?;
+        }
+        if !known(schemas, snapshot.subject(), snapshot.version()) {
+            schemas.push(snapshot);
+        }
+        Ok(())
+    }
+
+    /// Latest key and value schemas of the topic and the schemas they reference
+    pub fn snapshot(&self, topic: &str) -> Result<Vec<SchemaSnapshot>, AppError> {
+        let mut schemas = vec![];
+        for subject in [format!("{}-key", topic), format!("{}-value", topic)] {
+            if let Some(snapshot) = self.subject_version(&subject, "latest")? {
+                info!(
+                    "Schema of subject:{} version:{}",
+                    subject,
+                    snapshot.version()
+                );
+                self.add_with_references(snapshot, &mut schemas)?;
+            }
+        }
+        Ok(schemas)
+    }
+
+    fn post<T: DeserializeOwned>(
+        &self,
+        path: &str,
+        body: &serde_json::Value,
+    ) -> Result<T, AppError> {
+        let response = self
+            .agent
+            .post(&format!("{}{}", self.url, path))
+            .set("Content-Type", CONTENT_TYPE)
+            .send_json(body)
+            .map_err(registry_error)?;
+        response.into_json().map_err(registry_error)
+    }
+
+    // Registers the schema, or finds it when it's registered already.
+    // Returns the id and the version of the subject.
+    fn register_schema(
+        &self,
+        subject: &str,
+        body: &serde_json::Value,
+    ) -> Result<(i32, i32), AppError> {
+        let subject = encode_segment(subject);
+        let registered: Registered = self.post(&format!("/subjects/{}/versions", subject), body)?;
+        let version: SubjectVersion = self.post(&format!("/subjects/{}", subject), body)?;
+        Ok((registered.id, version.version))
+    }
+
+    /// Registers the schemas of the `source` topic for the `target` topic.
+    /// Returns the new id of every schema id of the archive.
+    pub fn register(
+        &self,
+        schemas: &[SchemaSnapshot],
+        source: &str,
+        target: &str,
+    ) -> Result<HashMap<i32, i32>, AppError> {
+        let mut ids = HashMap::new();
+        // Referenced subject versions get new numbers in the target registry
+        let mut versions: HashMap<(String, i32), i32> = HashMap::new();
+        for snapshot in schemas {
+            let subject = target_subject(snapshot.subject(), source, target);
+            let references: Vec<Reference> = snapshot
+                .references
+                .iter()
+                .map(|r| Reference {
+                    name: r.name().to_string(),
+                    subject: r.subject().to_string(),
+                    version: versions
+                        .get(&(r.subject().to_string(), r.version()))
+                        .copied()
+                        .unwrap_or(r.version()),
+                })
+                .collect();
+            let mut body = json!({"schema": snapshot.schema(), "references": references});
+            if let Some(schema_type) = &snapshot.schema_type {
+                body["schemaType"] = json!(schema_type);
+            }
+
+            let (id, version) = self.register_schema(&subject, &body)?;
+            info!(
+                "Registered schema subject:{} version:{} id:{}",
+                subject, version, id
+            );
+            ids.insert(snapshot.id(), id);
+            versions.insert(
+                (snapshot.subject().to_string(), snapshot.version()),
+                version,
+            );
+        }
+        Ok(ids)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        io::{BufRead, BufReader, Read, Write},
+        net::TcpListener,
+        sync::{Arc, Mutex},
+        thread,
+    };
+
+    type Requests = Arc<Mutex<Vec<(String, String, String)>>>;
+
+    // Serves the responses of `route` for method, path and body, keeping the requests
+    fn mock_registry(route: fn(&str, &str) -> (u16, String)) -> (String, Requests) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let requests: Requests = Arc::default();
+        let log = requests.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let log = log.clone();
+                thread::spawn(move || {
+                    let mut reader = BufReader::new(stream.try_clone().unwrap());
+                    loop {
+                        let mut line = String::new();
+                        if reader.read_line(&mut line).unwrap_or(0) == 0 {
+                            return;
+                        }
+                        let mut parts = line.split_whitespace();
+                        let method = parts.next().unwrap().to_string();
+                        let path = parts.next().unwrap().to_string();
+                        let mut length = 0;
+                        loop {
+                            let mut header = String::new();
+                            reader.read_line(&mut header).unwrap();
+                            if header.trim().is_empty() {
+                                break;
+                            }
+                            if let Some((name, value)) = header.split_once(':') {
+                                if name.eq_ignore_ascii_case("content-length") {
+                                    length = value.trim().parse().unwrap();
+                                }
+                            }
+                        }
+                        let mut body = vec![0; length];
+                        reader.read_exact(&mut body).unwrap();
+                        let body = String::from_utf8(body).unwrap();
+
+                        let (status, response) = route(&method, &path);
+                        log.lock().unwrap().push((method, path, body));
+                        write!(
+                            stream,
+                            "HTTP/1.1 {} X\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+                            status,
+                            response.len(),
+                            response
+                        )
+                        .unwrap();
+                    }
+                });
+            }
+        });
+        (url, requests)
+    }
+
+    fn source_registry(method: &str, path: &str) -> (u16, String) {
+        let common = r#"{"subject":"common","version":1,"id":5,"schema":"{\"type\":\"string\"}"}"#;
+        let value = r#"{"subject":"orders-value","version":3,"id":10,"schemaType":"JSON",
+            "schema":"{}","references":[{"name":"common.json","subject":"common","version":1}]}"#;
+        match (method, path) {
+            ("GET", "/subjects/orders-value/versions/latest") => (200, value.to_string()),
+            ("GET", "/subjects/common/versions/1") => (200, common.to_string()),
+            _ => (
+                404,
+                r#"{"error_code":40401,"message":"Subject not found"}"#.to_string(),
+            ),
+        }
+    }
+
+    fn target_registry(method: &str, path: &str) -> (u16, String) {
+        match (method, path) {
+            ("POST", "/subjects/common/versions") => (200, r#"{"id":7}"#.to_string()),
+            ("POST", "/subjects/common") => (
+                200,
+                r#"{"subject":"common","version":4,"id":7,"schema":"{}"}"#.to_string(),
+            ),
+            ("POST", "/subjects/orders2-value/versions") => (200, r#"{"id":11}"#.to_string()),
+            ("POST", "/subjects/orders2-value") => (
+                200,
+                r#"{"subject":"orders2-value","version":1,"id":11,"schema":"{}"}"#.to_string(),
+            ),
+            _ => (500, "{}".to_string()),
+        }
+    }
+
+    #[test]
+    fn schemas_are_copied_between_registries() {
+        let (url, _) = mock_registry(source_registry);
+        let schemas = SchemaRegistry::new(&url).snapshot("orders").unwrap();
+        let subjects: Vec<(&str, i32)> = schemas.iter().map(|s| (s.subject(), s.id())).collect();
+        assert_eq!(subjects, vec![("common", 5), ("orders-value", 10)]);
+        assert_eq!(schemas[1].schema_type(), "JSON");
+
+        let (url, requests) = mock_registry(target_registry);
+        let ids = SchemaRegistry::new(&url)
+            .register(&schemas, "orders", "orders2")
+            .unwrap();
+        assert_eq!(ids, HashMap::from([(5, 7), (10, 11)]));
+
+        let requests = requests.lock().unwrap();
+        let (_, path, body) = &requests[2];
+        assert_eq!(path, "/subjects/orders2-value/versions");
+        let body: serde_json::Value = serde_json::from_str(body).unwrap();
+        assert_eq!(body["schemaType"], "JSON");
+        assert_eq!(body["references"][0]["version"], 4);
+    }
+
+    #[test]
+    fn record_schema_ids_are_replaced() {
+        let ids = HashMap::from([(10, 11)]);
+        let mut data = vec![0, 0, 0, 0, 10, 42];
+        remap_schema_id(&mut data, &ids);
+        assert_eq!(data, vec![0, 0, 0, 0, 11, 42]);
+
+        let mut plain = b"{\"a\":1}".to_vec();
+        remap_schema_id(&mut plain, &ids);
+        assert_eq!(plain, b"{\"a\":1}");
+    }
+
+    #[test]
+    fn subjects_follow_the_topic() {
+        assert_eq!(
+            target_subject("orders-value", "orders", "copy"),
+            "copy-value"
+        );
+        assert_eq!(target_subject("orders-key", "orders", "copy"), "copy-key");
+        assert_eq!(target_subject("common", "orders", "copy"), "common");
+        assert_eq!(
+            target_subject("orders-extra", "orders", "copy"),
+            "orders-extra"
+        );
+        assert_eq!(encode_segment("a/b c.proto"), "a%2Fb%20c.proto");
+    }
+}
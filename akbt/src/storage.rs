@@ -0,0 +1,102 @@
+use std::{
+    fs::File,
+    io::{self, Read, Stdout, Write},
+    path::Path,
+};
+
+use crate::{
+    errors::AppError,
+    s3::{S3Storage, S3_SCHEME},
+    stream::is_stdio,
+};
+
+/// Archive being written, it's complete only after `finish`
+pub trait ArchiveWrite: Write + Send {
+    fn finish(self: Box<Self>) -> io::Result<()>;
+}
+
+impl ArchiveWrite for File {
+    fn finish(self: Box<Self>) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl ArchiveWrite for Stdout {
+    fn finish(mut self: Box<Self>) -> io::Result<()> {
+        self.flush()
+    }
+}
+
+/// Place where archives are written and read
+pub trait Storage: Send {
+    fn exists(&self, path: &str) -> Result<bool, AppError>;
+    /// Reader of the archive with its size when it's known
+    fn open(&self, path: &str) -> Result<(Box<dyn Read + Send>, Option<u64>), AppError>;
+    fn create(&self, path: &str) -> Result<Box<dyn ArchiveWrite>, AppError>;
+    /// Only local archives are resumed and patched in place
+    fn is_local(&self) -> bool;
+}
+
+pub struct LocalStorage;
+
+impl Storage for LocalStorage {
+    fn exists(&self, path: &str) -> Result<bool, AppError> {
+        Ok(Path::new(path).exists())
+    }
+
+    fn open(&self, path: &str) -> Result<(Box<dyn Read + Send>, Option<u64>), AppError> {
+        if !Path::new(path).exists() {
+            return Err(AppError::FileNotExists(path.to_string()));
+        }
+        let file = File::open(path).map_err(|e| AppError::IoError(e.to_string()))?;
+        let metadata = file
+            .metadata()
+            .map_err(|e| AppError::IoError(e.to_string()))?;
+        Ok((Box::new(file), Some(metadata.len())))
+    }
+
+    fn create(&self, path: &str) -> Result<Box<dyn ArchiveWrite>, AppError> {
+        let file = File::create(path).map_err(|e| AppError::IoError(e.to_string()))?;
+        Ok(Box::new(file))
+    }
+
+    fn is_local(&self) -> bool {
+        true
+    }
+}
+
+/// Backups are written to stdout and restored from stdin of unknown size
+pub struct StdioStorage;
+
+impl Storage for StdioStorage {
+    fn exists(&self, _path: &str) -> Result<bool, AppError> {
+        Ok(false)
+    }
+
+    fn open(&self, _path: &str) -> Result<(Box<dyn Read + Send>, Option<u64>), AppError> {
+        Ok((Box::new(io::stdin()), None))
+    }
+
+    fn create(&self, _path: &str) -> Result<Box<dyn ArchiveWrite>, AppError> {
+        Ok(Box::new(io::stdout()))
+    }
+
+    fn is_local(&self) -> bool {
+        false
+    }
+}
+
+pub fn is_remote(path: &str) -> bool {
+    path.starts_with(S3_SCHEME)
+}
+
+/// Storage of the archive path: `-`, s3://bucket/key or a local file
+pub fn storage(path: &str) -> Result<Box<dyn Storage>, AppError> {
+    if is_stdio(path) {
+        Ok(Box::new(StdioStorage))
+    } else if is_remote(path) {
+        Ok(Box::new(S3Storage::from_env()?))
+    } else {
+        Ok(Box::new(LocalStorage))
+    }
+}
@@ -0,0 +1,1175 @@
+use log::{error, info, warn};
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom};
+use std::path::Path;
+use std::sync::atomic::AtomicUsize;
+use std::sync::mpsc::{sync_channel, Receiver, RecvTimeoutError, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::archive::{
+    patch_record_count, read_frame, read_len, read_versioned_header, write_block, write_header,
+    write_trailer, Block, Frame, Trailer, CHECKSUM_FORMAT_VERSION, STREAM_FORMAT_VERSION,
+};
+use crate::checkpoint::{self, checkpoint_path, BackupCheckpoint};
+use crate::codec::Codec;
+use crate::counters::ByteCounter;
+use crate::crypto::{archive_cipher, BlockCipher, BlockPosition, Keys};
+use crate::errors::AppError;
+use crate::groups::PositionCommitter;
+use crate::mbprocess::MProgressBars;
+use crate::metrics::Metrics;
+use crate::protos::kafka_archive::ArchiveHeader;
+use crate::protos::kafka_messages::{kafka_message_unpack, KafkaMessage};
+use crate::storage::{self, ArchiveWrite, Storage};
+use crate::volumes::{self, Checksummed, Manifest, PartitionRange, Ranges, Volume, VolumeLimits};
+use crate::workers::ordered_map;
+
+#[derive(Debug, Default)]
+pub struct StreamMsg {
+    pub data: Vec<u8>,
+    pub records: u64,
+    /// Next offset per topic and partition after the records
+    pub offsets: Vec<(String, i32, i64)>,
+}
+
+const CHECKPOINT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// File name of stdout for backups and stdin for restores
+pub const STDIO: &str = "-";
+
+pub fn is_stdio(file: &str) -> bool {
+    file == STDIO
+}
+
+// Appends ".<codec extension>" to files without an extension
+pub fn archive_path(file: String, codec: Codec) -> String {
+    if is_stdio(&file) {
+        return file;
+    }
+    match Path::new(&file).extension() {
+        Some(_) => file,
+        None => format!("{}.{}", file, codec.extension()),
+    }
+}
+
+/// How the blocks of a new archive are compressed and encrypted
+#[derive(Debug, Clone, Default)]
+pub struct BlockEncoding {
+    pub codec: Codec,
+    pub level: u32,
+    /// Compression threads
+    pub workers: usize,
+    /// Blocks are encrypted after compression with the key
+    pub keys: Option<Keys>,
+}
+
+#[derive(Debug)]
+pub struct StreamWriter {}
+
+impl StreamWriter {
+    fn compress(codec: Codec, level: u32, msg: StreamMsg) -> (Block, Vec<(String, i32, i64)>) {
+        let mut data = Vec::with_capacity(msg.data.len() / 2);
+        let mut encoder = codec
+            .encoder(&mut data, level)
+            .expect("Can't create encoder");
+        encoder.write_all(&msg.data).unwrap();
+        encoder.finish().unwrap();
+        let records = msg.records as u32;
+        (Block { records, data }, msg.offsets)
+    }
+
+    // Creates the archive, or truncates the interrupted local one to the checkpoint
+    fn open(
+        storage: &dyn Storage,
+        pathfile: &str,
+        header: &ArchiveHeader,
+        checkpoint: Option<BackupCheckpoint>,
+    ) -> Result<(Box<dyn ArchiveWrite>, BackupCheckpoint), AppError> {
+        let io_error = |e: std::io::Error| AppError::IoError(e.to_string());
+        match checkpoint {
+            Some(checkpoint) => {
+                let mut file = OpenOptions::new()
+                    .write(true)
+                    .open(pathfile)
+                    .map_err(io_error)?;
+                file.set_len(checkpoint.length).map_err(io_error)?;
+                file.seek(SeekFrom::End(0)).map_err(io_error)?;
+                Ok((Box::new(file), checkpoint))
+            }
+            None => {
+                let mut file = storage.create(pathfile)?;
+                let mut buf = vec![];
+                write_header(&mut buf, header).map_err(io_error)?;
+                file.write_all(&buf).map_err(io_error)?;
+                let checkpoint = BackupCheckpoint {
+                    length: buf.len() as u64,
+                    ..Default::default()
+                };
+                Ok((file, checkpoint))
+            }
+        }
+    }
+
+    // Every message is compressed into its own block by one of `workers`.
+    // Until the archive is finished its progress is kept in the checkpoint,
+    // with the checkpoint of an interrupted archive new blocks are appended to it.
+    // An archive on stdout or object storage has neither the checkpoint nor the record count
+    // in the header.
+    pub fn run(
+        file: String,
+        encoding: BlockEncoding,
+        mut header: ArchiveHeader,
+        checkpoint: Option<BackupCheckpoint>,
+        metrics: Option<Arc<Metrics>>,
+    ) -> Result<(SyncSender<StreamMsg>, WriterHandle), AppError> {
+        let BlockEncoding {
+            codec,
+            level,
+            workers,
+            keys,
+        } = encoding;
+        let codec = match checkpoint {
+            Some(_) => Codec::from(header.compression()),
+            None => codec,
+        };
+        codec.check_level(level)?;
+        header.set_compression(codec.into());
+        // An interrupted archive keeps its encryption
+        let cipher = match (&checkpoint, keys) {
+            (Some(_), Some(_)) if header.encryption.is_none() => {
+                return Err(AppError::InvalidArgument(
+                    "the interrupted archive isn't encrypted, resume it without the key"
+                        .to_string(),
+                ))
+            }
+            (Some(_), keys) => archive_cipher(Some(&header), keys.as_ref())?,
+            (None, Some(keys)) => Some(keys.encrypt_header(&mut header)?),
+            (None, None) => None,
+        };
+
+        let pathfile = archive_path(file, codec);
+        let storage = storage::storage(&pathfile)?;
+        let local = storage.is_local();
+        if !local && checkpoint.is_some() {
+            return Err(AppError::InvalidArgument(
+                "only an archive of a local file can be resumed".to_string(),
+            ));
+        }
+        if checkpoint.is_none() && storage.exists(&pathfile)? {
+            return Err(AppError::FileExists(pathfile));
+        }
+        let checkpoint_file = checkpoint_path(&pathfile, "checkpoint");
+        let (mut file, mut checkpoint) =
+            StreamWriter::open(storage.as_ref(), &pathfile, &header, checkpoint)?;
+        if local {
+            checkpoint::save(&checkpoint_file, &checkpoint)?;
+        }
+
+        let (sender, receiver): (SyncSender<StreamMsg>, Receiver<StreamMsg>) = sync_channel(1);
+        let (blocks, compress_handle) = ordered_map(receiver, workers, move |msg| {
+            StreamWriter::compress(codec, level, msg)
+        });
+
+        let handle: WriterHandle = thread::spawn(move || {
+            let io_error = |e: std::io::Error| AppError::IoError(e.to_string());
+            let bytes_written = AtomicUsize::new(0);
+            let mut writer = BufWriter::new(ByteCounter::new(&mut file, &bytes_written));
+            let length = checkpoint.length;
+
+            let mut trailer = Trailer {
+                blocks: checkpoint.blocks,
+                records: checkpoint.records,
+            };
+            let mut saved_at = Instant::now();
+            for (block, offsets) in blocks {
+                if let Some(block) = encrypt_block(cipher.as_ref(), &trailer, block) {
+                    write_block(&mut writer, &block).map_err(io_error)?;
+                    if let Some(metrics) = &metrics {
+                        metrics.written(block.records as u64, block.data.len() as u64);
+                    }
+                    trailer.blocks += 1;
+                    trailer.records += block.records as u64;
+                }
+                for (topic, partition, offset) in offsets {
+                    checkpoint
+                        .offsets
+                        .entry(topic)
+                        .or_default()
+                        .insert(partition, offset);
+                }
+
+                if local && saved_at.elapsed() >= CHECKPOINT_INTERVAL {
+                    writer.flush().map_err(io_error)?;
+                    checkpoint.length =
+                        length + bytes_written.load(std::sync::atomic::Ordering::Relaxed) as u64;
+                    (checkpoint.blocks, checkpoint.records) = (trailer.blocks, trailer.records);
+                    checkpoint::save(&checkpoint_file, &checkpoint)?;
+                    saved_at = Instant::now();
+                }
+            }
+            write_end(&mut writer, cipher.as_ref(), &mut trailer).map_err(io_error)?;
+            writer.flush().map_err(io_error)?;
+            drop(writer);
+            file.finish().map_err(io_error)?;
+            join_compressors(compress_handle)?;
+
+            if local {
+                patch_record_count(&pathfile, &mut header, trailer.records)?;
+                checkpoint::remove(&checkpoint_file)?;
+            }
+            info!("Records written: {}", trailer.records);
+            Ok(())
+        });
+
+        Ok((sender, handle))
+    }
+
+    // Like `run` without the checkpoint, the archive rolls into numbered volumes at the limits.
+    // Every volume has its own header and trailer, the manifest is rewritten after every volume.
+    // Record counts of the volumes are kept in their trailers and in the manifest.
+    // With the committer of a following backup the existing manifest is continued
+    // and the position is committed after every volume. A volume missing from the manifest
+    // is left by an interrupted backup and is written again.
+    pub fn run_volumes(
+        file: String,
+        encoding: BlockEncoding,
+        mut header: ArchiveHeader,
+        limits: VolumeLimits,
+        committer: Option<PositionCommitter>,
+        metrics: Option<Arc<Metrics>>,
+    ) -> Result<(SyncSender<StreamMsg>, WriterHandle), AppError> {
+        let BlockEncoding {
+            codec,
+            level,
+            workers,
+            keys,
+        } = encoding;
+        codec.check_level(level)?;
+        header.set_compression(codec.into());
+        // Volumes share the key and the salt, every volume binds the cipher to its header
+        let cipher = keys
+            .map(|keys| keys.encrypt_header(&mut header))
+            .transpose()?;
+
+        let pathfile = archive_path(file, codec);
+        let storage = storage::storage(&pathfile)?;
+        let manifest_file = volumes::manifest_path(&pathfile);
+        let mut manifest = if committer.is_some() && storage.exists(&manifest_file)? {
+            volumes::load(&manifest_file)?
+        } else if storage.exists(&manifest_file)? {
+            return Err(AppError::FileExists(manifest_file));
+        } else {
+            Manifest::new()
+        };
+        let next = volumes::volume_path(&pathfile, manifest.volumes.len() as u32 + 1);
+        if storage.exists(&next)? {
+            if committer.is_none() {
+                return Err(AppError::FileExists(next));
+            }
+            warn!("Volume:{} isn't in the manifest, it's written again", next);
+        }
+        let continued = manifest.volumes.len();
+        let mut ranges = Ranges::new(header.topics.iter().flat_map(|t| {
+            t.partitions.iter().map(|p| {
+                (
+                    (t.name().to_string(), p.partition()),
+                    p.offset_begin.unwrap_or(p.low_watermark()),
+                )
+            })
+        }));
+
+        let (sender, receiver): (SyncSender<StreamMsg>, Receiver<StreamMsg>) = sync_channel(1);
+        let (blocks, compress_handle) = ordered_map(receiver, workers, move |msg| {
+            StreamWriter::compress(codec, level, msg)
+        });
+
+        let handle: WriterHandle = thread::spawn(move || {
+            let mut volume: Option<OpenVolume> = None;
+            let mut records = 0;
+            loop {
+                // An idle volume is sealed when its time is up
+                let timeout = volume
+                    .as_ref()
+                    .zip(limits.time)
+                    .map(|(v, time)| time.saturating_sub(v.opened.elapsed()));
+                let received = match timeout {
+                    Some(timeout) => match blocks.recv_timeout(timeout) {
+                        Ok(received) => Some(received),
+                        Err(RecvTimeoutError::Timeout) => None,
+                        Err(RecvTimeoutError::Disconnected) => break,
+                    },
+                    None => match blocks.recv() {
+                        Ok(received) => Some(received),
+                        Err(_) => break,
+                    },
+                };
+
+                if let Some((block, offsets)) = received {
+                    let open = match volume.as_mut() {
+                        Some(open) => open,
+                        None => {
+                            header.volume = Some(manifest.volumes.len() as u32 + 1);
+                            volume.insert(OpenVolume::create(
+                                storage.as_ref(),
+                                &pathfile,
+                                &header,
+                                cipher.as_ref(),
+                            )?)
+                        }
+                    };
+                    if let Some(block) = encrypt_block(open.cipher.as_ref(), &open.trailer, block) {
+                        write_block(&mut open.writer, &block)
+                            .map_err(|e| AppError::IoError(format!("`{}`: {}", open.path, e)))?;
+                        if let Some(metrics) = &metrics {
+                            metrics.written(block.records as u64, block.data.len() as u64);
+                        }
+                        open.trailer.blocks += 1;
+                        open.trailer.records += block.records as u64;
+                        records += block.records as u64;
+                    }
+                    for (topic, partition, offset) in offsets {
+                        ranges.advance(topic, partition, offset);
+                    }
+                }
+
+                if volume
+                    .as_ref()
+                    .is_some_and(|v| limits.reached(v.size(), v.trailer.records, v.opened))
+                {
+                    let sealed = volume.take().unwrap().seal(ranges.seal())?;
+                    manifest.volumes.push(sealed);
+                    volumes::save(&manifest_file, &manifest)?;
+                    commit(&committer, &manifest)?;
+                }
+            }
+
+            // A backup without records still has a volume
+            if volume.is_none() && manifest.volumes.is_empty() {
+                header.volume = Some(1);
+                volume = Some(OpenVolume::create(
+                    storage.as_ref(),
+                    &pathfile,
+                    &header,
+                    cipher.as_ref(),
+                )?);
+            }
+            if let Some(open) = volume {
+                manifest.volumes.push(open.seal(ranges.seal())?);
+                volumes::save(&manifest_file, &manifest)?;
+                commit(&committer, &manifest)?;
+            }
+            join_compressors(compress_handle)?;
+            info!(
+                "Records written: {} volumes: {}",
+                records,
+                manifest.volumes.len() - continued
+            );
+            Ok(())
+        });
+
+        Ok((sender, handle))
+    }
+}
+
+// Encrypts the block at its place in the archive. Records of an encrypted archive are never in
+// an empty block, the only one is the end block.
+fn encrypt_block(cipher: Option<&BlockCipher>, trailer: &Trailer, block: Block) -> Option<Block> {
+    let Some(cipher) = cipher else {
+        return Some(block);
+    };
+    if block.records == 0 {
+        return None;
+    }
+    let position = BlockPosition {
+        index: trailer.blocks,
+        records_before: trailer.records,
+        records: block.records,
+    };
+    Some(Block {
+        records: block.records,
+        data: cipher.encrypt(position, &block.data),
+    })
+}
+
+// An encrypted archive ends with an empty block authenticating the counts of the trailer,
+// so a truncated archive with a forged trailer is detected
+fn write_end(
+    mut writer: impl Write,
+    cipher: Option<&BlockCipher>,
+    trailer: &mut Trailer,
+) -> std::io::Result<()> {
+    if let Some(cipher) = cipher {
+        let position = BlockPosition {
+            index: trailer.blocks,
+            records_before: trailer.records,
+            records: 0,
+        };
+        let end = Block {
+            records: 0,
+            data: cipher.encrypt(position, &[]),
+        };
+        write_block(&mut writer, &end)?;
+        trailer.blocks += 1;
+    }
+    write_trailer(&mut writer, trailer)
+}
+
+/// Writer thread of an archive, fails when the archive can't be written
+pub type WriterHandle = JoinHandle<Result<(), AppError>>;
+
+fn join_compressors(handle: JoinHandle<()>) -> Result<(), AppError> {
+    handle
+        .join()
+        .map_err(|_| AppError::Panicked("compressor".to_string()))
+}
+
+// A failed commit stops the backup, a restarted one backs up the volume again
+fn commit(committer: &Option<PositionCommitter>, manifest: &Manifest) -> Result<(), AppError> {
+    let (Some(committer), Some(volume)) = (committer, manifest.volumes.last()) else {
+        return Ok(());
+    };
+    committer.commit(&volume.ranges).map_err(|e| {
+        error!(
+            "Can't commit the position of volume:{} error:{}",
+            volume.file, e
+        );
+        e
+    })
+}
+
+// Volume being written by `run_volumes`
+struct OpenVolume {
+    path: String,
+    writer: BufWriter<Checksummed<Box<dyn ArchiveWrite>>>,
+    trailer: Trailer,
+    opened: Instant,
+    /// Cipher bound to the header of the volume
+    cipher: Option<BlockCipher>,
+}
+
+impl OpenVolume {
+    fn create(
+        storage: &dyn Storage,
+        pathfile: &str,
+        header: &ArchiveHeader,
+        cipher: Option<&BlockCipher>,
+    ) -> Result<Self, AppError> {
+        let path = volumes::volume_path(pathfile, header.volume());
+        let file = storage.create(&path)?;
+        let mut writer = BufWriter::new(Checksummed::new(file));
+        write_header(&mut writer, header)
+            .map_err(|e| AppError::IoError(format!("`{}`: {}", path, e)))?;
+        Ok(OpenVolume {
+            path,
+            writer,
+            trailer: Trailer::default(),
+            opened: Instant::now(),
+            cipher: cipher.map(|cipher| cipher.bind(header)),
+        })
+    }
+
+    fn size(&self) -> u64 {
+        self.writer.get_ref().size() + self.writer.buffer().len() as u64
+    }
+
+    fn seal(mut self, ranges: Vec<PartitionRange>) -> Result<Volume, AppError> {
+        let io_error = |e: std::io::Error| AppError::IoError(format!("`{}`: {}", self.path, e));
+        write_end(&mut self.writer, self.cipher.as_ref(), &mut self.trailer).map_err(io_error)?;
+        let checksummed = self
+            .writer
+            .into_inner()
+            .map_err(|e| io_error(e.into_error()))?;
+        let (file, size, sha256) = checksummed.into_parts();
+        file.finish().map_err(io_error)?;
+        info!(
+            "Volume:{} records:{} size:{}",
+            self.path, self.trailer.records, size
+        );
+        Ok(Volume {
+            file: Path::new(&self.path)
+                .file_name()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .to_string(),
+            records: self.trailer.records,
+            size,
+            sha256,
+            ranges,
+        })
+    }
+}
+
+/// Records of a block or an error with its location in the archive
+pub type MessageBatch = Result<Vec<KafkaMessage>, AppError>;
+type KafkaMessageReceiver = Receiver<MessageBatch>;
+type StreamReaderHandle = (
+    KafkaMessageReceiver,
+    u64,
+    Option<ArchiveHeader>,
+    ReaderHandle,
+);
+
+/// Reader thread of an archive, fails when a decompress worker panics
+pub type ReaderHandle = JoinHandle<Result<(), AppError>>;
+
+/// Waits for the reader thread, its panic is an error too
+pub fn join_reader(handle: ReaderHandle) -> Result<(), AppError> {
+    handle
+        .join()
+        .map_err(|_| AppError::Panicked("decoder".to_string()))?
+}
+
+fn corrupted(location: &str, reason: impl std::fmt::Display) -> AppError {
+    AppError::BadArchive(format!("{}: {}", location, reason))
+}
+
+// Adds the location to errors of the archive layout
+fn located(location: &str, e: AppError) -> AppError {
+    match e {
+        AppError::BadArchive(reason) => corrupted(location, reason),
+        AppError::Encryption(reason) => AppError::Encryption(format!("{}: {}", location, reason)),
+        e => corrupted(location, e),
+    }
+}
+
+#[derive(Debug)]
+pub struct StreamReader {}
+
+impl StreamReader {
+    // Returns Eof only at the boundary of records
+    fn read_msg(mut reader: impl Read) -> Result<KafkaMessage, AppError> {
+        let mut buf_size: [u8; 8] = [0; 8];
+        match reader.read(&mut buf_size) {
+            Ok(0) => return Err(AppError::Eof),
+            Ok(n) => reader.read_exact(&mut buf_size[n..]),
+            Err(e) => Err(e),
+        }
+        .map_err(|e| AppError::BadArchive(format!("truncated record: {}", e)))?;
+
+        let msg_size = usize::from_be_bytes(buf_size);
+        let msg_body = read_len(&mut reader, msg_size, "record")?;
+        kafka_message_unpack(&msg_body).map_err(AppError::BadArchive)
+    }
+
+    fn decompress(
+        codec: Codec,
+        cipher: Option<&BlockCipher>,
+        location: &str,
+        position: BlockPosition,
+        block: Block,
+    ) -> MessageBatch {
+        let compressed = match cipher {
+            Some(cipher) => cipher
+                .decrypt(position, &block.data)
+                .map_err(|e| located(location, e))?,
+            None => block.data,
+        };
+        // The end block of an encrypted archive is empty
+        if cipher.is_some() && block.records == 0 {
+            return match compressed.is_empty() {
+                true => Ok(vec![]),
+                false => Err(corrupted(location, "end block has data")),
+            };
+        }
+        let mut data = Vec::with_capacity(compressed.len() * 2);
+        codec
+            .decoder(compressed.as_slice())
+            .and_then(|mut decoder| decoder.read_to_end(&mut data))
+            .map_err(|e| corrupted(location, e))?;
+
+        let mut batch = Vec::with_capacity(block.records as usize);
+        let mut reader = data.as_slice();
+        loop {
+            match StreamReader::read_msg(&mut reader) {
+                Ok(kmsg) => batch.push(kmsg),
+                Err(AppError::Eof) => break,
+                Err(e) => return Err(located(location, e)),
+            }
+        }
+        if batch.len() != block.records as usize {
+            return Err(corrupted(
+                location,
+                format!("{} records instead of {}", batch.len(), block.records),
+            ));
+        }
+        Ok(batch)
+    }
+
+    // Blocks are decompressed by `workers`, archives of the first version are read as a single stream.
+    // Corruption is sent as the last batch.
+    pub fn run(
+        pathfile: String,
+        workers: usize,
+        keys: Option<&Keys>,
+        mb: Arc<Mutex<MProgressBars>>,
+    ) -> Result<StreamReaderHandle, AppError> {
+        let (source, file_size) = storage::storage(&pathfile)?.open(&pathfile)?;
+        if let Some(size) = file_size {
+            info!("File size: {}", size);
+        }
+        let mut reader = BufReader::new(source);
+        let header_bytes = AtomicUsize::new(0);
+        let versioned_header = read_versioned_header(ByteCounter::new(&mut reader, &header_bytes))?;
+        let header_len = header_bytes.into_inner() as u64;
+        // Legacy archives are always gzipped
+        let (version, codec) = match &versioned_header {
+            Some((version, header)) => (*version, Codec::from(header.compression())),
+            None => {
+                info!("Archive has no header, reading as legacy format");
+                (STREAM_FORMAT_VERSION, Codec::Gzip)
+            }
+        };
+        let header = versioned_header.map(|(_, header)| header);
+        let cipher = archive_cipher(header.as_ref(), keys)?;
+        let encrypted = cipher.is_some();
+        // Archives without the trailer can be checked only by the count in the header
+        let expected_records = header
+            .as_ref()
+            .map(|h| h.record_count())
+            .filter(|count| *count > 0);
+        info!(
+            "Archive version: {} codec: {:?} encrypted: {}",
+            version,
+            codec,
+            cipher.is_some()
+        );
+
+        {
+            let mut mb = mb.lock().unwrap();
+            mb.add_pb(0, 0, 0, file_size.unwrap_or_default() as i64);
+            if file_size.is_none() {
+                mb.unknown_size();
+            }
+        }
+        let file_size = file_size.unwrap_or_default();
+
+        if version > STREAM_FORMAT_VERSION {
+            let (block_sender, block_receiver) = sync_channel(workers);
+            let (receiver, decompress_handle) = ordered_map(
+                block_receiver,
+                workers,
+                move |block: Result<(String, BlockPosition, Block), AppError>| {
+                    block.and_then(|(location, position, block)| {
+                        StreamReader::decompress(codec, cipher.as_ref(), &location, position, block)
+                    })
+                },
+            );
+
+            let handle: ReaderHandle = thread::spawn(move || {
+                let bytes_read = AtomicUsize::new(0);
+                let mut reader = ByteCounter::new(reader, &bytes_read);
+                let mut trailer = None;
+                let (mut blocks, mut records) = (0, 0);
+                let mut ended = false;
+                let result = loop {
+                    let offset =
+                        header_len + bytes_read.load(std::sync::atomic::Ordering::Relaxed) as u64;
+                    let location = format!("block {} at byte {}", blocks, offset);
+                    let frame = match read_frame(&mut reader, version) {
+                        Ok(frame) => frame,
+                        Err(e) => break Err(located(&location, e)),
+                    };
+                    let block = match (frame, &trailer) {
+                        (None, _) => break Ok(()),
+                        (Some(_), Some(_)) => {
+                            break Err(corrupted(&location, "data after the trailer"))
+                        }
+                        (Some(Frame::Trailer(t)), None) => {
+                            trailer = Some(t);
+                            continue;
+                        }
+                        (Some(Frame::Block(block)), None) => block,
+                    };
+                    if ended {
+                        break Err(corrupted(&location, "data after the end block"));
+                    }
+                    ended = encrypted && block.records == 0;
+                    let position = BlockPosition {
+                        index: blocks,
+                        records_before: records,
+                        records: block.records,
+                    };
+                    blocks += 1;
+                    records += block.records as u64;
+                    {
+                        let mut mb = mb.lock().unwrap();
+                        mb.update(
+                            0,
+                            0,
+                            bytes_read.load(std::sync::atomic::Ordering::Relaxed) as i64,
+                        );
+                        mb.read(block.records as u64, 0);
+                    }
+                    if block_sender.send(Ok((location, position, block))).is_err() {
+                        break Ok(());
+                    }
+                };
+
+                let end = format!(
+                    "end of archive at byte {}",
+                    header_len + bytes_read.into_inner() as u64
+                );
+                let result = result.and_then(|_| match trailer {
+                    Some(t) if (t.blocks, t.records) != (blocks, records) => Err(corrupted(
+                        &end,
+                        format!(
+                            "trailer expects {} blocks {} records, found {} blocks {} records",
+                            t.blocks, t.records, blocks, records
+                        ),
+                    )),
+                    None if version >= CHECKSUM_FORMAT_VERSION => {
+                        Err(corrupted(&end, "no trailer, the archive is truncated"))
+                    }
+                    Some(_) if encrypted && !ended => {
+                        Err(corrupted(&end, "no end block, the archive is truncated"))
+                    }
+                    None if expected_records.is_some_and(|count| count != records) => {
+                        Err(corrupted(
+                            &end,
+                            format!(
+                                "{} records instead of {}",
+                                records,
+                                expected_records.unwrap()
+                            ),
+                        ))
+                    }
+                    _ => Ok(()),
+                });
+                if let Err(e) = result {
+                    let _ = block_sender.send(Err(e));
+                }
+                drop(block_sender);
+                decompress_handle
+                    .join()
+                    .map_err(|_| AppError::Panicked("decompressor".to_string()))
+            });
+
+            return Ok((receiver, file_size, header, handle));
+        }
+
+        let (sender, receiver): (SyncSender<MessageBatch>, Receiver<MessageBatch>) =
+            sync_channel(1);
+
+        let handle: ReaderHandle = thread::spawn(move || {
+            let bytes_read = AtomicUsize::new(0);
+            let reader = BufReader::new(ByteCounter::new(reader, &bytes_read));
+
+            let mut records: u64 = 0;
+            let mut decoder = match codec.decoder(reader) {
+                Ok(decoder) => decoder,
+                Err(e) => {
+                    let _ = sender.send(Err(corrupted("record 0", e)));
+                    return Ok(());
+                }
+            };
+            loop {
+                let mut batch = Vec::with_capacity(1000);
+                let mut error = None;
+                for _ in 0..1000 {
+                    match StreamReader::read_msg(&mut decoder) {
+                        Ok(kmsg) => {
+                            batch.push(kmsg);
+                        }
+                        Err(AppError::Eof) => break,
+                        Err(e) => {
+                            error = Some(located(
+                                &format!("record {}", records + batch.len() as u64),
+                                e,
+                            ));
+                            break;
+                        }
+                    }
+                }
+                let last_batch = batch.len() < 1000;
+                records += batch.len() as u64;
+
+                {
+                    let mut mb = mb.lock().unwrap();
+                    mb.update(
+                        0,
+                        0,
+                        bytes_read.load(std::sync::atomic::Ordering::Relaxed) as i64,
+                    );
+                    mb.read(batch.len() as u64, 0);
+                }
+
+                if sender.send(Ok(batch)).is_err() {
+                    return Ok(());
+                }
+                if last_batch {
+                    if error.is_none() && expected_records.is_some_and(|count| count != records) {
+                        error = Some(corrupted(
+                            &format!("record {}", records),
+                            format!(
+                                "{} records instead of {}",
+                                records,
+                                expected_records.unwrap()
+                            ),
+                        ));
+                    }
+                    if let Some(e) = error {
+                        let _ = sender.send(Err(e));
+                    }
+                    return Ok(());
+                }
+            }
+        });
+
+        Ok((receiver, file_size, header, handle))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::archive::archive_header_new;
+    use crate::protos::kafka_messages::{
+        kafka_message_len, kafka_message_new, kafka_message_pack, TimestampType,
+    };
+
+    fn stream_msg(offsets: std::ops::Range<i64>) -> StreamMsg {
+        let mut data = vec![];
+        for offset in offsets.clone() {
+            let kmsg = kafka_message_new(
+                None,
+                Some(offset.to_string().into_bytes()),
+                Some(0),
+                vec![],
+                None,
+                TimestampType::NotAvailable,
+                Some(offset),
+            );
+            data.extend_from_slice(&kafka_message_len(&kmsg).to_be_bytes());
+            data.extend_from_slice(&kafka_message_pack(&kmsg));
+        }
+        StreamMsg {
+            data,
+            records: offsets.count() as u64,
+            ..Default::default()
+        }
+    }
+
+    fn encoding(codec: Codec, workers: usize) -> BlockEncoding {
+        BlockEncoding {
+            codec,
+            level: 1,
+            workers,
+            keys: None,
+        }
+    }
+
+    #[test]
+    fn archive_roundtrip() {
+        let dir = std::env::temp_dir().join(format!("akbt-stream-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        for codec in [Codec::Gzip, Codec::Zstd, Codec::Lz4, Codec::None] {
+            let pathfile = dir.join(codec.extension()).to_string_lossy().to_string();
+            let header = archive_header_new("localhost:9092", vec![]);
+            let (sender, handle) =
+                StreamWriter::run(pathfile.clone(), encoding(codec, 3), header, None, None)
+                    .unwrap();
+            for i in 0..10 {
+                sender.send(stream_msg(i * 100..(i + 1) * 100)).unwrap();
+            }
+            drop(sender);
+            handle.join().unwrap().unwrap();
+
+            let pathfile = format!("{}.{}", pathfile, codec.extension());
+            let mb = MProgressBars::restore(String::new(), pathfile.clone(), true, None);
+            let (receiver, _, header, handle) = StreamReader::run(pathfile, 3, None, mb).unwrap();
+            let offsets: Vec<i64> = receiver
+                .into_iter()
+                .flat_map(|batch| batch.unwrap())
+                .map(|m| m.offset())
+                .collect();
+            join_reader(handle).unwrap();
+
+            assert_eq!(header.unwrap().record_count(), 1000);
+            assert_eq!(offsets, (0..1000).collect::<Vec<_>>(), "{:?}", codec);
+        }
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn archive_rolls_into_volumes() {
+        let dir = std::env::temp_dir().join(format!("akbt-volumes-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let pathfile = dir.join("backup.gz").to_string_lossy().to_string();
+
+        let header = archive_header_new("localhost:9092", vec![]);
+        let limits = VolumeLimits {
+            records: Some(250),
+            ..Default::default()
+        };
+        let (sender, handle) = StreamWriter::run_volumes(
+            pathfile.clone(),
+            encoding(Codec::Gzip, 2),
+            header,
+            limits,
+            None,
+            None,
+        )
+        .unwrap();
+        for i in 0..10 {
+            let mut msg = stream_msg(i * 100..(i + 1) * 100);
+            msg.offsets = vec![("topic".to_string(), 0, (i + 1) * 100)];
+            sender.send(msg).unwrap();
+        }
+        drop(sender);
+        handle.join().unwrap().unwrap();
+
+        let manifest_file = volumes::manifest_path(&pathfile);
+        let manifest = volumes::load(&manifest_file).unwrap();
+        let records: Vec<u64> = manifest.volumes.iter().map(|v| v.records).collect();
+        assert_eq!(records, vec![300, 300, 300, 100]);
+        // The header has no partitions, so the first range starts at its first end
+        let ranges: Vec<(i64, i64)> = manifest
+            .volumes
+            .iter()
+            .map(|v| (v.ranges[0].begin, v.ranges[0].end))
+            .collect();
+        assert_eq!(
+            ranges,
+            vec![(100, 300), (300, 600), (600, 900), (900, 1000)]
+        );
+
+        let mut offsets = vec![];
+        let paths = crate::archive::list_archives(&manifest_file).unwrap();
+        for (number, (volume, path)) in manifest.volumes.iter().zip(paths).enumerate() {
+            volumes::check_volume(&path, volume).unwrap();
+            let mb = MProgressBars::restore(String::new(), path.clone(), true, None);
+            let (receiver, _, header, handle) = StreamReader::run(path, 2, None, mb).unwrap();
+            offsets.extend(
+                receiver
+                    .into_iter()
+                    .flat_map(|batch| batch.unwrap())
+                    .map(|m| m.offset()),
+            );
+            join_reader(handle).unwrap();
+            assert_eq!(header.unwrap().volume, Some(number as u32 + 1));
+        }
+        assert_eq!(offsets, (0..1000).collect::<Vec<_>>());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn block_moved_between_volumes_fails_authentication() {
+        let dir = std::env::temp_dir().join(format!("akbt-moved-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let pathfile = dir.join("backup.zst").to_string_lossy().to_string();
+
+        let keys = crate::crypto::EncryptionArgs {
+            encryption_key: Some("a".repeat(64)),
+            ..Default::default()
+        }
+        .keys()
+        .unwrap();
+        let encoding = BlockEncoding {
+            keys: keys.clone(),
+            ..encoding(Codec::Zstd, 1)
+        };
+        let limits = VolumeLimits {
+            records: Some(100),
+            ..Default::default()
+        };
+        let header = archive_header_new("localhost:9092", vec![]);
+        let (sender, handle) =
+            StreamWriter::run_volumes(pathfile.clone(), encoding, header, limits, None, None)
+                .unwrap();
+        for i in 0..2 {
+            sender.send(stream_msg(i * 100..(i + 1) * 100)).unwrap();
+        }
+        drop(sender);
+        handle.join().unwrap().unwrap();
+
+        // Both volumes start with a block of 100 records at index 0,
+        // the first block of the second volume is replaced with the one of the first
+        let paths = crate::archive::list_archives(&volumes::manifest_path(&pathfile)).unwrap();
+        let (first, second) = (
+            std::fs::read(&paths[0]).unwrap(),
+            std::fs::read(&paths[1]).unwrap(),
+        );
+        let header_len =
+            |data: &[u8]| 10 + u32::from_be_bytes(data[6..10].try_into().unwrap()) as usize;
+        let block_len =
+            |data: &[u8]| 13 + u32::from_be_bytes(data[5..9].try_into().unwrap()) as usize;
+        let (first_header, second_header) = (header_len(&first), header_len(&second));
+        let first_block = &first[first_header..first_header + block_len(&first[first_header..])];
+        let mut moved = second[..second_header].to_vec();
+        moved.extend_from_slice(first_block);
+        moved.extend_from_slice(&second[second_header + block_len(&second[second_header..])..]);
+        std::fs::write(&paths[1], moved).unwrap();
+
+        let mb = MProgressBars::restore(String::new(), paths[1].clone(), true, None);
+        let (receiver, _, _, handle) =
+            StreamReader::run(paths[1].clone(), 1, keys.as_ref(), mb).unwrap();
+        let first_batch = receiver.into_iter().next().unwrap();
+        join_reader(handle).unwrap();
+        assert!(matches!(first_batch, Err(AppError::Encryption(_))));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn record_length_is_bounded() {
+        let huge = u64::MAX.to_be_bytes();
+        assert!(matches!(
+            StreamReader::read_msg(&huge[..]),
+            Err(AppError::Corrupted(_))
+        ));
+        let mut truncated = 1000u64.to_be_bytes().to_vec();
+        truncated.extend_from_slice(&[0; 10]);
+        assert!(matches!(
+            StreamReader::read_msg(&truncated[..]),
+            Err(AppError::BadArchive(_))
+        ));
+    }
+
+    #[test]
+    fn unwritable_volume_fails_the_writer() {
+        let dir = std::env::temp_dir().join(format!("akbt-unwritable-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let pathfile = dir.join("backup.gz").to_string_lossy().to_string();
+        // The second volume can't be created over a directory
+        std::fs::create_dir(volumes::volume_path(&pathfile, 2)).unwrap();
+
+        let limits = VolumeLimits {
+            records: Some(100),
+            ..Default::default()
+        };
+        let header = archive_header_new("localhost:9092", vec![]);
+        let (sender, handle) = StreamWriter::run_volumes(
+            pathfile.clone(),
+            encoding(Codec::Gzip, 1),
+            header,
+            limits,
+            None,
+            None,
+        )
+        .unwrap();
+        for i in 0..3 {
+            if sender.send(stream_msg(i * 100..(i + 1) * 100)).is_err() {
+                break;
+            }
+        }
+        drop(sender);
+        let error = handle.join().unwrap().unwrap_err();
+        assert!(matches!(error, AppError::IoError(_)), "{:?}", error);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn encrypted_archive_needs_the_key() {
+        let dir = std::env::temp_dir().join(format!("akbt-encrypted-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let pathfile = dir.join("archive.gz").to_string_lossy().to_string();
+        let keys = |key: &str| {
+            crate::crypto::EncryptionArgs {
+                encryption_key: Some(key.repeat(64)),
+                ..Default::default()
+            }
+            .keys()
+            .unwrap()
+        };
+
+        let header = archive_header_new("localhost:9092", vec![]);
+        let encoding = BlockEncoding {
+            keys: keys("a"),
+            ..encoding(Codec::Zstd, 2)
+        };
+        let (sender, handle) =
+            StreamWriter::run(pathfile.clone(), encoding, header, None, None).unwrap();
+        sender.send(stream_msg(0..100)).unwrap();
+        drop(sender);
+        handle.join().unwrap().unwrap();
+
+        let run = |keys: Option<Keys>| {
+            let mb = MProgressBars::restore(String::new(), pathfile.clone(), true, None);
+            StreamReader::run(pathfile.clone(), 2, keys.as_ref(), mb)
+        };
+        let (receiver, _, header, handle) = run(keys("a")).unwrap();
+        let records: usize = receiver.into_iter().map(|b| b.unwrap().len()).sum();
+        join_reader(handle).unwrap();
+        assert_eq!(records, 100);
+        assert!(header.unwrap().encryption.is_some());
+
+        assert!(matches!(run(None), Err(AppError::Encryption(_))));
+        assert!(matches!(run(keys("b")), Err(AppError::Encryption(_))));
+
+        // Without the end block the forged trailer is caught:
+        // the end block has 13 bytes of frame, 12 of nonce and 16 of tag, the trailer 21
+        let data = std::fs::read(&pathfile).unwrap();
+        let mut forged = data[..data.len() - 41 - 21].to_vec();
+        let trailer = Trailer {
+            blocks: 1,
+            records: 100,
+        };
+        crate::archive::write_trailer(&mut forged, &trailer).unwrap();
+        std::fs::write(&pathfile, &forged).unwrap();
+        let (receiver, _, _, handle) = run(keys("a")).unwrap();
+        let error = receiver.into_iter().find_map(|b| b.err());
+        join_reader(handle).unwrap();
+        assert!(error.unwrap().to_string().contains("no end block"));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    // Returns the records read before the error
+    fn read_damaged(pathfile: &str) -> (usize, Option<AppError>) {
+        let mb = MProgressBars::restore(String::new(), pathfile.to_string(), true, None);
+        let (receiver, _, _, handle) =
+            StreamReader::run(pathfile.to_string(), 2, None, mb).unwrap();
+        let mut records = 0;
+        let mut error = None;
+        for batch in receiver {
+            match batch {
+                Ok(batch) => records += batch.len(),
+                Err(e) => error = Some(e),
+            }
+        }
+        join_reader(handle).unwrap();
+        (records, error)
+    }
+
+    #[test]
+    fn damaged_archive_is_reported() {
+        let dir = std::env::temp_dir().join(format!("akbt-damaged-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let pathfile = dir.join("archive.gz").to_string_lossy().to_string();
+
+        let header = archive_header_new("localhost:9092", vec![]);
+        let (sender, handle) = StreamWriter::run(
+            pathfile.clone(),
+            encoding(Codec::Gzip, 2),
+            header,
+            None,
+            None,
+        )
+        .unwrap();
+        for i in 0..3 {
+            sender.send(stream_msg(i * 100..(i + 1) * 100)).unwrap();
+        }
+        drop(sender);
+        handle.join().unwrap().unwrap();
+        let data = std::fs::read(&pathfile).unwrap();
+        assert_eq!(read_damaged(&pathfile), (300, None));
+
+        // The trailer is lost
+        std::fs::write(&pathfile, &data[..data.len() - 21]).unwrap();
+        let (records, error) = read_damaged(&pathfile);
+        assert_eq!(records, 300);
+        assert!(error.unwrap().to_string().contains("no trailer"));
+
+        // The last byte of the last block is flipped
+        let mut flipped = data.clone();
+        flipped[data.len() - 22] ^= 1;
+        std::fs::write(&pathfile, &flipped).unwrap();
+        let (records, error) = read_damaged(&pathfile);
+        assert_eq!(records, 200);
+        assert!(error.unwrap().to_string().contains("block 2"));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,467 @@
+use std::{
+    collections::{btree_map::Entry, BTreeMap, HashMap},
+    path::Path,
+    sync::mpsc::SyncSender,
+};
+
+use log::info;
+use regex::Regex;
+use serde_json::Value;
+
+use crate::{
+    archive::{archive_header_new, read_archive_header},
+    codec::Codec,
+    consumer::check_single_topic,
+    crypto::Keys,
+    errors::AppError,
+    mbprocess::MProgressBars,
+    protos::{
+        kafka_archive::{topic_metadata_new, ArchiveHeader, PartitionMetadata, TopicMetadata},
+        kafka_messages::{kafka_message_len, kafka_message_pack, KafkaMessage},
+    },
+    stream::{
+        archive_path, is_stdio, join_reader, BlockEncoding, StreamMsg, StreamReader, StreamWriter,
+        WriterHandle,
+    },
+    verify::verify_archive,
+};
+
+const BLOCK_RECORDS: u64 = 1000;
+const REDACTED: &str = "REDACTED";
+
+/// Which records to copy and how to change them
+#[derive(Debug, Clone, Default)]
+pub struct TransformOptions {
+    /// Archives to read, records are merged in the given order
+    pub inputs: Vec<String>,
+    /// Topic of legacy archives without a header
+    pub topic: Option<String>,
+    pub partitions: Vec<u32>,
+    /// Records with a matching key, records without a key are skipped
+    pub key_regex: Option<Regex>,
+    /// Unix time in milliseconds, records without a timestamp are skipped
+    pub from_time: Option<i64>,
+    pub to_time: Option<i64>,
+    /// Offsets range per partition of the single topic, END is exclusive, partitions without a
+    /// range are skipped
+    pub offsets: HashMap<i32, (i64, i64)>,
+    /// Fields of JSON values to redact, nested fields are separated by dots
+    pub redact: Vec<String>,
+    /// Write an archive per topic and partition into the output directory
+    pub split_partitions: bool,
+    pub codec: Codec,
+    pub level: u32,
+    pub workers: usize,
+    /// Decrypts the inputs and encrypts the outputs
+    pub keys: Option<Keys>,
+}
+
+impl TransformOptions {
+    fn matches(&self, kmsg: &KafkaMessage) -> bool {
+        if !self.partitions.is_empty() && !self.partitions.contains(&kmsg.partition()) {
+            return false;
+        }
+        if let Some(regex) = &self.key_regex {
+            match &kmsg.key {
+                Some(key) if regex.is_match(&String::from_utf8_lossy(key)) => (),
+                _ => return false,
+            }
+        }
+        if self.from_time.is_some() || self.to_time.is_some() {
+            let Some(ts) = kmsg.timestamp else {
+                return false;
+            };
+            if self.from_time.is_some_and(|from| ts < from)
+                || self.to_time.is_some_and(|to| ts >= to)
+            {
+                return false;
+            }
+        }
+        if !self.offsets.is_empty() {
+            match self.offsets.get(&(kmsg.partition() as i32)) {
+                Some((begin, end)) if (*begin..*end).contains(&kmsg.offset()) => (),
+                _ => return false,
+            }
+        }
+        true
+    }
+}
+
+// Replaces the field at the path, arrays on the way are redacted element by element
+fn redact_field(value: &mut Value, path: &[&str]) -> bool {
+    match (value, path) {
+        (Value::Array(items), _) => {
+            let mut redacted = false;
+            for item in items.iter_mut() {
+                redacted |= redact_field(item, path);
+            }
+            redacted
+        }
+        (Value::Object(fields), [name]) => match fields.get_mut(*name) {
+            Some(field) => {
+                *field = Value::String(REDACTED.to_string());
+                true
+            }
+            None => false,
+        },
+        (Value::Object(fields), [name, rest @ ..]) => fields
+            .get_mut(*name)
+            .is_some_and(|field| redact_field(field, rest)),
+        _ => false,
+    }
+}
+
+// Returns the redacted value, None when the value isn't JSON or has none of the fields
+fn redact(data: &[u8], fields: &[String]) -> Option<Vec<u8>> {
+    let mut value: Value = serde_json::from_slice(data).ok()?;
+    let mut redacted = false;
+    for field in fields {
+        let path: Vec<&str> = field.split('.').collect();
+        redacted |= redact_field(&mut value, &path);
+    }
+    redacted.then(|| serde_json::to_vec(&value).unwrap())
+}
+
+fn merge_partition(merged: &mut PartitionMetadata, other: &PartitionMetadata) {
+    merged.low_watermark = merged.low_watermark.min(other.low_watermark);
+    merged.high_watermark = merged.high_watermark.max(other.high_watermark);
+    merged.offset_begin = merged.offset_begin.min(other.offset_begin);
+    merged.offset_end = merged.offset_end.max(other.offset_end);
+}
+
+// Topics of all archives by name, partitions present in several archives cover all their ranges.
+// Returns the topics and the index of every archive topic among them.
+fn merge_topics(headers: &[ArchiveHeader]) -> (Vec<TopicMetadata>, Vec<Vec<usize>>) {
+    let mut topics: Vec<TopicMetadata> = vec![];
+    let mut indexes = Vec::with_capacity(headers.len());
+    for header in headers {
+        let mut index = Vec::with_capacity(header.topics.len());
+        for topic in header.topics.iter() {
+            let Some(idx) = topics.iter().position(|t| t.name() == topic.name()) else {
+                index.push(topics.len());
+                topics.push(topic.clone());
+                continue;
+            };
+            let merged = &mut topics[idx];
+            for partition in topic.partitions.iter() {
+                match merged
+                    .partitions
+                    .iter_mut()
+                    .find(|p| p.partition() == partition.partition())
+                {
+                    Some(p) => merge_partition(p, partition),
+                    None => merged.partitions.push(partition.clone()),
+                }
+            }
+            merged.partitions.sort_by_key(|p| p.partition());
+            for config in topic.configs.iter() {
+                if !merged.configs.iter().any(|c| c.name() == config.name()) {
+                    merged.configs.push(config.clone());
+                }
+            }
+            index.push(idx);
+        }
+        indexes.push(index);
+    }
+    (topics, indexes)
+}
+
+struct Output {
+    pathfile: String,
+    sender: SyncSender<StreamMsg>,
+    handle: WriterHandle,
+    chunk: StreamMsg,
+    records: u64,
+}
+
+impl Output {
+    fn create(
+        pathfile: String,
+        header: ArchiveHeader,
+        opts: &TransformOptions,
+    ) -> Result<Output, AppError> {
+        let pathfile = archive_path(pathfile, opts.codec);
+        let encoding = BlockEncoding {
+            codec: opts.codec,
+            level: opts.level,
+            workers: opts.workers,
+            keys: opts.keys.clone(),
+        };
+        let (sender, handle) = StreamWriter::run(pathfile.clone(), encoding, header, None, None)?;
+        Ok(Output {
+            pathfile,
+            sender,
+            handle,
+            chunk: StreamMsg::default(),
+            records: 0,
+        })
+    }
+
+    fn write(&mut self, kmsg: &KafkaMessage) -> Result<(), AppError> {
+        self.chunk
+            .data
+            .extend_from_slice(&kafka_message_len(kmsg).to_be_bytes());
+        self.chunk.data.extend_from_slice(&kafka_message_pack(kmsg));
+        self.chunk.records += 1;
+        self.records += 1;
+        if self.chunk.records >= BLOCK_RECORDS {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), AppError> {
+        if self.chunk.records == 0 {
+            return Ok(());
+        }
+        self.sender
+            .send(std::mem::take(&mut self.chunk))
+            .map_err(|e| AppError::Send2Encoder(e.to_string()))
+    }
+
+    fn finish(mut self) -> Result<(), AppError> {
+        self.flush()?;
+        drop(self.sender);
+        self.handle
+            .join()
+            .map_err(|_| AppError::Panicked("encoder".to_string()))??;
+        eprintln!("{}: records:{}", self.pathfile, self.records);
+        Ok(())
+    }
+}
+
+// Archive of a topic, or of a topic partition when the archives are split
+type OutputKey = (usize, Option<u32>);
+
+pub fn transform(file: String, opts: TransformOptions) -> Result<(), AppError> {
+    let mut headers = Vec::with_capacity(opts.inputs.len());
+    if opts.inputs.iter().any(|input| is_stdio(input)) {
+        return Err(AppError::InvalidArgument(
+            "transform reads the inputs twice, stdin can't be an input".to_string(),
+        ));
+    }
+    for input in opts.inputs.iter() {
+        info!("Verifying archive:{}", input);
+        verify_archive(input, opts.workers, opts.keys.as_ref())?;
+        let header = match read_archive_header(input)? {
+            Some(header) => header,
+            None => {
+                // Legacy archives don't know their topic
+                let topic = opts
+                    .topic
+                    .clone()
+                    .ok_or_else(|| AppError::MissingArgument("topic".to_string()))?;
+                archive_header_new("", vec![topic_metadata_new(topic, vec![], vec![])])
+            }
+        };
+        headers.push(header);
+    }
+    let (topics, indexes) = merge_topics(&headers);
+    check_single_topic(&opts.offsets, topics.len())?;
+    let brokers = headers[0].brokers().to_string();
+
+    if opts.split_partitions {
+        std::fs::create_dir_all(&file).map_err(|e| AppError::IoError(e.to_string()))?;
+    }
+    let mut outputs: BTreeMap<OutputKey, Output> = BTreeMap::new();
+    if !opts.split_partitions {
+        let header = archive_header_new(&brokers, topics.clone());
+        outputs.insert((0, None), Output::create(file.clone(), header, &opts)?);
+    }
+
+    let (mut read, mut redacted) = (0, 0);
+    for (input, index) in opts.inputs.iter().zip(indexes) {
+        let mb = MProgressBars::restore(String::new(), input.clone(), true, None);
+        let (receiver, _, _, decoder_handler) =
+            StreamReader::run(input.clone(), opts.workers, opts.keys.as_ref(), mb)?;
+        for batch in receiver {
+            for mut kmsg in batch? {
+                read += 1;
+                if !opts.matches(&kmsg) {
+                    continue;
+                }
+                let topic_idx = *index.get(kmsg.topic() as usize).ok_or_else(|| {
+                    AppError::BadArchive(format!(
+                        "record of topic index {} past the topics of `{}`",
+                        kmsg.topic(),
+                        input
+                    ))
+                })?;
+                if let Some(value) = kmsg.value.as_deref() {
+                    if let Some(value) = redact(value, &opts.redact) {
+                        kmsg.value = Some(value);
+                        redacted += 1;
+                    }
+                }
+
+                let key = match opts.split_partitions {
+                    true => (topic_idx, Some(kmsg.partition())),
+                    false => (0, None),
+                };
+                let output = match outputs.entry(key) {
+                    Entry::Occupied(entry) => entry.into_mut(),
+                    Entry::Vacant(entry) => {
+                        let name = format!("{}-{}", topics[topic_idx].name(), kmsg.partition());
+                        let pathfile = Path::new(&file).join(name).to_string_lossy().to_string();
+                        let header = archive_header_new(&brokers, vec![topics[topic_idx].clone()]);
+                        entry.insert(Output::create(pathfile, header, &opts)?)
+                    }
+                };
+                // Split archives have a single topic
+                kmsg.topic = (!opts.split_partitions && topic_idx > 0).then_some(topic_idx as u32);
+                output.write(&kmsg)?;
+            }
+        }
+        join_reader(decoder_handler)?;
+    }
+
+    let written: u64 = outputs.values().map(|o| o.records).sum();
+    for output in outputs.into_values() {
+        output.finish()?;
+    }
+    eprintln!(
+        "Records read:{} written:{} redacted:{}",
+        read, written, redacted
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protos::kafka_archive::partition_metadata_new;
+    use crate::protos::kafka_messages::{kafka_message_new, TimestampType};
+
+    fn record(partition: u32, offset: i64, key: &str, timestamp: Option<i64>) -> KafkaMessage {
+        kafka_message_new(
+            Some(key.as_bytes().to_vec()),
+            None,
+            Some(partition),
+            vec![],
+            timestamp,
+            TimestampType::CreateTime,
+            Some(offset),
+        )
+    }
+
+    #[test]
+    fn records_are_filtered() {
+        let opts = TransformOptions {
+            partitions: vec![0, 1],
+            key_regex: Some(Regex::new("^tenant-1").unwrap()),
+            from_time: Some(100),
+            to_time: Some(200),
+            offsets: HashMap::from([(0, (10, 20)), (1, (0, 5))]),
+            ..Default::default()
+        };
+        assert!(opts.matches(&record(0, 10, "tenant-1:a", Some(100))));
+        assert!(opts.matches(&record(1, 4, "tenant-12", Some(199))));
+        // Wrong partition, key, time and offset
+        assert!(!opts.matches(&record(2, 10, "tenant-1", Some(100))));
+        assert!(!opts.matches(&record(0, 10, "tenant-2", Some(100))));
+        assert!(!opts.matches(&record(0, 10, "tenant-1", Some(200))));
+        assert!(!opts.matches(&record(0, 10, "tenant-1", None)));
+        assert!(!opts.matches(&record(0, 20, "tenant-1", Some(100))));
+
+        assert!(TransformOptions::default().matches(&record(7, 0, "", None)));
+    }
+
+    #[test]
+    fn json_fields_are_redacted() {
+        let fields = vec!["email".to_string(), "cards.number".to_string()];
+        let value = br#"{"email":"a@b.c","name":"A","cards":[{"number":"1"},{"type":"x"}]}"#;
+        let redacted: Value = serde_json::from_slice(&redact(value, &fields).unwrap()).unwrap();
+        assert_eq!(
+            redacted,
+            serde_json::json!({
+                "email": REDACTED,
+                "name": "A",
+                "cards": [{"number": REDACTED}, {"type": "x"}]
+            })
+        );
+        assert_eq!(redact(br#"{"name":"A"}"#, &fields), None);
+        assert_eq!(redact(b"not json", &fields), None);
+    }
+
+    #[test]
+    fn unknown_topic_index_is_a_bad_archive() {
+        let dir = std::env::temp_dir().join(format!("akbt-transform-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let input = dir.join("input.zst").to_string_lossy().to_string();
+        let header = archive_header_new(
+            "",
+            vec![topic_metadata_new("a".to_string(), vec![], vec![])],
+        );
+        let encoding = BlockEncoding {
+            codec: Codec::Zstd,
+            level: 1,
+            workers: 1,
+            keys: None,
+        };
+        let (sender, handle) =
+            StreamWriter::run(input.clone(), encoding, header, None, None).unwrap();
+        // The record points to a topic the header doesn't have
+        let mut kmsg = record(0, 0, "k", None);
+        kmsg.topic = Some(3);
+        let mut data = kafka_message_len(&kmsg).to_be_bytes().to_vec();
+        data.extend_from_slice(&kafka_message_pack(&kmsg));
+        sender
+            .send(StreamMsg {
+                data,
+                records: 1,
+                ..Default::default()
+            })
+            .unwrap();
+        drop(sender);
+        handle.join().unwrap().unwrap();
+
+        let opts = TransformOptions {
+            inputs: vec![input],
+            workers: 1,
+            ..Default::default()
+        };
+        let output = dir.join("output.zst").to_string_lossy().to_string();
+        assert!(matches!(
+            transform(output, opts),
+            Err(AppError::BadArchive(_))
+        ));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn topics_are_merged_by_name() {
+        let topic = |name: &str, partitions: Vec<PartitionMetadata>| {
+            topic_metadata_new(name.to_string(), partitions, vec![])
+        };
+        let first = archive_header_new(
+            "",
+            vec![topic(
+                "a",
+                vec![partition_metadata_new(0, (0, 10), (0, 10))],
+            )],
+        );
+        let second = archive_header_new(
+            "",
+            vec![
+                topic("b", vec![]),
+                topic(
+                    "a",
+                    vec![
+                        partition_metadata_new(1, (0, 5), (0, 5)),
+                        partition_metadata_new(0, (5, 20), (10, 20)),
+                    ],
+                ),
+            ],
+        );
+        let (topics, indexes) = merge_topics(&[first, second]);
+        assert_eq!(indexes, vec![vec![0], vec![1, 0]]);
+        assert_eq!(topics[1].name(), "b");
+        assert_eq!(
+            topics[0].partitions,
+            vec![
+                partition_metadata_new(0, (0, 20), (0, 20)),
+                partition_metadata_new(1, (0, 5), (0, 5)),
+            ]
+        );
+    }
+}
@@ -0,0 +1,81 @@
+use crate::{
+    archive::list_archives,
+    crypto::Keys,
+    errors::AppError,
+    mbprocess::MProgressBars,
+    stream::{join_reader, StreamReader},
+    volumes::{self, is_manifest, Volume},
+};
+
+// Reads and checks the whole archive, returns the count of records
+pub fn verify_archive(
+    pathfile: &str,
+    workers: usize,
+    keys: Option<&Keys>,
+) -> Result<u64, AppError> {
+    let mb = MProgressBars::restore(String::new(), pathfile.to_string(), true, None);
+    let (receiver, _, _, decoder_handler) =
+        StreamReader::run(pathfile.to_string(), workers, keys, mb)?;
+
+    let mut records = 0;
+    let mut result = Ok(());
+    for batch in receiver {
+        match batch {
+            Ok(batch) => records += batch.len() as u64,
+            Err(e) => {
+                result = Err(e);
+                break;
+            }
+        }
+    }
+    join_reader(decoder_handler)?;
+    result.map(|_| records)
+}
+
+// Checks the size and checksum of the volume against the manifest before reading it,
+// then its record count
+pub fn verify_volume(
+    pathfile: &str,
+    volume: &Volume,
+    workers: usize,
+    keys: Option<&Keys>,
+) -> Result<u64, AppError> {
+    volumes::check_volume(pathfile, volume)?;
+    let records = verify_archive(pathfile, workers, keys)?;
+    if volume.records != records {
+        return Err(AppError::BadArchive(format!(
+            "{} records instead of {} of the manifest",
+            records, volume.records
+        )));
+    }
+    Ok(records)
+}
+
+// Volumes of a manifest are checked against it as well
+pub fn verify(file: String, workers: usize, keys: Option<Keys>) -> Result<(), AppError> {
+    let manifest = match is_manifest(&file) {
+        true => Some(volumes::load(&file)?),
+        false => None,
+    };
+    let mut corrupted = vec![];
+    for (idx, pathfile) in list_archives(&file)?.into_iter().enumerate() {
+        let verified = match manifest.as_ref().map(|m| &m.volumes[idx]) {
+            Some(volume) => verify_volume(&pathfile, volume, workers, keys.as_ref()),
+            None => verify_archive(&pathfile, workers, keys.as_ref()),
+        };
+        match verified {
+            Ok(records) => eprintln!("{}: OK records:{}", pathfile, records),
+            // Without the right key nothing can be checked
+            Err(e @ AppError::Encryption(_)) => return Err(e),
+            Err(e) => {
+                eprintln!("{}: CORRUPTED {}", pathfile, e);
+                corrupted.push(pathfile);
+            }
+        }
+    }
+
+    if !corrupted.is_empty() {
+        return Err(AppError::Corrupted(corrupted.join(", ")));
+    }
+    Ok(())
+}
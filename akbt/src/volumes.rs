@@ -0,0 +1,375 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashSet},
+    io::{self, Read, Write},
+    path::Path,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{errors::AppError, storage::storage};
+
+/// Suffix of the manifest of an archive written in volumes
+pub const MANIFEST_SUFFIX: &str = ".manifest.json";
+
+/// Offsets of a partition in a volume, `end` is exclusive
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PartitionRange {
+    pub topic: String,
+    pub partition: i32,
+    pub begin: i64,
+    pub end: i64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Volume {
+    /// File name next to the manifest
+    pub file: String,
+    pub records: u64,
+    pub size: u64,
+    pub sha256: String,
+    /// Empty when the offsets aren't known (compacted archives)
+    pub ranges: Vec<PartitionRange>,
+}
+
+/// Volumes of the archive in order
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct Manifest {
+    pub created_at: i64,
+    pub volumes: Vec<Volume>,
+}
+
+impl Manifest {
+    pub fn new() -> Self {
+        Manifest {
+            created_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_millis() as i64)
+                .unwrap_or_default(),
+            volumes: vec![],
+        }
+    }
+
+    /// Paths of the volumes next to the manifest
+    pub fn volume_paths(&self, manifest: &str) -> Vec<String> {
+        self.volumes
+            .iter()
+            .map(|v| {
+                Path::new(manifest)
+                    .with_file_name(&v.file)
+                    .to_string_lossy()
+                    .to_string()
+            })
+            .collect()
+    }
+}
+
+/// Limits of a volume, the next volume starts after the block reaching any of them
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct VolumeLimits {
+    pub size: Option<u64>,
+    pub records: Option<u64>,
+    pub time: Option<Duration>,
+}
+
+impl VolumeLimits {
+    pub fn is_set(&self) -> bool {
+        self.size.is_some() || self.records.is_some() || self.time.is_some()
+    }
+
+    pub fn reached(&self, size: u64, records: u64, opened: Instant) -> bool {
+        self.size.is_some_and(|max| size >= max)
+            || self.records.is_some_and(|max| records >= max)
+            || self.time.is_some_and(|max| opened.elapsed() >= max)
+    }
+}
+
+/// Size with an optional K, M, G or T suffix of powers of 1024
+pub fn parse_size(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let (number, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+        Some(idx) => s.split_at(idx),
+        None => (s, ""),
+    };
+    let shift = match unit.to_ascii_uppercase().trim_end_matches(['B', 'I']) {
+        "" => 0,
+        "K" => 10,
+        "M" => 20,
+        "G" => 30,
+        "T" => 40,
+        _ => return Err(format!("unknown unit of size `{}`", s)),
+    };
+    let number: u64 = number.parse().map_err(|e| format!("`{}` {}", s, e))?;
+    number
+        .checked_mul(1 << shift)
+        .filter(|size| *size > 0)
+        .ok_or_else(|| format!("bad size `{}`", s))
+}
+
+pub fn is_manifest(path: &str) -> bool {
+    path.ends_with(MANIFEST_SUFFIX)
+}
+
+// The archive name without its extension
+fn stem(pathfile: &str) -> (String, String) {
+    let path = Path::new(pathfile);
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let extension = path
+        .extension()
+        .map(|e| format!(".{}", e.to_string_lossy()))
+        .unwrap_or_default();
+    (stem.to_string(), extension)
+}
+
+/// backup.gz is described by backup.manifest.json
+pub fn manifest_path(pathfile: &str) -> String {
+    let (stem, _) = stem(pathfile);
+    Path::new(pathfile)
+        .with_file_name(format!("{}{}", stem, MANIFEST_SUFFIX))
+        .to_string_lossy()
+        .to_string()
+}
+
+/// Volumes of backup.gz are backup-00001.gz, backup-00002.gz and so on
+pub fn volume_path(pathfile: &str, number: u32) -> String {
+    let (stem, extension) = stem(pathfile);
+    Path::new(pathfile)
+        .with_file_name(format!("{}-{:05}{}", stem, number, extension))
+        .to_string_lossy()
+        .to_string()
+}
+
+pub fn load(path: &str) -> Result<Manifest, AppError> {
+    let (mut reader, _) = storage(path)?.open(path)?;
+    let mut data = vec![];
+    reader
+        .read_to_end(&mut data)
+        .map_err(|e| AppError::IoError(format!("`{}`: {}", path, e)))?;
+    serde_json::from_slice(&data)
+        .map_err(|e| AppError::BadArchive(format!("bad manifest `{}`: {}", path, e)))
+}
+
+// Rewritten after every volume, so an interrupted backup lists the finished volumes
+pub fn save(path: &str, manifest: &Manifest) -> Result<(), AppError> {
+    let io_error = |e: io::Error| AppError::IoError(format!("`{}`: {}", path, e));
+    let data = serde_json::to_vec_pretty(manifest).map_err(|e| AppError::IoError(e.to_string()))?;
+    let mut writer = storage(path)?.create(path)?;
+    writer.write_all(&data).map_err(io_error)?;
+    writer.finish().map_err(io_error)
+}
+
+/// Writer keeping the size and the checksum of the written bytes
+pub struct Checksummed<W> {
+    inner: W,
+    hasher: Sha256,
+    size: u64,
+}
+
+impl<W: Write> Checksummed<W> {
+    pub fn new(inner: W) -> Self {
+        Checksummed {
+            inner,
+            hasher: Sha256::new(),
+            size: 0,
+        }
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// The inner writer with the size and the hex SHA-256 of the written bytes
+    pub fn into_parts(self) -> (W, u64, String) {
+        let hash = self.hasher.finalize();
+        let hex = hash.iter().map(|b| format!("{:02x}", b)).collect();
+        (self.inner, self.size, hex)
+    }
+}
+
+impl<W: Write> Write for Checksummed<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Checks the size and the checksum of the volume file against the manifest
+pub fn check_volume(path: &str, volume: &Volume) -> Result<(), AppError> {
+    let (mut reader, _) = storage(path)?.open(path)?;
+    let mut checksummed = Checksummed::new(io::sink());
+    io::copy(&mut reader, &mut checksummed)
+        .map_err(|e| AppError::IoError(format!("`{}`: {}", path, e)))?;
+    let (_, size, sha256) = checksummed.into_parts();
+    if size != volume.size {
+        return Err(AppError::BadArchive(format!(
+            "{} bytes instead of {}",
+            size, volume.size
+        )));
+    }
+    if sha256 != volume.sha256 {
+        return Err(AppError::BadArchive(format!(
+            "sha256 {} instead of {}",
+            sha256, volume.sha256
+        )));
+    }
+    Ok(())
+}
+
+/// Offset ranges of the partitions in the volume being written
+#[derive(Debug, Default)]
+pub struct Ranges {
+    /// Where the next volume starts per partition
+    next: BTreeMap<(String, i32), i64>,
+    current: BTreeMap<(String, i32), (i64, i64)>,
+}
+
+impl Ranges {
+    /// Partitions start at the beginning of the backup
+    pub fn new(begin: impl IntoIterator<Item = ((String, i32), i64)>) -> Self {
+        Ranges {
+            next: begin.into_iter().collect(),
+            current: BTreeMap::new(),
+        }
+    }
+
+    /// The partition is done up to the offset
+    pub fn advance(&mut self, topic: String, partition: i32, end: i64) {
+        let key = (topic, partition);
+        let begin = self.next.get(&key).copied().unwrap_or(end);
+        self.current.entry(key).or_insert((begin, end)).1 = end;
+    }
+
+    /// Ranges of the finished volume
+    pub fn seal(&mut self) -> Vec<PartitionRange> {
+        std::mem::take(&mut self.current)
+            .into_iter()
+            .map(|((topic, partition), (begin, end))| {
+                self.next.insert((topic.clone(), partition), end);
+                PartitionRange {
+                    topic,
+                    partition,
+                    begin,
+                    end,
+                }
+            })
+            .collect()
+    }
+}
+
+/// Partitions of the volumes split into up to `count` streams. A stream has all records
+/// of its partitions, so restoring its volumes in order keeps the order of every partition.
+pub fn partition_streams(volumes: &[Volume], count: usize) -> Vec<HashSet<(String, i32)>> {
+    let partitions: BTreeSet<(String, i32)> = volumes
+        .iter()
+        .flat_map(|v| v.ranges.iter().map(|r| (r.topic.clone(), r.partition)))
+        .collect();
+    let mut streams = vec![HashSet::new(); count.clamp(1, partitions.len().max(1))];
+    let len = streams.len();
+    for (idx, partition) in partitions.into_iter().enumerate() {
+        streams[idx % len].insert(partition);
+    }
+    streams
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn volume(partitions: &[i32]) -> Volume {
+        Volume {
+            file: String::new(),
+            records: 0,
+            size: 0,
+            sha256: String::new(),
+            ranges: partitions
+                .iter()
+                .map(|p| PartitionRange {
+                    topic: "t".to_string(),
+                    partition: *p,
+                    begin: 0,
+                    end: 1,
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn names_and_sizes() {
+        assert_eq!(manifest_path("/b/backup.gz"), "/b/backup.manifest.json");
+        assert_eq!(volume_path("/b/backup.gz", 2), "/b/backup-00002.gz");
+        assert_eq!(
+            volume_path("s3://bucket/backup.zst", 1),
+            "s3://bucket/backup-00001.zst"
+        );
+        let manifest = Manifest {
+            created_at: 0,
+            volumes: vec![Volume {
+                file: "backup-00001.gz".to_string(),
+                ..volume(&[])
+            }],
+        };
+        assert_eq!(
+            manifest.volume_paths("/b/backup.manifest.json"),
+            vec!["/b/backup-00001.gz"]
+        );
+
+        assert_eq!(parse_size("100"), Ok(100));
+        assert_eq!(parse_size("2K"), Ok(2048));
+        assert_eq!(parse_size("1GiB"), Ok(1 << 30));
+        assert_eq!(parse_size("3mb"), Ok(3 << 20));
+        assert!(parse_size("1X").is_err());
+        assert!(parse_size("0").is_err());
+    }
+
+    #[test]
+    fn ranges_continue_across_volumes() {
+        let mut ranges = Ranges::new([(("t".to_string(), 0), 10)]);
+        ranges.advance("t".to_string(), 0, 15);
+        ranges.advance("t".to_string(), 1, 3);
+        ranges.advance("t".to_string(), 0, 20);
+        assert_eq!(
+            ranges.seal(),
+            vec![
+                PartitionRange {
+                    topic: "t".to_string(),
+                    partition: 0,
+                    begin: 10,
+                    end: 20
+                },
+                PartitionRange {
+                    topic: "t".to_string(),
+                    partition: 1,
+                    begin: 3,
+                    end: 3
+                },
+            ]
+        );
+        ranges.advance("t".to_string(), 0, 25);
+        assert_eq!(ranges.seal()[0].begin, 20);
+    }
+
+    #[test]
+    fn partitions_are_split_into_streams() {
+        let volumes = [volume(&[0]), volume(&[1]), volume(&[0, 2]), volume(&[3])];
+        let streams = partition_streams(&volumes, 2);
+        let partitions = |stream: &HashSet<(String, i32)>| {
+            let mut partitions: Vec<i32> = stream.iter().map(|(_, p)| *p).collect();
+            partitions.sort();
+            partitions
+        };
+        assert_eq!(streams.len(), 2);
+        assert_eq!(partitions(&streams[0]), vec![0, 2]);
+        assert_eq!(partitions(&streams[1]), vec![1, 3]);
+
+        // No more streams than partitions
+        assert_eq!(partition_streams(&volumes, 8).len(), 4);
+        assert_eq!(partition_streams(&[volume(&[])], 8).len(), 1);
+    }
+}
@@ -0,0 +1,107 @@
+use std::{
+    collections::BTreeMap,
+    sync::{
+        mpsc::{sync_channel, Receiver},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+};
+
+/// Default count of compression workers
+pub fn default_workers() -> usize {
+    thread::available_parallelism()
+        .map(|n| n.get())
+        .unwrap_or(1)
+}
+
+/// Applies `f` to the received items on `workers` threads.
+/// Results are sent in the order the items were received.
+pub fn ordered_map<T, U, F>(
+    receiver: Receiver<T>,
+    workers: usize,
+    f: F,
+) -> (Receiver<U>, JoinHandle<()>)
+where
+    T: Send + 'static,
+    U: Send + 'static,
+    F: Fn(T) -> U + Send + Sync + 'static,
+{
+    let workers = workers.max(1);
+    let (task_sender, task_receiver) = sync_channel::<(u64, T)>(workers);
+    let (done_sender, done_receiver) = sync_channel::<(u64, U)>(workers);
+    let (sender, results) = sync_channel::<U>(workers);
+
+    let handle = thread::spawn(move || {
+        let task_receiver = Arc::new(Mutex::new(task_receiver));
+        let f = Arc::new(f);
+        let mut handles = Vec::with_capacity(workers + 1);
+
+        for _ in 0..workers {
+            let task_receiver = task_receiver.clone();
+            let done_sender = done_sender.clone();
+            let f = f.clone();
+            handles.push(thread::spawn(move || loop {
+                // The lock is released before the work starts
+                let task = task_receiver.lock().unwrap().recv();
+                let Ok((seq, item)) = task else {
+                    break;
+                };
+                if done_sender.send((seq, f(item))).is_err() {
+                    break;
+                }
+            }));
+        }
+        drop(done_sender);
+
+        handles.push(thread::spawn(move || {
+            let mut pending = BTreeMap::new();
+            let mut next = 0;
+            for (seq, result) in done_receiver {
+                pending.insert(seq, result);
+                while let Some(result) = pending.remove(&next) {
+                    if sender.send(result).is_err() {
+                        return;
+                    }
+                    next += 1;
+                }
+            }
+        }));
+
+        for (seq, item) in receiver.into_iter().enumerate() {
+            if task_sender.send((seq as u64, item)).is_err() {
+                break;
+            }
+        }
+        drop(task_sender);
+
+        for handle in handles {
+            handle.join().unwrap();
+        }
+    });
+
+    (results, handle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn results_keep_order() {
+        let (sender, receiver) = sync_channel(1);
+        let (results, handle) = ordered_map(receiver, 4, |i: u64| {
+            // Earlier items finish later
+            thread::sleep(Duration::from_millis(10 - i % 10));
+            i * 2
+        });
+        thread::spawn(move || {
+            for i in 0..50 {
+                sender.send(i).unwrap();
+            }
+        });
+        let results: Vec<u64> = results.into_iter().collect();
+        handle.join().unwrap();
+        assert_eq!(results, (0..50).map(|i| i * 2).collect::<Vec<_>>());
+    }
+}